@@ -1,13 +1,46 @@
-use bincode::{deserialize_from, serialize_into};
-use std::io::*;
+use crate::error::Error;
+use crate::server::{
+    execute::SelectResult,
+    response::{Format, Response},
+};
+use bincode::serialize_into;
+use std::io::{stdin, BufRead};
 use std::net::TcpStream;
 
-pub fn from_stdin() {
-    let mut stream = TcpStream::connect("127.0.0.1:12345").unwrap();
+// Print a response. JSON clients get the JSON itself, one response per line.
+fn print_response(response: &Response, format: Format) {
+    if format == Format::Json {
+        println!("{}", serde_json::to_string(response).unwrap());
+        return;
+    }
+    match response {
+        Response::Ok(SelectResult::Records(records)) => {
+            for record in records {
+                println!("{}", record);
+            }
+        }
+        Response::Ok(SelectResult::Series(series)) => {
+            for s in series {
+                println!("{} {:?} {:?}", s.name, s.labels, s.variables);
+                for (timestamp, values) in s.points.iter() {
+                    println!("  {} {:?}", timestamp, values);
+                }
+            }
+        }
+        Response::Written(count) => println!("Wrote {} record(s)", count),
+        Response::Deleted => println!("Deleted"),
+        Response::Error { code, message } => println!("Error ({:?}): {}", code, message),
+    }
+}
+
+// Send each line of stdin to the server at addr, and print its responses.
+pub fn from_stdin(addr: &str, format: Format) -> Result<(), Error> {
+    let mut stream = TcpStream::connect(addr)?;
+    format.write_to(&mut stream)?;
     for line in stdin().lock().lines() {
-        let line = line.unwrap();
-        serialize_into(&mut stream, &line).unwrap();
-        let responses: String = deserialize_from(&mut stream).unwrap();
-        println!("{}", responses);
+        serialize_into(&mut stream, &line?)?;
+        let response = Response::read_from(&mut stream, format)?;
+        print_response(&response, format);
     }
+    Ok(())
 }
@@ -0,0 +1,390 @@
+use crate::error::Error;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use serde::Deserialize;
+use std::{collections::HashMap, fmt::Display, fs, str::FromStr};
+
+// Setting Struct. A config setting, and the names it goes by as a command line flag
+// and an environment variable.
+pub struct Setting {
+    pub key: &'static str,
+    pub flag: &'static str,
+    pub env: &'static str,
+    pub help: &'static str,
+}
+
+// CONSTANTS
+// Every setting that can be given outside the config file.
+pub const SETTINGS: [Setting; 11] = [
+    Setting {
+        key: "listen_addr",
+        flag: "listen-addr",
+        env: "LISTEN_ADDR",
+        help: "Address the server listens on, and clients connect to",
+    },
+    Setting {
+        key: "data_dir",
+        flag: "data-dir",
+        env: "DATAROOT",
+        help: "Folder holding the index, write-ahead log and blocks",
+    },
+    Setting {
+        key: "flush_count",
+        flag: "flush-count",
+        env: "FLUSH_COUNT",
+        help: "Flush the in-memory block after this many writes",
+    },
+    Setting {
+        key: "flush_bytes",
+        flag: "flush-bytes",
+        env: "FLUSH_BYTES",
+        help: "Flush the in-memory block once it holds about this many bytes",
+    },
+    Setting {
+        key: "flush_age_secs",
+        flag: "flush-age-secs",
+        env: "FLUSH_AGE_SECS",
+        help: "Flush the in-memory block once it is this many seconds old",
+    },
+    Setting {
+        key: "flush_span_secs",
+        flag: "flush-span-secs",
+        env: "FLUSH_SPAN_SECS",
+        help: "Flush the in-memory block once its points span this many seconds",
+    },
+    Setting {
+        key: "flush_idle_secs",
+        flag: "flush-idle-secs",
+        env: "FLUSH_IDLE_SECS",
+        help: "Flush the in-memory block once it has had no writes for this many seconds",
+    },
+    Setting {
+        key: "block_window_secs",
+        flag: "block-window-secs",
+        env: "BLOCK_WINDOW_SECS",
+        help: "Width of the wall-clock windows blocks are aligned to, in seconds",
+    },
+    Setting {
+        key: "reader_threads",
+        flag: "reader-threads",
+        env: "READER_THREADS",
+        help: "Number of threads answering selects",
+    },
+    Setting {
+        key: "retention",
+        flag: "retention",
+        env: "RETENTION",
+        help: "Seconds to keep data for; unset keeps it forever",
+    },
+    Setting {
+        key: "retention_by_metric",
+        flag: "retention-by-metric",
+        env: "RETENTION_BY_METRIC",
+        help: "Per-metric retention overrides, as comma-separated name=seconds pairs",
+    },
+];
+// Environment variable naming the config file, if --config isn't given.
+const CONFIG_ENV: &str = "CONFIG";
+// Longest retention accepted (about 1000 years), so expiry cutoffs stay representable.
+const MAX_RETENTION_SECS: u64 = 1000 * 365 * 24 * 60 * 60;
+
+// Config Struct. Settings merged from, in increasing priority, defaults, a TOML file,
+// environment variables (including .env) and command line flags.
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen_addr: String,
+    pub data_dir: String,
+    pub flush_count: u64,
+    pub flush_bytes: Option<u64>,
+    pub flush_age_secs: Option<u64>,
+    pub flush_span_secs: Option<u64>,
+    pub flush_idle_secs: Option<u64>,
+    pub block_window_secs: u64,
+    pub reader_threads: usize,
+    pub retention: Option<u64>,
+    pub retention_by_metric: HashMap<String, u64>,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen_addr: String::from("127.0.0.1:12345"),
+            data_dir: String::from("./data"),
+            flush_count: 50000,
+            flush_bytes: None,
+            flush_age_secs: None,
+            flush_span_secs: None,
+            flush_idle_secs: None,
+            block_window_secs: 2 * 60 * 60,
+            reader_threads: 1,
+            retention: None,
+            retention_by_metric: HashMap::new(),
+        }
+    }
+}
+impl Config {
+    // Constructor from a TOML file. Settings it leaves out keep their defaults.
+    pub fn from_file(path: &str) -> Result<Self, Error> {
+        let data = fs::read_to_string(path)?;
+        toml::from_str(&data).map_err(|e| Error::Config(format!("{}: {}", path, e)))
+    }
+
+    // Load the config for a subcommand: the file given by --config (or CONFIG), then
+    // the environment, then flags.
+    pub fn load(matches: &ArgMatches) -> Result<Self, Error> {
+        let path = match matches.value_of("config") {
+            Some(path) => Some(String::from(path)),
+            None => dotenv::var(CONFIG_ENV).ok(),
+        };
+        let mut config = match path {
+            Some(path) => Config::from_file(&path)?,
+            None => Config::default(),
+        };
+        config.apply(|setting| dotenv::var(setting.env).ok())?;
+        config.apply(|setting| matches.value_of(setting.key).map(String::from))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Override each setting get returns a value for.
+    pub fn apply<F: Fn(&Setting) -> Option<String>>(&mut self, get: F) -> Result<(), Error> {
+        for setting in SETTINGS.iter() {
+            if let Some(value) = get(setting) {
+                self.set(setting.key, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Set a setting from its string value.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        match key {
+            "listen_addr" => self.listen_addr = String::from(value),
+            "data_dir" => self.data_dir = String::from(value),
+            "flush_count" => self.flush_count = parse(key, value)?,
+            "flush_bytes" => self.flush_bytes = Some(parse(key, value)?),
+            "flush_age_secs" => self.flush_age_secs = Some(parse(key, value)?),
+            "flush_span_secs" => self.flush_span_secs = Some(parse(key, value)?),
+            "flush_idle_secs" => self.flush_idle_secs = Some(parse(key, value)?),
+            "block_window_secs" => self.block_window_secs = parse(key, value)?,
+            "reader_threads" => self.reader_threads = parse(key, value)?,
+            "retention" => self.retention = Some(parse(key, value)?),
+            "retention_by_metric" => self.retention_by_metric = parse_overrides(key, value)?,
+            _ => return Err(Error::Config(format!("unknown setting {}", key))),
+        }
+        Ok(())
+    }
+
+    // Check that settings are usable.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.flush_count == 0 || self.reader_threads == 0 {
+            return Err(Error::Config(String::from(
+                "flush_count and reader_threads must be at least 1",
+            )));
+        }
+        let limits = [
+            self.flush_bytes,
+            self.flush_age_secs,
+            self.flush_span_secs,
+            self.flush_idle_secs,
+        ];
+        if limits.contains(&Some(0)) {
+            return Err(Error::Config(String::from(
+                "flush limits must be at least 1 if set",
+            )));
+        }
+        // Windows are handled in millis, and must line up with days.
+        if self.block_window_secs == 0
+            || self.block_window_secs > 24 * 60 * 60
+            || (24 * 60 * 60) % self.block_window_secs != 0
+        {
+            return Err(Error::Config(String::from(
+                "block_window_secs must divide a day evenly",
+            )));
+        }
+        let mut retentions = self
+            .retention
+            .iter()
+            .chain(self.retention_by_metric.values());
+        if retentions.any(|x| *x == 0 || *x > MAX_RETENTION_SECS) {
+            return Err(Error::Config(format!(
+                "retention must be between 1 and {} seconds",
+                MAX_RETENTION_SECS
+            )));
+        }
+        Ok(())
+    }
+
+    // Get the block window width, in millis.
+    pub fn get_window_millis(&self) -> i64 {
+        self.block_window_secs as i64 * 1000
+    }
+}
+
+// Parse a setting's value.
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, Error>
+where
+    T::Err: Display,
+{
+    value
+        .trim()
+        .parse()
+        .map_err(|e| Error::Config(format!("invalid {} {:?}: {}", key, value, e)))
+}
+
+// Parse per-metric overrides, given as comma-separated name=value pairs.
+fn parse_overrides<T: FromStr>(key: &str, value: &str) -> Result<HashMap<String, T>, Error>
+where
+    T::Err: Display,
+{
+    let mut overrides = HashMap::new();
+    for pair in value.split(',').filter(|x| !x.trim().is_empty()) {
+        let mut parts = pair.splitn(2, '=');
+        let name = parts.next().unwrap_or_default().trim();
+        match parts.next() {
+            Some(value) if !name.is_empty() => {
+                overrides.insert(String::from(name), parse(key, value)?);
+            }
+            _ => {
+                return Err(Error::Config(format!(
+                    "invalid {} {:?}: expected name=value",
+                    key, pair
+                )))
+            }
+        }
+    }
+    Ok(overrides)
+}
+
+// Get the command line interface.
+pub fn app() -> App<'static, 'static> {
+    let config = Arg::with_name("config")
+        .long("config")
+        .short("c")
+        .takes_value(true)
+        .value_name("FILE")
+        .global(true)
+        .help("TOML config file");
+    let server = SETTINGS.iter().fold(
+        SubCommand::with_name("server").about("Runs the database server"),
+        |server, setting| {
+            server.arg(
+                Arg::with_name(setting.key)
+                    .long(setting.flag)
+                    .takes_value(true)
+                    .value_name("VALUE")
+                    .help(setting.help),
+            )
+        },
+    );
+    let client = SubCommand::with_name("client")
+        .about("Sends queries from stdin to a server, one per line")
+        .arg(
+            Arg::with_name("listen_addr")
+                .long("listen-addr")
+                .takes_value(true)
+                .value_name("VALUE")
+                .help("Address of the server"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Print responses as JSON"),
+        );
+    App::new("timeseries-storage")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(config)
+        .subcommand(server)
+        .subcommand(client)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_precedence() {
+        // The file sets some settings, the environment overrides one, flags another.
+        let path = env::temp_dir().join(format!("{}.toml", Uuid::new_v4()));
+        fs::write(
+            &path,
+            "listen_addr = \"0.0.0.0:9000\"\ndata_dir = \"/var/db\"\nflush_count = 10\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+        let mut config = Config::from_file(path).unwrap();
+        let mut vars = HashMap::new();
+        vars.insert("DATAROOT", "/srv/db");
+        vars.insert("FLUSH_BYTES", "1024");
+        config
+            .apply(|setting| vars.get(setting.env).map(|x| x.to_string()))
+            .unwrap();
+        let matches = app()
+            .get_matches_from_safe(vec![
+                "timeseries-storage",
+                "server",
+                "--config",
+                path,
+                "--data-dir",
+                "/data/a",
+                "--reader-threads",
+                "4",
+            ])
+            .unwrap();
+        let matches = matches.subcommand_matches("server").unwrap();
+        assert_eq!(matches.value_of("config"), Some(path));
+        config
+            .apply(|setting| matches.value_of(setting.key).map(String::from))
+            .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                listen_addr: String::from("0.0.0.0:9000"),
+                data_dir: String::from("/data/a"),
+                flush_count: 10,
+                flush_bytes: Some(1024),
+                flush_age_secs: None,
+                flush_span_secs: None,
+                flush_idle_secs: None,
+                block_window_secs: 7200,
+                reader_threads: 4,
+                retention: None,
+                retention_by_metric: HashMap::new(),
+            }
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut config = Config::default();
+        match config.set("flush_count", "ten") {
+            Err(Error::Config(_)) => (),
+            _ => panic!("invalid number was accepted"),
+        }
+        config.set("reader_threads", "0").unwrap();
+        assert!(config.validate().is_err());
+        let mut config = Config::default();
+        config.set("block_window_secs", "7000").unwrap();
+        assert!(config.validate().is_err());
+
+        // Retention overrides must be name=seconds pairs, and in range.
+        let mut config = Config::default();
+        config
+            .set("retention_by_metric", "cpu=60, mem=120")
+            .unwrap();
+        assert_eq!(config.retention_by_metric.get("mem"), Some(&120));
+        assert!(config.set("retention_by_metric", "cpu").is_err());
+        assert!(config.set("retention_by_metric", "cpu=1d").is_err());
+        assert!(config.set("retention", "-5").is_err());
+        config.set("retention", "0").unwrap();
+        assert!(config.validate().is_err());
+        let config: Config = toml::from_str("[retention_by_metric]\ncpu = 60\n").unwrap();
+        assert_eq!(config.retention_by_metric.get("cpu"), Some(&60));
+        match toml::from_str::<Config>("flush_cuont = 3") {
+            Err(_) => (),
+            _ => panic!("unknown setting was accepted"),
+        }
+    }
+}
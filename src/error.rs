@@ -1,13 +1,100 @@
+use serde::{Deserialize, Serialize};
 use std::error;
 use std::fmt;
+use std::io;
 
 #[derive(Debug)]
-pub enum Error {}
+pub enum Error {
+    // Reading or writing a file or socket failed.
+    Io(io::Error),
+    // A block file was written in a format this version can't read.
+    UnsupportedBlockFormat(String),
+    // A block file is truncated or otherwise malformed.
+    CorruptBlock(String),
+    // Some other stored or received data (e.g. the index) couldn't be decoded.
+    Decode(String),
+    // A record's variables don't match the schema of the series it belongs to.
+    SchemaMismatch(String),
+    // A text query is malformed, at the given (1-based) column.
+    QueryParse(usize, String),
+    // A query is well formed, but can't be evaluated.
+    InvalidQuery(String),
+    // The named database thread has shut down, so requests can't be served.
+    ChannelClosed(String),
+    // A config file, environment variable or flag has an invalid setting.
+    Config(String),
+}
 
-impl error::Error for Error {}
+impl Error {
+    // Get the code clients see the error as.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::Io(_)
+            | Error::UnsupportedBlockFormat(_)
+            | Error::CorruptBlock(_)
+            | Error::Decode(_) => ErrorCode::Storage,
+            Error::SchemaMismatch(_) => ErrorCode::SchemaMismatch,
+            Error::QueryParse(..) => ErrorCode::BadRequest,
+            Error::InvalidQuery(_) => ErrorCode::InvalidQuery,
+            Error::ChannelClosed(_) | Error::Config(_) => ErrorCode::Unavailable,
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", format!("{:?}", self))
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::UnsupportedBlockFormat(msg) => write!(f, "unsupported block format: {}", msg),
+            Error::CorruptBlock(msg) => write!(f, "corrupt block: {}", msg),
+            Error::Decode(msg) => write!(f, "decode error: {}", msg),
+            Error::SchemaMismatch(msg) => write!(f, "schema mismatch: {}", msg),
+            Error::QueryParse(column, msg) => {
+                write!(f, "query parse error at column {}: {}", column, msg)
+            }
+            Error::InvalidQuery(msg) => write!(f, "invalid query: {}", msg),
+            Error::ChannelClosed(msg) => write!(f, "channel closed: {}", msg),
+            Error::Config(msg) => write!(f, "invalid config: {}", msg),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
     }
 }
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        match *e {
+            bincode::ErrorKind::Io(e) => Error::Io(e),
+            e => Error::Decode(e.to_string()),
+        }
+    }
+}
+
+// ErrorCode Enum. A stable category for errors sent to clients, so they can react
+// without parsing messages.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+pub enum ErrorCode {
+    // The request couldn't be parsed.
+    BadRequest,
+    // The request parsed, but can't be evaluated.
+    InvalidQuery,
+    // A write doesn't match the schema of its series.
+    SchemaMismatch,
+    // Stored data couldn't be read or written.
+    Storage,
+    // The server can't serve requests right now.
+    Unavailable,
+}
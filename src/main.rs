@@ -1,17 +1,30 @@
 mod client;
+mod config;
 mod error;
 mod server;
 
+use config::Config;
 use dotenv::dotenv;
-use std::env;
+use server::response::Format;
+use std::process;
 
 fn main() {
     dotenv().ok();
 
-    let args: Vec<String> = env::args().collect();
-    if args[1] == "client" {
-        client::from_stdin()
-    } else if args[1] == "server" {
-        server::server()
+    let matches = config::app().get_matches();
+    let result = match matches.subcommand() {
+        ("client", Some(matches)) => Config::load(matches).and_then(|config| {
+            let format = match matches.is_present("json") {
+                true => Format::Json,
+                false => Format::Bincode,
+            };
+            client::from_stdin(&config.listen_addr, format)
+        }),
+        ("server", Some(matches)) => Config::load(matches).and_then(server::server),
+        _ => Ok(()),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(1);
     }
 }
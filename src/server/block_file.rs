@@ -0,0 +1,277 @@
+use crate::error::Error;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::{convert::TryInto, ops::Range};
+
+// On-disk layout of a block file (all integers little-endian):
+//   magic (4 bytes) | version (u32) | section count (u32) | section end offsets (u64 each)
+// followed by the sections themselves, back to back.
+const MAGIC: &[u8; 4] = b"TRDB";
+pub const FORMAT_VERSION: u32 = 4;
+const PREAMBLE_SIZE: usize = 12;
+
+// Section Enum. The sections of a block file, in the order they are laid out.
+//   Fst: maps each label / metric to the offset of its bitmap in Bitmaps.
+//   Bitmaps: serialized bitmaps, each prefixed with its u32 length.
+//   SeriesOffsets: the u64 end offset of each series in Storage, by series id.
+//   Storage: packed series (metadata plus a compressed chunk), back to back, so each
+//     can be decoded on its own.
+//   Sequence: the u64 flush order of the block; a later block holds later writes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Section {
+    StartTimestamp,
+    EndTimestamp,
+    Fst,
+    Bitmaps,
+    IdMap,
+    KeyMap,
+    SeriesOffsets,
+    Storage,
+    Sequence,
+}
+pub const NUM_SECTIONS: usize = 9;
+
+// Serialize sections (ordered as in Section) into the bytes of a block file.
+pub fn write_block_file(sections: Vec<Vec<u8>>) -> Vec<u8> {
+    assert!(sections.len() == NUM_SECTIONS);
+
+    // Create a header of end offsets.
+    let header_size = PREAMBLE_SIZE + NUM_SECTIONS * 8;
+    let mut data: Vec<u8> = Vec::with_capacity(header_size);
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    data.extend_from_slice(&(NUM_SECTIONS as u32).to_le_bytes());
+    let mut cum = header_size as u64;
+    for s in sections.iter() {
+        cum += s.len() as u64;
+        data.extend_from_slice(&cum.to_le_bytes());
+    }
+
+    // Append each section.
+    for s in sections.iter() {
+        data.extend_from_slice(s);
+    }
+    data
+}
+
+// BlockFileReader Struct. Validates a block file's header and hands out its sections.
+pub struct BlockFileReader<'a> {
+    bytes: &'a [u8],
+    offsets: Vec<usize>,
+}
+impl<'a> BlockFileReader<'a> {
+    // Parse and validate the header.
+    pub fn new(bytes: &'a [u8]) -> Result<Self, Error> {
+        // Check the magic number and version.
+        if bytes.len() < PREAMBLE_SIZE || &bytes[0..4] != MAGIC {
+            return Err(Error::UnsupportedBlockFormat(String::from(
+                "missing magic number; block was written by an older version and must be regenerated",
+            )));
+        }
+        let version = read_u32(&bytes[4..8]);
+        if version != FORMAT_VERSION {
+            return Err(Error::UnsupportedBlockFormat(format!(
+                "format version {} (expected {})",
+                version, FORMAT_VERSION
+            )));
+        }
+        let num_sections = read_u32(&bytes[8..12]) as usize;
+        if num_sections != NUM_SECTIONS {
+            return Err(Error::CorruptBlock(format!(
+                "{} sections (expected {})",
+                num_sections, NUM_SECTIONS
+            )));
+        }
+
+        // Read the section offsets and make sure they fit the file.
+        let header_size = PREAMBLE_SIZE + NUM_SECTIONS * 8;
+        if bytes.len() < header_size {
+            return Err(Error::CorruptBlock(String::from("truncated header")));
+        }
+        let mut offsets = vec![header_size];
+        for i in 0..NUM_SECTIONS {
+            let pos = PREAMBLE_SIZE + i * 8;
+            let offset = u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap()) as usize;
+            if offset < offsets[i] || offset > bytes.len() {
+                return Err(Error::CorruptBlock(format!("bad offset for section {}", i)));
+            }
+            offsets.push(offset);
+        }
+        if offsets[NUM_SECTIONS] != bytes.len() {
+            return Err(Error::CorruptBlock(String::from(
+                "trailing bytes after last section",
+            )));
+        }
+        Ok(BlockFileReader { bytes, offsets })
+    }
+
+    // Get the bytes of a section.
+    pub fn section(&self, section: Section) -> &'a [u8] {
+        &self.bytes[self.section_range(section)]
+    }
+
+    // Get the position of a section within the file.
+    pub fn section_range(&self, section: Section) -> Range<usize> {
+        let i = section as usize;
+        self.offsets[i]..self.offsets[i + 1]
+    }
+
+    // Decode a timestamp section (millis since the epoch).
+    pub fn timestamp(&self, section: Section) -> Result<DateTime<Utc>, Error> {
+        let bytes: [u8; 8] = self
+            .section(section)
+            .try_into()
+            .map_err(|_| Error::CorruptBlock(format!("bad {:?} section", section)))?;
+        decode_timestamp(i64::from_le_bytes(bytes))
+    }
+
+    // Decode the sequence section.
+    pub fn sequence(&self) -> Result<u64, Error> {
+        let bytes: [u8; 8] = self
+            .section(Section::Sequence)
+            .try_into()
+            .map_err(|_| Error::CorruptBlock(String::from("bad Sequence section")))?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+// Convert a stored timestamp (millis since the epoch) into a DateTime, failing on
+// values out of chrono's range rather than panicking.
+pub fn decode_timestamp(millis: i64) -> Result<DateTime<Utc>, Error> {
+    let secs = millis.div_euclid(1000);
+    let nanos = (millis.rem_euclid(1000) * 1_000_000) as u32;
+    NaiveDateTime::from_timestamp_opt(secs, nanos)
+        .map(|x| DateTime::from_utc(x, Utc))
+        .ok_or_else(|| Error::CorruptBlock(format!("bad timestamp {}", millis)))
+}
+
+// Serialize a list of items as a sequence of u32 length-prefixed entries.
+// Returns the bytes and the offset at which each entry starts.
+pub fn write_entries(entries: Vec<Vec<u8>>) -> (Vec<u8>, Vec<u64>) {
+    let mut data = vec![];
+    let mut offsets = vec![];
+    for e in entries.iter() {
+        offsets.push(data.len() as u64);
+        data.extend_from_slice(&(e.len() as u32).to_le_bytes());
+        data.extend_from_slice(e);
+    }
+    (data, offsets)
+}
+
+// Get the length-prefixed entry starting at offset.
+pub fn read_entry(bytes: &[u8], offset: usize) -> Result<&[u8], Error> {
+    let len = bytes
+        .get(offset..offset + 4)
+        .map(read_u32)
+        .ok_or_else(|| Error::CorruptBlock(format!("bad entry offset {}", offset)))?;
+    bytes
+        .get(offset + 4..offset + 4 + len as usize)
+        .ok_or_else(|| Error::CorruptBlock(format!("truncated entry at {}", offset)))
+}
+
+// Serialize a list of u64 offsets.
+pub fn write_offsets(offsets: &[u64]) -> Vec<u8> {
+    offsets
+        .iter()
+        .flat_map(|x| x.to_le_bytes().to_vec())
+        .collect()
+}
+
+// Get the range of the i-th item in a section, given the section's u64 end offsets.
+pub fn read_offset_range(offsets: &[u8], i: usize) -> Result<Range<usize>, Error> {
+    let read = |j: usize| {
+        offsets
+            .get(j * 8..j * 8 + 8)
+            .map(|x| u64::from_le_bytes(x.try_into().unwrap()) as usize)
+    };
+    let start = if i == 0 { Some(0) } else { read(i - 1) };
+    match (start, read(i)) {
+        (Some(start), Some(end)) if start <= end => Ok(start..end),
+        _ => Err(Error::CorruptBlock(format!("bad offset for item {}", i))),
+    }
+}
+
+// Read a little-endian u32 from a 4-byte slice.
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes.try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_sections() -> Vec<Vec<u8>> {
+        vec![
+            10_000i64.to_le_bytes().to_vec(),
+            20_500i64.to_le_bytes().to_vec(),
+            vec![1, 2, 3],
+            vec![],
+            vec![4],
+            vec![5, 6],
+            write_offsets(&[1, 4]),
+            vec![7, 8, 9, 10],
+            3u64.to_le_bytes().to_vec(),
+        ]
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let bytes = write_block_file(make_sections());
+        let reader = BlockFileReader::new(&bytes).unwrap();
+        assert_eq!(reader.section(Section::Fst), &[1, 2, 3]);
+        assert_eq!(reader.section(Section::Bitmaps), &[] as &[u8]);
+        assert_eq!(reader.section(Section::Storage), &[7, 8, 9, 10]);
+        assert_eq!(
+            reader
+                .timestamp(Section::EndTimestamp)
+                .unwrap()
+                .timestamp_millis(),
+            20_500
+        );
+        assert_eq!(reader.sequence().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_entries_offsets() {
+        let (bytes, offsets) = write_entries(vec![vec![1, 2], vec![], vec![3]]);
+        assert_eq!(offsets, vec![0, 6, 10]);
+        assert_eq!(read_entry(&bytes, 0).unwrap(), &[1, 2]);
+        assert_eq!(read_entry(&bytes, 6).unwrap(), &[] as &[u8]);
+        assert_eq!(read_entry(&bytes, 10).unwrap(), &[3]);
+        assert!(read_entry(&bytes, 12).is_err());
+
+        let offsets = write_offsets(&[1, 4]);
+        assert_eq!(read_offset_range(&offsets, 0).unwrap(), 0..1);
+        assert_eq!(read_offset_range(&offsets, 1).unwrap(), 1..4);
+        assert!(read_offset_range(&offsets, 2).is_err());
+    }
+
+    #[test]
+    fn test_reject_legacy() {
+        // Legacy blocks started directly with a native-endian usize header.
+        let mut bytes = 56usize.to_ne_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 64]);
+        match BlockFileReader::new(&bytes) {
+            Err(Error::UnsupportedBlockFormat(_)) => (),
+            _ => panic!("legacy block was not rejected"),
+        }
+    }
+
+    #[test]
+    fn test_reject_version() {
+        let mut bytes = write_block_file(make_sections());
+        bytes[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        match BlockFileReader::new(&bytes) {
+            Err(Error::UnsupportedBlockFormat(_)) => (),
+            _ => panic!("unknown version was not rejected"),
+        }
+    }
+
+    #[test]
+    fn test_reject_truncated() {
+        let bytes = write_block_file(make_sections());
+        match BlockFileReader::new(&bytes[..bytes.len() - 1]) {
+            Err(Error::CorruptBlock(_)) => (),
+            _ => panic!("truncated block was not rejected"),
+        }
+    }
+}
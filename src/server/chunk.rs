@@ -0,0 +1,479 @@
+use crate::error::Error;
+use std::convert::TryInto;
+
+// On-disk layout of a chunk (all integers little-endian):
+//   point count (u32) | column count (u32) | byte length of each column (u32 each)
+// followed by the columns, back to back. Column 0 holds the timestamps, encoded as
+// delta-of-deltas; every other column holds one variable, XOR-encoded.
+const CHUNK_HEADER_SIZE: usize = 8;
+
+// ChunkEncoder Struct. Compresses the points of a series, one row at a time.
+pub struct ChunkEncoder {
+    count: u32,
+    timestamps: TimestampEncoder,
+    columns: Vec<FloatEncoder>,
+}
+impl ChunkEncoder {
+    // Constructor.
+    pub fn new(num_columns: usize) -> Self {
+        ChunkEncoder {
+            count: 0,
+            timestamps: TimestampEncoder::new(),
+            columns: (0..num_columns).map(|_| FloatEncoder::new()).collect(),
+        }
+    }
+
+    // Append a point. Values must be given in the same column order for every point.
+    pub fn push(&mut self, timestamp: i64, values: &[f64]) {
+        assert!(values.len() == self.columns.len());
+        self.count += 1;
+        self.timestamps.push(timestamp);
+        for (column, value) in self.columns.iter_mut().zip(values) {
+            column.push(*value);
+        }
+    }
+
+    // Finish all columns and return the chunk's bytes.
+    pub fn finish(self) -> Vec<u8> {
+        let mut columns = vec![self.timestamps.finish()];
+        columns.extend(self.columns.into_iter().map(|x| x.finish()));
+
+        // Write header, then each column.
+        let mut data = vec![];
+        data.extend_from_slice(&self.count.to_le_bytes());
+        data.extend_from_slice(&(columns.len() as u32 - 1).to_le_bytes());
+        for c in columns.iter() {
+            data.extend_from_slice(&(c.len() as u32).to_le_bytes());
+        }
+        for c in columns.iter() {
+            data.extend_from_slice(c);
+        }
+        data
+    }
+}
+
+// ChunkDecoder Struct. Streams the points of a chunk back out, in insertion order.
+pub struct ChunkDecoder<'a> {
+    remaining: u32,
+    timestamps: TimestampDecoder<'a>,
+    columns: Vec<FloatDecoder<'a>>,
+}
+impl<'a> ChunkDecoder<'a> {
+    // Parse the header and position a reader at the start of every column.
+    pub fn new(bytes: &'a [u8]) -> Result<Self, Error> {
+        if bytes.len() < CHUNK_HEADER_SIZE {
+            return Err(Error::CorruptBlock(String::from("truncated chunk header")));
+        }
+        let count = read_u32(&bytes[0..4]);
+        let num_columns = read_u32(&bytes[4..8]) as usize;
+
+        // Split out the columns.
+        let mut pos = CHUNK_HEADER_SIZE + (num_columns + 1) * 4;
+        let mut columns = vec![];
+        for i in 0..num_columns + 1 {
+            let len = bytes
+                .get(CHUNK_HEADER_SIZE + i * 4..CHUNK_HEADER_SIZE + i * 4 + 4)
+                .map(read_u32)
+                .ok_or_else(|| Error::CorruptBlock(String::from("truncated chunk header")))?;
+            let column = bytes
+                .get(pos..pos + len as usize)
+                .ok_or_else(|| Error::CorruptBlock(format!("truncated chunk column {}", i)))?;
+            columns.push(column);
+            pos += len as usize;
+        }
+        Ok(ChunkDecoder {
+            remaining: count,
+            timestamps: TimestampDecoder::new(columns[0]),
+            columns: columns[1..].iter().map(|x| FloatDecoder::new(x)).collect(),
+        })
+    }
+}
+impl<'a> Iterator for ChunkDecoder<'a> {
+    type Item = Result<(i64, Vec<f64>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let decoded = self.timestamps.next().and_then(|timestamp| {
+            let values: Option<Vec<f64>> = self.columns.iter_mut().map(|x| x.next()).collect();
+            values.map(|values| (timestamp, values))
+        });
+        match decoded {
+            Some(point) => Some(Ok(point)),
+            None => {
+                // Stop here, rather than yielding the same error forever.
+                self.remaining = 0;
+                Some(Err(Error::CorruptBlock(String::from("truncated chunk"))))
+            }
+        }
+    }
+}
+
+// TimestampEncoder Struct. Stores each timestamp as the change in its delta from the
+// previous one, which is almost always zero for regularly sampled series.
+struct TimestampEncoder {
+    writer: BitWriter,
+    prev: Option<i64>,
+    prev_delta: i64,
+}
+impl TimestampEncoder {
+    fn new() -> Self {
+        TimestampEncoder {
+            writer: BitWriter::new(),
+            prev: None,
+            prev_delta: 0,
+        }
+    }
+
+    fn push(&mut self, timestamp: i64) {
+        // The first timestamp is stored as is.
+        let prev = match self.prev {
+            Some(prev) => prev,
+            None => {
+                self.writer.write_bits(timestamp as u64, 64);
+                self.prev = Some(timestamp);
+                return;
+            }
+        };
+
+        // Pick the smallest bucket the delta-of-delta fits in.
+        let delta = timestamp.wrapping_sub(prev);
+        let dod = delta.wrapping_sub(self.prev_delta);
+        match dod {
+            0 => self.writer.write_bits(0b0, 1),
+            -64..=63 => {
+                self.writer.write_bits(0b10, 2);
+                self.writer.write_bits(dod as u64, 7);
+            }
+            -256..=255 => {
+                self.writer.write_bits(0b110, 3);
+                self.writer.write_bits(dod as u64, 9);
+            }
+            -2048..=2047 => {
+                self.writer.write_bits(0b1110, 4);
+                self.writer.write_bits(dod as u64, 12);
+            }
+            _ => {
+                self.writer.write_bits(0b1111, 4);
+                self.writer.write_bits(dod as u64, 64);
+            }
+        }
+        self.prev = Some(timestamp);
+        self.prev_delta = delta;
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.writer.finish()
+    }
+}
+
+// TimestampDecoder Struct.
+struct TimestampDecoder<'a> {
+    reader: BitReader<'a>,
+    prev: Option<i64>,
+    prev_delta: i64,
+}
+impl<'a> TimestampDecoder<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        TimestampDecoder {
+            reader: BitReader::new(bytes),
+            prev: None,
+            prev_delta: 0,
+        }
+    }
+
+    fn next(&mut self) -> Option<i64> {
+        let prev = match self.prev {
+            Some(prev) => prev,
+            None => {
+                let timestamp = self.reader.read_bits(64)? as i64;
+                self.prev = Some(timestamp);
+                return Some(timestamp);
+            }
+        };
+
+        // Count the leading ones (up to four) to find the bucket.
+        let mut prefix = 0;
+        while prefix < 4 && self.reader.read_bit()? {
+            prefix += 1;
+        }
+        let dod = match prefix {
+            0 => 0,
+            1 => sign_extend(self.reader.read_bits(7)?, 7),
+            2 => sign_extend(self.reader.read_bits(9)?, 9),
+            3 => sign_extend(self.reader.read_bits(12)?, 12),
+            _ => self.reader.read_bits(64)? as i64,
+        };
+        let delta = self.prev_delta.wrapping_add(dod);
+        let timestamp = prev.wrapping_add(delta);
+        self.prev = Some(timestamp);
+        self.prev_delta = delta;
+        Some(timestamp)
+    }
+}
+
+// FloatEncoder Struct. Stores each value XORed with the previous one, keeping only the
+// meaningful bits; slowly changing values share most of their bits.
+struct FloatEncoder {
+    writer: BitWriter,
+    prev: Option<u64>,
+    // Leading and trailing zeros of the last stored XOR, if any.
+    window: Option<(u32, u32)>,
+}
+impl FloatEncoder {
+    fn new() -> Self {
+        FloatEncoder {
+            writer: BitWriter::new(),
+            prev: None,
+            window: None,
+        }
+    }
+
+    fn push(&mut self, value: f64) {
+        let bits = value.to_bits();
+        let prev = match self.prev {
+            Some(prev) => prev,
+            None => {
+                self.writer.write_bits(bits, 64);
+                self.prev = Some(bits);
+                return;
+            }
+        };
+        self.prev = Some(bits);
+
+        // Identical values take a single bit.
+        let xor = bits ^ prev;
+        if xor == 0 {
+            self.writer.write_bits(0b0, 1);
+            return;
+        }
+        self.writer.write_bits(0b1, 1);
+
+        // Reuse the previous window if the meaningful bits fit in it.
+        let leading = xor.leading_zeros().min(31);
+        let trailing = xor.trailing_zeros();
+        match self.window {
+            Some((prev_leading, prev_trailing))
+                if leading >= prev_leading && trailing >= prev_trailing =>
+            {
+                self.writer.write_bits(0b0, 1);
+                self.writer
+                    .write_bits(xor >> prev_trailing, 64 - prev_leading - prev_trailing);
+            }
+            _ => {
+                // A length of 64 doesn't fit in six bits, and is stored as 0.
+                let len = 64 - leading - trailing;
+                self.writer.write_bits(0b1, 1);
+                self.writer.write_bits(leading as u64, 5);
+                self.writer.write_bits((len % 64) as u64, 6);
+                self.writer.write_bits(xor >> trailing, len);
+                self.window = Some((leading, trailing));
+            }
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.writer.finish()
+    }
+}
+
+// FloatDecoder Struct.
+struct FloatDecoder<'a> {
+    reader: BitReader<'a>,
+    prev: Option<u64>,
+    window: Option<(u32, u32)>,
+}
+impl<'a> FloatDecoder<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        FloatDecoder {
+            reader: BitReader::new(bytes),
+            prev: None,
+            window: None,
+        }
+    }
+
+    fn next(&mut self) -> Option<f64> {
+        let prev = match self.prev {
+            Some(prev) => prev,
+            None => {
+                let bits = self.reader.read_bits(64)?;
+                self.prev = Some(bits);
+                return Some(f64::from_bits(bits));
+            }
+        };
+        if !self.reader.read_bit()? {
+            return Some(f64::from_bits(prev));
+        }
+
+        // Either reuse the previous window or read a new one.
+        let (leading, trailing) = if self.reader.read_bit()? {
+            let leading = self.reader.read_bits(5)? as u32;
+            let len = match self.reader.read_bits(6)? as u32 {
+                0 => 64,
+                len => len,
+            };
+            if leading + len > 64 {
+                return None;
+            }
+            self.window = Some((leading, 64 - leading - len));
+            self.window?
+        } else {
+            self.window?
+        };
+        let xor = self.reader.read_bits(64 - leading - trailing)? << trailing;
+        let bits = prev ^ xor;
+        self.prev = Some(bits);
+        Some(f64::from_bits(bits))
+    }
+}
+
+// BitWriter Struct. Packs values into bytes, most significant bit first.
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u32,
+}
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            bytes: vec![],
+            used: 8,
+        }
+    }
+
+    // Write the low n bits of value.
+    fn write_bits(&mut self, value: u64, n: u32) {
+        for i in (0..n).rev() {
+            if self.used == 8 {
+                self.bytes.push(0);
+                self.used = 0;
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().unwrap() |= bit << (7 - self.used);
+            self.used += 1;
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+// BitReader Struct.
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, pos: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.pos / 8)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Some(bit == 1)
+    }
+
+    // Read n bits into the low bits of a value.
+    fn read_bits(&mut self, n: u32) -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..n {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Some(value)
+    }
+}
+
+// Interpret the low n bits of value as a two's complement integer.
+fn sign_extend(value: u64, n: u32) -> i64 {
+    let shift = 64 - n;
+    ((value << shift) as i64) >> shift
+}
+
+// Read a little-endian u32 from a 4-byte slice.
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes.try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn roundtrip(points: &[(i64, Vec<f64>)]) -> Vec<(i64, Vec<f64>)> {
+        let mut encoder = ChunkEncoder::new(points[0].1.len());
+        for (timestamp, values) in points.iter() {
+            encoder.push(*timestamp, values);
+        }
+        let bytes = encoder.finish();
+        ChunkDecoder::new(&bytes)
+            .unwrap()
+            .map(|x| x.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let points = vec![
+            (1_465_839_830_100, vec![58.0, 2.0]),
+            (1_465_839_840_100, vec![58.0, 2.5]),
+            (1_465_839_850_100, vec![-12.25, 2.5]),
+            (1_465_839_850_101, vec![f64::MAX, 0.0]),
+            (1_465_839_850_000, vec![f64::MIN_POSITIVE, -0.0]),
+            (0, vec![1e-300, f64::INFINITY]),
+            (i64::MAX, vec![0.1, 0.2]),
+        ];
+        assert_eq!(roundtrip(&points), points);
+    }
+
+    #[test]
+    fn test_timestamp_buckets() {
+        // Exercise the edge of every delta-of-delta bucket, in both directions.
+        let mut timestamp = 1_000_000;
+        let mut delta = 0;
+        let mut points = vec![(timestamp, vec![])];
+        for dod in [
+            0, 63, -64, 64, -65, 255, -256, 256, 2047, -2048, 2048, -2049,
+        ]
+        .iter()
+        {
+            delta += dod;
+            timestamp += delta;
+            points.push((timestamp, vec![]));
+        }
+        assert_eq!(roundtrip(&points), points);
+    }
+
+    #[test]
+    fn test_compression() {
+        // Regularly sampled, slowly changing values should shrink a lot.
+        let points: Vec<(i64, Vec<f64>)> = (0..1000)
+            .map(|i| (1_465_839_830_000 + i * 10_000, vec![50.0 + (i % 4) as f64]))
+            .collect();
+        let mut encoder = ChunkEncoder::new(1);
+        for (timestamp, values) in points.iter() {
+            encoder.push(*timestamp, values);
+        }
+        let bytes = encoder.finish();
+        assert!(bytes.len() * 10 < points.len() * 16);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut encoder = ChunkEncoder::new(1);
+        encoder.push(10, &[1.0]);
+        encoder.push(20, &[2.0]);
+        let bytes = encoder.finish();
+        assert!(ChunkDecoder::new(&bytes[..bytes.len() - 1]).is_err());
+
+        // Running out of column data surfaces as an error, then stops the stream.
+        let mut bytes = bytes;
+        bytes[0] = 100;
+        let decoded: Vec<_> = ChunkDecoder::new(&bytes).unwrap().collect();
+        assert!(decoded.len() < 100);
+        assert!(decoded[..decoded.len() - 1].iter().all(|x| x.is_ok()));
+        assert!(decoded[decoded.len() - 1].is_err());
+    }
+}
@@ -0,0 +1,240 @@
+use crate::server::{
+    record::Record,
+    store::{replace_blocks, Block, BlockIndex, BlockView, PackedBlock, Tombstone},
+};
+use std::{
+    collections::HashSet,
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+// CONSTANTS
+// How often the compactor looks for blocks to merge.
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(60);
+
+// Periodically merge flushed blocks into time partitions partition_millis wide (the
+// same windows blocks are flushed in). Runs until stop_rx is signalled or closed.
+pub fn db_compact(
+    shared_index: Arc<RwLock<BlockIndex>>,
+    partition_millis: i64,
+    stop_rx: Receiver<()>,
+) {
+    while stop_rx.recv_timeout(COMPACTION_INTERVAL) == Err(RecvTimeoutError::Timeout) {
+        compact(&shared_index, partition_millis);
+    }
+}
+
+// Merge every group of overlapping or same-partition blocks into a single block, and
+// rewrite blocks with tombstones without their deleted points.
+pub fn compact(shared_index: &Arc<RwLock<BlockIndex>>, partition_millis: i64) {
+    // Snapshot the flushed blocks. The mapped files stay readable even if they are
+    // removed from the index in the meantime.
+    let (packed_blocks, tombstoned) = {
+        let index = shared_index.read().expect("RwLock poisoned");
+        (index.get_packed_blocks(), index.get_tombstoned_filepaths())
+    };
+
+    for group in plan(packed_blocks, &tombstoned, partition_millis) {
+        let tombstones: Vec<Tombstone> = {
+            let index = shared_index.read().expect("RwLock poisoned");
+            group
+                .iter()
+                .flat_map(|x| index.get_tombstones(&x.get_filepath()))
+                .collect()
+        };
+
+        // Merge the group in memory, without holding any locks.
+        let mut blocks = vec![];
+        for packed_block in group.iter() {
+            match packed_block.unpack() {
+                Ok(block) => blocks.push(block),
+                Err(e) => println!("Not compacting {}: {}", packed_block.get_filepath(), e),
+            }
+        }
+        if blocks.len() != group.len() {
+            continue;
+        }
+        let mut merged = merge(blocks, &tombstones);
+
+        // Swap the merged block in for its sources, unless one of them has gone away.
+        // If everything was deleted, the sources are just dropped.
+        let applied: Vec<u64> = tombstones.iter().map(|x| x.get_id()).collect();
+        let new = merged.get_start_timestamp().map(|_| &mut merged);
+        match replace_blocks(shared_index, &group, new, &applied) {
+            Ok(true) => println!("Compacted {} blocks.", group.len()),
+            Ok(false) => (),
+            Err(e) => println!("Failed to compact blocks: {}", e),
+        }
+    }
+}
+
+// Group blocks to merge: a block joins the previous one's group if it overlaps the
+// group's time range or starts in the same partition. Only groups of two or more
+// blocks, or with tombstones to apply, are returned.
+fn plan(
+    mut packed_blocks: Vec<PackedBlock>,
+    tombstoned: &HashSet<String>,
+    partition_millis: i64,
+) -> Vec<Vec<PackedBlock>> {
+    let needs_rewrite = |group: &Vec<PackedBlock>| {
+        group.len() > 1 || group.iter().any(|x| tombstoned.contains(&x.get_filepath()))
+    };
+    packed_blocks.sort_by_key(|x| x.get_start_timestamp());
+    let mut groups: Vec<Vec<PackedBlock>> = vec![];
+    let mut current: Vec<PackedBlock> = vec![];
+    let mut group_end = 0;
+    let mut group_partition = 0;
+    for packed_block in packed_blocks {
+        let start = packed_block
+            .get_start_timestamp()
+            .unwrap()
+            .timestamp_millis();
+        let end = packed_block.get_end_timestamp().unwrap().timestamp_millis();
+        let partition = start.div_euclid(partition_millis);
+        if !current.is_empty() && (start <= group_end || partition == group_partition) {
+            group_end = group_end.max(end);
+            current.push(packed_block);
+        } else {
+            if needs_rewrite(&current) {
+                groups.push(current);
+            }
+            current = vec![packed_block];
+            group_end = end;
+            group_partition = partition;
+        }
+    }
+    if needs_rewrite(&current) {
+        groups.push(current);
+    }
+    groups
+}
+
+// Merge blocks into one, rebuilding its index. Points with the same series key and
+// timestamp are kept once, the newest write winning, and points deleted by a tombstone
+// are dropped. The merged block takes the flush order of the newest block.
+fn merge(mut blocks: Vec<Block>, tombstones: &[Tombstone]) -> Block {
+    // Gather every point, newest write first: later blocks, and within a series later
+    // inserts, hold later writes.
+    blocks.sort_by_key(|x| x.get_sequence());
+    let mut records: Vec<Record> = vec![];
+    for block in blocks.iter() {
+        for id in 0..block.get_storage().len() {
+            records.extend(block.get_series_records(id as u32));
+        }
+    }
+    records.reverse();
+
+    // Records sort latest-first, so reverse the comparison. The sort is stable, so
+    // dedup keeps the newest write of each point.
+    records.sort_by(|a, b| b.cmp(a));
+    records.dedup();
+    records.retain(|r| !tombstones.iter().any(|t| t.matches(r)));
+
+    // Insert into a new block.
+    let mut merged = Block::new();
+    merged.set_sequence(blocks.iter().map(|x| x.get_sequence()).max().unwrap_or(0));
+    for record in records {
+        if let Err(e) = merged.insert(record) {
+            println!("Dropping record while compacting: {}", e);
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::test_util::{make_record, pack_block};
+    use std::env;
+    use uuid::Uuid;
+
+    fn make_packed_block(secs: &[i64]) -> PackedBlock {
+        let mut block = Block::new();
+        for s in secs.iter() {
+            block.insert(make_record("host_0", *s as f64, *s)).unwrap();
+        }
+        pack_block(&block)
+    }
+
+    #[test]
+    fn test_plan() {
+        let hour = 60 * 60;
+        let groups = plan(
+            vec![
+                // Overlaps the block after it, across a partition boundary.
+                make_packed_block(&[hour + 10, 2 * hour + 10]),
+                make_packed_block(&[2 * hour, 2 * hour + 20]),
+                // Alone in its partition.
+                make_packed_block(&[5 * hour, 5 * hour + 10]),
+                // Adjacent blocks in the same partition.
+                make_packed_block(&[8 * hour, 8 * hour + 10]),
+                make_packed_block(&[8 * hour + 20, 8 * hour + 30]),
+                make_packed_block(&[8 * hour + 40, 8 * hour + 50]),
+            ],
+            &HashSet::new(),
+            2 * hour * 1000,
+        );
+        let sizes: Vec<usize> = groups.iter().map(|x| x.len()).collect();
+        assert_eq!(sizes, vec![2, 3]);
+
+        // A block alone in its partition is still rewritten if it has tombstones.
+        let tombstoned_block = make_packed_block(&[5 * hour, 5 * hour + 10]);
+        let mut tombstoned = HashSet::new();
+        tombstoned.insert(tombstoned_block.get_filepath());
+        let groups = plan(
+            vec![
+                tombstoned_block,
+                make_packed_block(&[9 * hour, 9 * hour + 10]),
+            ],
+            &tombstoned,
+            2 * hour * 1000,
+        );
+        let sizes: Vec<usize> = groups.iter().map(|x| x.len()).collect();
+        assert_eq!(sizes, vec![1]);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut old_block = Block::new();
+        old_block.set_sequence(1);
+        old_block.insert(make_record("host_0", 1.0, 10)).unwrap();
+        old_block.insert(make_record("host_0", 3.0, 30)).unwrap();
+        old_block.insert(make_record("host_1", 5.0, 10)).unwrap();
+        let mut new_block = Block::new();
+        new_block.set_sequence(2);
+        new_block.insert(make_record("host_0", 2.0, 20)).unwrap();
+        new_block.insert(make_record("host_0", 4.0, 30)).unwrap();
+        new_block.insert(make_record("host_1", 6.0, 10)).unwrap();
+        new_block.insert(make_record("host_1", 7.0, 10)).unwrap();
+
+        // The newest write of a point wins, whatever order the blocks come in.
+        let usages = |merged: &Block, hostname: &str| -> Vec<f64> {
+            let series = merged
+                .search_index(format!("hostname={}", hostname))
+                .unwrap();
+            merged
+                .get_series_records(series.iter().next().unwrap())
+                .map(|r| *r.get_metric("usage_user".to_string()).unwrap())
+                .collect()
+        };
+        let merged = merge(vec![new_block, old_block], &[]);
+        assert_eq!(merged.get_storage().len(), 2);
+        assert_eq!(merged.get_sequence(), 2);
+        assert_eq!(usages(&merged, "host_0"), vec![1.0, 2.0, 4.0]);
+        assert_eq!(usages(&merged, "host_1"), vec![7.0]);
+    }
+
+    #[test]
+    fn test_stop() {
+        // The compactor returns as soon as it is told to, without waiting out its interval.
+        let dir = env::temp_dir().join(Uuid::new_v4().to_string());
+        let shared_index = Arc::new(RwLock::new(BlockIndex::new(dir.to_str().unwrap())));
+        let (stop_tx, stop_rx) = std::sync::mpsc::channel();
+        let compact_thr = std::thread::spawn(move || db_compact(shared_index, 1000, stop_rx));
+        stop_tx.send(()).unwrap();
+        compact_thr.join().unwrap();
+    }
+}
@@ -1,16 +1,54 @@
-use crate::server::operators::{Op, Select};
-use crate::{error::Error, server::record::Record};
+use crate::server::operators::{Delete, Op, Select};
+use crate::server::promql::{self, PromQuery};
+use crate::server::response::Response;
+use crate::{
+    error::Error,
+    server::record::{Record, SeriesResult},
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
 use std::sync::mpsc::{channel, Receiver, Sender};
 
+// SelectResult Enum. Results as one timestamp-ordered list of records, or grouped by
+// series if the select asked for it.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub enum SelectResult {
+    Records(Vec<Record>),
+    Series(Vec<SeriesResult>),
+}
+impl SelectResult {
+    // Get the results as records, flattening any series.
+    pub fn into_records(self) -> Vec<Record> {
+        match self {
+            SelectResult::Records(records) => records,
+            SelectResult::Series(series) => {
+                let mut records = vec![];
+                for s in series {
+                    for (timestamp, values) in s.points {
+                        records.push(Record::new(
+                            s.name.clone(),
+                            s.labels.clone(),
+                            s.variables.iter().cloned().zip(values).collect(),
+                            timestamp,
+                        ));
+                    }
+                }
+                records.sort_by(|a, b| b.cmp(a));
+                records
+            }
+        }
+    }
+}
+
 // SelectRequest struct.
 pub struct SelectRequest {
     pub statement: Select,
-    result_tx: Sender<Vec<Record>>,
+    result_tx: Sender<SelectResult>,
 }
 impl SelectRequest {
     // Constructor.
-    fn new(s: Select) -> (Self, Receiver<Vec<Record>>) {
-        let (tx, rx): (Sender<Vec<Record>>, Receiver<Vec<Record>>) = channel();
+    pub fn new(s: Select) -> (Self, Receiver<SelectResult>) {
+        let (tx, rx): (Sender<SelectResult>, Receiver<SelectResult>) = channel();
         (
             SelectRequest {
                 statement: s,
@@ -21,8 +59,43 @@ impl SelectRequest {
     }
 
     // Send a reply back to the receiver.
-    pub fn reply(&self, r: Vec<Record>) {
-        self.result_tx.send(r).unwrap();
+    // The requester may have gone away, in which case there's nobody to tell.
+    pub fn reply(&self, r: SelectResult) {
+        let _ = self.result_tx.send(r);
+    }
+}
+
+// Mutation Enum. A change to the stored data, applied (and logged) in order.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub enum Mutation {
+    Write(Record),
+    Delete(Delete),
+}
+
+// The outcome of a write: Ok once it's durable, or why it was rejected.
+pub type WriteResult = Result<(), Error>;
+
+// WriteRequest struct.
+pub struct WriteRequest {
+    pub mutation: Mutation,
+    result_tx: Sender<WriteResult>,
+}
+impl WriteRequest {
+    // Constructor.
+    pub fn new(m: Mutation) -> (Self, Receiver<WriteResult>) {
+        let (tx, rx): (Sender<WriteResult>, Receiver<WriteResult>) = channel();
+        (
+            WriteRequest {
+                mutation: m,
+                result_tx: tx,
+            },
+            rx,
+        )
+    }
+
+    // Acknowledge that the write is durable, or report why it was rejected.
+    pub fn reply(&self, r: WriteResult) {
+        let _ = self.result_tx.send(r);
     }
 }
 
@@ -31,29 +104,87 @@ impl SelectRequest {
 pub fn execute(
     operation: Op,
     read_tx: &Sender<SelectRequest>,
-    write_tx: &Sender<Record>,
-) -> Result<Option<Vec<Record>>, Error> {
+    write_tx: &Sender<WriteRequest>,
+) -> Result<Response, Error> {
     match operation {
         Op::Write(record) => execute_write(record, write_tx),
         Op::Select(statement) => execute_select(statement, read_tx),
+        Op::Delete(delete) => execute_delete(delete, write_tx),
+        Op::PromQL(query) => execute_promql(query, read_tx),
     }
 }
 
-// Execute a select.
-fn execute_select(
-    statement: Select,
-    tx: &Sender<SelectRequest>,
-) -> Result<Option<Vec<Record>>, Error> {
+// Send a select to the reader, and wait for its result.
+fn request_select(statement: Select, tx: &Sender<SelectRequest>) -> Result<SelectResult, Error> {
     let (request, rx) = SelectRequest::new(statement);
-    tx.send(request).unwrap();
-    let result = rx.recv().unwrap();
+    let closed = || Error::ChannelClosed(String::from("database reader is not running"));
+    tx.send(request).map_err(|_| closed())?;
+    rx.recv().map_err(|_| closed())
+}
+
+// Send a mutation to the writer, and wait for it to be logged or rejected.
+fn request_write(mutation: Mutation, tx: &Sender<WriteRequest>) -> Result<(), Error> {
+    let (request, rx) = WriteRequest::new(mutation);
+    let closed = || Error::ChannelClosed(String::from("database writer is not running"));
+    tx.send(request).map_err(|_| closed())?;
+    rx.recv().map_err(|_| closed())?
+}
+
+// Execute a select.
+fn execute_select(statement: Select, tx: &Sender<SelectRequest>) -> Result<Response, Error> {
+    statement.validate()?;
+    let result = request_select(statement, tx)?;
     println!("Received result: {:?}", result);
-    Ok(Some(result))
+    Ok(Response::Ok(result))
 }
 
-// Execute a write.
-fn execute_write(record: Record, tx: &Sender<Record>) -> Result<Option<Vec<Record>>, Error> {
-    let record_dup = record.clone();
-    tx.send(record).unwrap();
-    Ok(Some(vec![record_dup]))
+// Execute a PromQL query, running a select for each of its selectors.
+fn execute_promql(query: PromQuery, tx: &Sender<SelectRequest>) -> Result<Response, Error> {
+    let mut fetch = |statement: Select| Ok(request_select(statement, tx)?.into_records());
+    let result = promql::execute(&query, Utc::now(), &mut fetch)?;
+    Ok(Response::Ok(SelectResult::Records(result)))
+}
+
+// Execute a write. Only returns once the record has been logged or rejected.
+fn execute_write(record: Record, tx: &Sender<WriteRequest>) -> Result<Response, Error> {
+    request_write(Mutation::Write(record), tx)?;
+    Ok(Response::Written(1))
+}
+
+// Execute a delete. Only returns once the delete has been logged.
+fn execute_delete(delete: Delete, tx: &Sender<WriteRequest>) -> Result<Response, Error> {
+    delete.validate()?;
+    request_write(Mutation::Delete(delete), tx)?;
+    Ok(Response::Deleted)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ErrorCode;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_closed_channels() {
+        // With the database threads gone, requests fail instead of panicking.
+        let (read_tx, read_rx) = channel();
+        let (write_tx, write_rx) = channel();
+        drop(read_rx);
+        drop(write_rx);
+        let record = Record::new(
+            String::from("cpu"),
+            HashMap::new(),
+            HashMap::new(),
+            Utc::now(),
+        );
+        match execute(Op::Write(record), &read_tx, &write_tx) {
+            Err(e) => assert_eq!(e.code(), ErrorCode::Unavailable),
+            _ => panic!("closed channel was not reported"),
+        }
+        let query = PromQuery::new(String::from("cpu_usage_user"));
+        match execute(Op::PromQL(query), &read_tx, &write_tx) {
+            Err(Error::ChannelClosed(_)) => (),
+            _ => panic!("closed channel was not reported"),
+        }
+    }
 }
@@ -1,7 +1,19 @@
-mod execute;
+mod block_file;
+mod chunk;
+mod compact;
+pub mod execute;
 mod operators;
+mod promql;
+mod query;
 mod record;
+pub mod response;
+mod retention;
+mod rollup;
 mod server;
+mod signal;
 mod store;
+#[cfg(test)]
+mod test_util;
+mod wal;
 
 pub use server::server;
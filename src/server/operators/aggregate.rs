@@ -0,0 +1,421 @@
+use crate::server::record::Record;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+// AggregateFn Enum.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub enum AggregateFn {
+    Mean,
+    Sum,
+    Min,
+    Max,
+    Count,
+    First,
+    Last,
+    Stddev,
+    Percentile(f64),
+}
+impl AggregateFn {
+    // Apply the function to a bucket's values, which are in timestamp order.
+    fn apply(&self, values: &[f64]) -> f64 {
+        let count = values.len() as f64;
+        match self {
+            AggregateFn::Mean => values.iter().sum::<f64>() / count,
+            AggregateFn::Sum => values.iter().sum(),
+            AggregateFn::Min => values.iter().cloned().fold(f64::INFINITY, f64::min),
+            AggregateFn::Max => values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+            AggregateFn::Count => count,
+            AggregateFn::First => values[0],
+            AggregateFn::Last => values[values.len() - 1],
+            AggregateFn::Stddev => {
+                let mean = values.iter().sum::<f64>() / count;
+                let variance = values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / count;
+                variance.sqrt()
+            }
+            // Nearest-rank percentile. NaNs have no rank, so they're left out.
+            AggregateFn::Percentile(p) => {
+                let mut sorted: Vec<f64> = values.iter().cloned().filter(|x| !x.is_nan()).collect();
+                sorted.sort_by(f64::total_cmp);
+                let rank = ((p.clamp(0.0, 100.0) / 100.0) * sorted.len() as f64).ceil() as usize;
+                sorted.get(rank.max(1) - 1).cloned().unwrap_or(f64::NAN)
+            }
+        }
+    }
+
+    // Apply the function to a bucket's summary, if it can be computed from one.
+    fn apply_summary(&self, summary: &Summary) -> Option<f64> {
+        match self {
+            AggregateFn::Mean => Some(summary.sum / summary.count),
+            AggregateFn::Sum => Some(summary.sum),
+            AggregateFn::Min => Some(summary.min),
+            AggregateFn::Max => Some(summary.max),
+            AggregateFn::Count => Some(summary.count),
+            _ => None,
+        }
+    }
+}
+
+// Summary Struct. The min, max, sum and count of a set of values. Summaries of
+// disjoint sets combine exactly, which is what rollups rely on.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Summary {
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub count: f64,
+}
+impl Summary {
+    // Constructor, for a single value.
+    pub fn new(value: f64) -> Self {
+        Summary {
+            min: value,
+            max: value,
+            sum: value,
+            count: 1.0,
+        }
+    }
+
+    // Fold another summary into this one.
+    pub fn add(&mut self, other: &Summary) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+
+    // Encode summaries of several variables as a record's variables (e.g. "usage.min").
+    fn to_variables(summaries: &HashMap<String, Summary>) -> HashMap<String, f64> {
+        let mut variables = HashMap::new();
+        for (variable, summary) in summaries.iter() {
+            variables.insert(format!("{}.min", variable), summary.min);
+            variables.insert(format!("{}.max", variable), summary.max);
+            variables.insert(format!("{}.sum", variable), summary.sum);
+            variables.insert(format!("{}.count", variable), summary.count);
+        }
+        variables
+    }
+
+    // Decode summaries from a record's variables; incomplete summaries are skipped.
+    fn from_variables(variables: &HashMap<String, f64>) -> HashMap<String, Summary> {
+        let mut summaries = HashMap::new();
+        for key in variables.keys() {
+            if let Some(variable) = key.strip_suffix(".count") {
+                let get = |stat: &str| variables.get(&format!("{}.{}", variable, stat));
+                if let (Some(min), Some(max), Some(sum), Some(count)) =
+                    (get("min"), get("max"), get("sum"), get("count"))
+                {
+                    summaries.insert(
+                        variable.to_string(),
+                        Summary {
+                            min: *min,
+                            max: *max,
+                            sum: *sum,
+                            count: *count,
+                        },
+                    );
+                }
+            }
+        }
+        summaries
+    }
+}
+
+// Summarize records into one record per series and time bucket (of width millis),
+// holding the summary of each variable. The output is ordered by bucket.
+pub fn summarize(records: Vec<Record>, width: i64) -> Vec<Record> {
+    let mut groups: BTreeMap<(i64, String), (Record, HashMap<String, Summary>)> = BTreeMap::new();
+    for record in records {
+        let millis = record.get_timestamp().timestamp_millis();
+        let key = (millis - millis.rem_euclid(width), record.get_key());
+        let variables = record.get_populated_variables();
+        let (_, group) = groups
+            .entry(key)
+            .or_insert_with(|| (record, HashMap::new()));
+        for (variable, value) in variables {
+            let value = Summary::new(value);
+            group
+                .entry(variable)
+                .and_modify(|x| x.add(&value))
+                .or_insert(value);
+        }
+    }
+    groups
+        .into_iter()
+        .map(|((bucket, _), (record, group))| {
+            Record::new(
+                record.get_name(),
+                record.get_populated_labels(),
+                Summary::to_variables(&group),
+                millis_to_datetime(bucket),
+            )
+        })
+        .collect()
+}
+
+// Aggregation Struct. TODO: Make fields private.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Aggregation {
+    // Bucket width in milliseconds; if unset, everything falls into one bucket.
+    #[serde(default)]
+    pub group_by_time: Option<i64>,
+    // Label keys to group by, in addition to the metric name.
+    #[serde(default)]
+    pub group_by_labels: Vec<String>,
+    // Aggregate function to apply to each variable; other variables are dropped.
+    pub functions: HashMap<String, AggregateFn>,
+}
+
+// Identifies a single output row: bucket start, metric name and grouped label values.
+type GroupKey = (i64, String, Vec<Option<String>>);
+
+impl Aggregation {
+    // Aggregate a timestamp-ordered list of records into one record per bucket and group.
+    pub fn apply(&self, records: Vec<Record>) -> Vec<Record> {
+        // Collect the values of each variable for each group, preserving timestamp order.
+        let groups = self.group(records, |group: &mut HashMap<String, Vec<f64>>, record| {
+            for (variable, value) in record.get_populated_variables() {
+                if self.functions.contains_key(&variable) {
+                    group.entry(variable).or_default().push(value);
+                }
+            }
+        });
+
+        // Apply the aggregate functions to each group.
+        self.to_records(groups, |f, values| Some(f.apply(values)))
+    }
+
+    // Aggregate summary records (see summarize) instead of raw ones. Only valid if
+    // can_use_summaries holds and the summaries' buckets nest in group_by_time.
+    pub fn apply_summaries(&self, records: Vec<Record>) -> Vec<Record> {
+        // Combine the summaries of each variable for each group.
+        let groups = self.group(records, |group: &mut HashMap<String, Summary>, record| {
+            let summaries = Summary::from_variables(&record.get_populated_variables());
+            for (variable, summary) in summaries {
+                if self.functions.contains_key(&variable) {
+                    group
+                        .entry(variable)
+                        .and_modify(|x| x.add(&summary))
+                        .or_insert(summary);
+                }
+            }
+        });
+
+        // Apply the aggregate functions to each group's summaries.
+        self.to_records(groups, |f, summary| f.apply_summary(summary))
+    }
+
+    // Returns true if the results can be computed from summaries with buckets of width
+    // millis, rather than from raw points.
+    pub fn can_use_summaries(&self, width: i64) -> bool {
+        let functions_ok = self.functions.values().all(|f| {
+            matches!(
+                f,
+                AggregateFn::Mean
+                    | AggregateFn::Sum
+                    | AggregateFn::Min
+                    | AggregateFn::Max
+                    | AggregateFn::Count
+            )
+        });
+        functions_ok && self.group_by_time.is_some_and(|x| x > 0 && x % width == 0)
+    }
+
+    // Sort records into groups by bucket, metric name and grouped label values.
+    fn group<T>(
+        &self,
+        records: Vec<Record>,
+        mut add: impl FnMut(&mut HashMap<String, T>, Record),
+    ) -> BTreeMap<GroupKey, HashMap<String, T>> {
+        let mut groups: BTreeMap<GroupKey, HashMap<String, T>> = BTreeMap::new();
+        for record in records {
+            let labels = record.get_populated_labels();
+            let key = (
+                self.get_bucket(record.get_timestamp().timestamp_millis()),
+                record.get_name(),
+                self.group_by_labels
+                    .iter()
+                    .map(|k| labels.get(k).cloned())
+                    .collect(),
+            );
+            add(groups.entry(key).or_default(), record);
+        }
+        groups
+    }
+
+    // Turn groups into output records, computing each variable with finish.
+    fn to_records<T>(
+        &self,
+        groups: BTreeMap<GroupKey, HashMap<String, T>>,
+        finish: impl Fn(&AggregateFn, &T) -> Option<f64>,
+    ) -> Vec<Record> {
+        groups
+            .into_iter()
+            .map(|((bucket, name, label_values), group)| {
+                let labels = self
+                    .group_by_labels
+                    .iter()
+                    .cloned()
+                    .zip(label_values)
+                    .filter_map(|(k, v)| v.map(|v| (k, v)))
+                    .collect();
+                let variables = group
+                    .iter()
+                    .filter_map(|(variable, values)| {
+                        finish(&self.functions[variable], values).map(|x| (variable.clone(), x))
+                    })
+                    .collect();
+                Record::new(name, labels, variables, millis_to_datetime(bucket))
+            })
+            .collect()
+    }
+
+    // Get the start of the bucket a timestamp falls into.
+    fn get_bucket(&self, millis: i64) -> i64 {
+        match self.group_by_time {
+            Some(interval) if interval > 0 => millis - millis.rem_euclid(interval),
+            _ => 0,
+        }
+    }
+}
+
+// Convert milliseconds since the epoch into a DateTime.
+pub fn millis_to_datetime(millis: i64) -> DateTime<Utc> {
+    let secs = millis.div_euclid(1000);
+    let nanos = (millis.rem_euclid(1000) * 1_000_000) as u32;
+    DateTime::from_utc(NaiveDateTime::from_timestamp(secs, nanos), Utc)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_record(hostname: &str, usage: f64, secs: i64) -> Record {
+        let mut labels = HashMap::new();
+        labels.insert("hostname".to_string(), hostname.to_string());
+        let mut variables = HashMap::new();
+        variables.insert("usage_user".to_string(), usage);
+        variables.insert("usage_system".to_string(), usage * 2.0);
+        Record::new(
+            "cpu".to_string(),
+            labels,
+            variables,
+            millis_to_datetime(secs * 1000),
+        )
+    }
+
+    fn make_aggregation(f: AggregateFn, group_by_labels: Vec<String>) -> Aggregation {
+        let mut functions = HashMap::new();
+        functions.insert("usage_user".to_string(), f);
+        Aggregation {
+            group_by_time: Some(10_000),
+            group_by_labels,
+            functions,
+        }
+    }
+
+    fn usages(records: &[Record]) -> Vec<f64> {
+        records
+            .iter()
+            .map(|r| *r.get_metric("usage_user".to_string()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_time_buckets() {
+        let records = vec![
+            make_record("host_0", 1.0, 1),
+            make_record("host_1", 3.0, 5),
+            make_record("host_0", 10.0, 12),
+            make_record("host_1", 20.0, 19),
+            make_record("host_0", 7.0, 25),
+        ];
+
+        let res = make_aggregation(AggregateFn::Mean, vec![]).apply(records.clone());
+        assert_eq!(usages(&res), vec![2.0, 15.0, 7.0]);
+        assert_eq!(res[1].get_timestamp(), millis_to_datetime(10_000));
+        // Variables without a function are dropped, and ungrouped labels are removed.
+        assert_eq!(res[0].get_metric("usage_system".to_string()), None);
+        assert!(res[0].get_populated_labels().is_empty());
+
+        let res = make_aggregation(AggregateFn::Count, vec![]).apply(records.clone());
+        assert_eq!(usages(&res), vec![2.0, 2.0, 1.0]);
+        let res = make_aggregation(AggregateFn::First, vec![]).apply(records.clone());
+        assert_eq!(usages(&res), vec![1.0, 10.0, 7.0]);
+        let res = make_aggregation(AggregateFn::Last, vec![]).apply(records.clone());
+        assert_eq!(usages(&res), vec![3.0, 20.0, 7.0]);
+        let res = make_aggregation(AggregateFn::Max, vec![]).apply(records);
+        assert_eq!(usages(&res), vec![3.0, 20.0, 7.0]);
+    }
+
+    #[test]
+    fn test_group_by_labels() {
+        let records = vec![
+            make_record("host_0", 1.0, 1),
+            make_record("host_1", 3.0, 2),
+            make_record("host_0", 5.0, 3),
+        ];
+        let res = make_aggregation(AggregateFn::Sum, vec!["hostname".to_string()]).apply(records);
+        assert_eq!(usages(&res), vec![6.0, 3.0]);
+        assert_eq!(res[0].get_populated_labels()["hostname"], "host_0");
+        assert_eq!(res[1].get_populated_labels()["hostname"], "host_1");
+    }
+
+    #[test]
+    fn test_stddev_percentile() {
+        let values = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+        assert_eq!(AggregateFn::Stddev.apply(&values), 2.0);
+        assert_eq!(AggregateFn::Percentile(50.0).apply(&values), 4.0);
+        assert_eq!(AggregateFn::Percentile(100.0).apply(&values), 9.0);
+        assert_eq!(AggregateFn::Percentile(0.0).apply(&values), 2.0);
+        assert_eq!(AggregateFn::Min.apply(&values), 2.0);
+
+        // NaNs are skipped by percentiles.
+        let values = [f64::NAN, 3.0, 1.0, f64::NAN, 2.0];
+        assert_eq!(AggregateFn::Percentile(50.0).apply(&values), 2.0);
+        assert_eq!(AggregateFn::Percentile(100.0).apply(&values), 3.0);
+        assert!(AggregateFn::Percentile(50.0).apply(&[f64::NAN]).is_nan());
+    }
+
+    #[test]
+    fn deserialize_aggregation() {
+        let data = r#"
+        {
+            "group_by_time": 60000,
+            "group_by_labels": ["hostname"],
+            "functions": { "usage_user": "Mean", "usage_system": { "Percentile": 95.0 } }
+        }
+        "#;
+        let d: Aggregation = serde_json::from_str(data).unwrap();
+        assert_eq!(d.group_by_time, Some(60000));
+        assert_eq!(d.group_by_labels, vec!["hostname".to_string()]);
+        assert_eq!(d.functions["usage_user"], AggregateFn::Mean);
+        assert_eq!(d.functions["usage_system"], AggregateFn::Percentile(95.0));
+    }
+
+    #[test]
+    fn test_summaries() {
+        let records: Vec<Record> = (0..40)
+            .map(|i| make_record(if i % 2 == 0 { "host_0" } else { "host_1" }, i as f64, i))
+            .collect();
+        let summaries = summarize(records.clone(), 5000);
+        assert_eq!(summaries.len(), 16);
+
+        // Aggregating the summaries matches aggregating the raw points.
+        for f in [
+            AggregateFn::Mean,
+            AggregateFn::Sum,
+            AggregateFn::Min,
+            AggregateFn::Max,
+            AggregateFn::Count,
+        ] {
+            let aggregation = make_aggregation(f, vec!["hostname".to_string()]);
+            assert!(aggregation.can_use_summaries(5000));
+            assert!(!aggregation.can_use_summaries(3000));
+            let expected = aggregation.apply(records.clone());
+            let actual = aggregation.apply_summaries(summaries.clone());
+            assert_eq!(usages(&actual), usages(&expected));
+        }
+        assert!(!make_aggregation(AggregateFn::Last, vec![]).can_use_summaries(5000));
+    }
+}
@@ -0,0 +1,78 @@
+use crate::error::Error;
+use crate::server::operators::select::{Predicate, Select, TimeRange};
+use crate::server::record::Record;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+// Delete Struct. Removes the points matching a predicate, optionally within a time
+// range. TODO: Make fields private.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Delete {
+    pub predicate: Predicate,
+    #[serde(default)]
+    pub start: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub end: Option<DateTime<Utc>>,
+}
+impl Delete {
+    // Check the delete can be run, e.g. that its regexes compile.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.predicate.condition.validate()
+    }
+
+    // Returns true if the record is one of the points to delete.
+    pub fn matches(&self, record: &Record) -> bool {
+        self.get_time_range().contains(record.get_timestamp())
+            && self.predicate.condition.matches(record)
+    }
+
+    // Get the time range this delete is bounded by.
+    pub fn get_time_range(&self) -> TimeRange {
+        TimeRange {
+            start: self.start,
+            end: self.end,
+        }
+    }
+
+    // Get the select for the points this delete removes.
+    pub fn to_select(&self) -> Select {
+        Select {
+            name: String::from("delete"),
+            predicate: self.predicate.clone(),
+            start: self.start,
+            end: self.end,
+            aggregation: None,
+            by_series: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::test_util::make_record;
+
+    #[test]
+    fn test_matches() {
+        let data = r#"
+        {
+            "predicate": {
+                "name": "p",
+                "condition": {
+                    "And": [
+                        { "Leaf": { "lhs": {"LabelKey": "hostname"}, "rhs": {"LabelValue": "host_0"}, "op": "Eq" } },
+                        { "Leaf": { "lhs": {"Variable": "usage_user"}, "rhs": {"Metric": 50.0}, "op": "Gt" } }
+                    ]
+                }
+            },
+            "end": "1970-01-01T00:01:00Z"
+        }
+        "#;
+        let d: Delete = serde_json::from_str(data).unwrap();
+        assert_eq!(d.start, None);
+        assert!(d.matches(&make_record("host_0", 60.0, 30)));
+        assert!(!d.matches(&make_record("host_0", 40.0, 30)));
+        assert!(!d.matches(&make_record("host_1", 60.0, 30)));
+        assert!(!d.matches(&make_record("host_0", 60.0, 90)));
+    }
+}
@@ -1,7 +1,10 @@
+pub mod aggregate;
+pub mod delete;
 pub mod process;
 pub mod select;
 
-use crate::server::record::Record;
+use crate::server::{promql::PromQuery, record::Record};
+pub use delete::Delete;
 pub use select::Select;
 use serde::{Deserialize, Serialize};
 
@@ -9,6 +12,8 @@ use serde::{Deserialize, Serialize};
 pub enum Op {
     Select(select::Select),
     Write(Record),
+    Delete(delete::Delete),
+    PromQL(PromQuery),
 }
 
 #[cfg(test)]
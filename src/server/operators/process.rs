@@ -10,6 +10,10 @@ pub fn dnf(s: Select) -> Select {
     Select {
         name: s.name,
         predicate: new_pred,
+        start: s.start,
+        end: s.end,
+        aggregation: s.aggregation,
+        by_series: s.by_series,
     }
 }
 
@@ -25,6 +29,9 @@ fn dnf_helper(f: Conditions) -> Conditions {
             // If this is a Leaf, return.
             Conditions::Leaf(_) => f,
 
+            // If this is a Not, push it down a level and evaluate the result.
+            Conditions::Not(x) => dnf_helper(negate(*x)),
+
             // If this is an Or, then evaluate the children; we can safely skip this.
             Conditions::Or(l, r) => {
                 Conditions::Or(Box::new(dnf_helper(*l)), Box::new(dnf_helper(*r)))
@@ -52,6 +59,24 @@ fn dnf_helper(f: Conditions) -> Conditions {
     }
 }
 
+// Negates f one level down, using De Morgan's laws on Ands and Ors and flipping the
+// op of a Leaf (see Condition::negate). Leaves without an opposite stay wrapped in a Not.
+fn negate(f: Conditions) -> Conditions {
+    match f {
+        Conditions::Leaf(x) => match x.negate() {
+            Some(y) => y,
+            None => Conditions::Not(Box::new(Conditions::Leaf(x))),
+        },
+        Conditions::Not(x) => *x,
+        Conditions::And(l, r) => {
+            Conditions::Or(Box::new(Conditions::Not(l)), Box::new(Conditions::Not(r)))
+        }
+        Conditions::Or(l, r) => {
+            Conditions::And(Box::new(Conditions::Not(l)), Box::new(Conditions::Not(r)))
+        }
+    }
+}
+
 // Generates the left and right after distributing x over or.
 fn pushdown_disjunction(x: Conditions, or: Conditions) -> (Conditions, Conditions) {
     match or {
@@ -71,12 +96,16 @@ fn is_or(f: Conditions) -> bool {
     }
 }
 
-// Returns true if the subtree doesn't contain any Ors.
+// Returns true if the subtree doesn't contain any Ors, or Nots left to push down.
 fn is_all_and(f: Conditions) -> bool {
     match f {
         Conditions::Leaf(_) => true,
         Conditions::And(l, r) => is_all_and(*l) && is_all_and(*r),
         Conditions::Or(_, _) => false,
+        Conditions::Not(x) => match *x {
+            Conditions::Leaf(y) => y.negate().is_none(),
+            _ => false,
+        },
     }
 }
 
@@ -390,4 +419,140 @@ mod test {
         let or123 = Conditions::Or(Box::new(or12), Box::new(or3));
         // TODO: Test this??
     }
+
+    #[test]
+    fn test_not() {
+        let a = Condition {
+            lhs: Type::LabelKey(String::from("A")),
+            rhs: Type::LabelValue(String::from("A")),
+            op: Op::Eq,
+        };
+        let x = Condition {
+            lhs: Type::Variable(String::from("X")),
+            rhs: Type::Metric(5.0),
+            op: Op::Gt,
+        };
+        let p = Condition {
+            lhs: Type::LabelKey(String::from("P")),
+            rhs: Type::LabelValue(String::from("P")),
+            op: Op::Prefix,
+        };
+        let not = |c: Conditions| Conditions::Not(Box::new(c));
+        let not_a = Condition {
+            op: Op::NEq,
+            ..a.clone()
+        };
+        let leaf = |c: &Condition| Box::new(Conditions::Leaf(c.clone()));
+        let x_le = Condition {
+            op: Op::LtEq,
+            ..x.clone()
+        };
+        let no_x = not(Conditions::Leaf(Condition {
+            lhs: Type::Variable(String::from("X")),
+            rhs: Type::Metric(0.0),
+            op: Op::Exists,
+        }));
+
+        // NOT X == X <= 5 OR NOT EXISTS X, keeping records without X.
+        let not_x = Conditions::Or(leaf(&x_le), Box::new(no_x.clone()));
+        assert_eq!(not_x, dnf_helper(not(Conditions::Leaf(x.clone()))));
+
+        // NOT (A AND X) == NOT A OR NOT X, with both ops flipped.
+        let cond = not(Conditions::And(leaf(&a), leaf(&x)));
+        let exp = Conditions::Or(leaf(&not_a), Box::new(not_x.clone()));
+        assert_eq!(exp, dnf_helper(cond));
+
+        // NOT (A OR X) AND P == NOT A AND NOT X AND P, distributed over NOT X's Or.
+        let cond = Conditions::And(Box::new(not(Conditions::Or(leaf(&a), leaf(&x)))), leaf(&p));
+        let and = |l: Conditions, r: Box<Conditions>| Box::new(Conditions::And(Box::new(l), r));
+        let exp = Conditions::Or(
+            and(Conditions::And(leaf(&x_le), leaf(&not_a)), leaf(&p)),
+            and(Conditions::And(Box::new(no_x), leaf(&not_a)), leaf(&p)),
+        );
+        assert_eq!(exp, dnf_helper(cond));
+
+        // Double negations cancel, and leaves without an opposite keep their Not.
+        let cond = not(not(Conditions::Leaf(a.clone())));
+        assert_eq!(Conditions::Leaf(a.clone()), dnf_helper(cond));
+        let cond = not(Conditions::Leaf(p.clone()));
+        assert_eq!(cond.clone(), dnf_helper(cond));
+    }
+
+    #[test]
+    fn test_not_eval() {
+        use crate::server::record::Record;
+        use crate::server::store::Block;
+        use chrono::{DateTime, NaiveDateTime, Utc};
+        use std::collections::HashMap;
+
+        // Only some series have usage_idle.
+        let mut block = Block::new();
+        for (secs, hostname, idle) in
+            [(1, "a", Some(2.0)), (2, "b", Some(8.0)), (3, "c", None)].iter()
+        {
+            let mut labels = HashMap::new();
+            labels.insert("hostname".to_string(), hostname.to_string());
+            let mut variables = HashMap::new();
+            variables.insert("usage_user".to_string(), *secs as f64);
+            if let Some(idle) = idle {
+                variables.insert("usage_idle".to_string(), *idle);
+            }
+            let timestamp = DateTime::from_utc(NaiveDateTime::from_timestamp(*secs, 0), Utc);
+            block
+                .insert(Record::new("cpu".to_string(), labels, variables, timestamp))
+                .unwrap();
+        }
+        let hostname = Condition {
+            lhs: Type::LabelKey(String::from("hostname")),
+            rhs: Type::LabelValue(String::from("b")),
+            op: Op::Eq,
+        };
+        let idle = Condition {
+            lhs: Type::Variable(String::from("usage_idle")),
+            rhs: Type::Metric(5.0),
+            op: Op::Gt,
+        };
+        let get_hostnames = |select: &Select| -> Vec<String> {
+            let mut result = select.eval(&block);
+            result.unpack(&block);
+            result
+                .into_vec()
+                .iter()
+                .map(|r| r.get_populated_labels()["hostname"].clone())
+                .collect()
+        };
+
+        // Evaluating the Not directly and after rewriting to DNF agree, and both keep
+        // records without the variable.
+        let not = |c: Conditions| Conditions::Not(Box::new(c));
+        for condition in [
+            not(Conditions::Leaf(idle.clone())),
+            not(Conditions::And(
+                Box::new(Conditions::Leaf(hostname.clone())),
+                Box::new(Conditions::Leaf(idle.clone())),
+            )),
+            not(Conditions::Or(
+                Box::new(Conditions::Leaf(hostname.clone())),
+                Box::new(Conditions::Leaf(idle.clone())),
+            )),
+        ] {
+            let select = Select {
+                name: String::from("s"),
+                predicate: Predicate {
+                    name: String::from("p"),
+                    condition,
+                },
+                start: None,
+                end: None,
+                aggregation: None,
+                by_series: false,
+            };
+            let mut direct = get_hostnames(&select);
+            let mut rewritten = get_hostnames(&dnf(select));
+            direct.sort();
+            rewritten.sort();
+            assert_eq!(direct, rewritten);
+            assert!(direct.contains(&String::from("c")));
+        }
+    }
 }
@@ -1,9 +1,14 @@
-use crate::server::record::Record;
-use crate::server::store::Block;
+use crate::error::Error;
+use crate::server::operators::aggregate::Aggregation;
+use crate::server::record::{Record, SeriesResult};
+use crate::server::store::{BlockView, PackedBlock};
+use chrono::{DateTime, Utc};
 use croaring::bitmap::Bitmap;
 use priority_queue::PriorityQueue;
+use regex::Regex;
+use regex_syntax::{hir::literal::Literals, Parser};
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, RwLock};
+use std::fmt;
 
 // Predicate Struct. TODO: Make fields private.
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
@@ -17,10 +22,86 @@ pub struct Predicate {
 pub struct Select {
     pub name: String,
     pub predicate: Predicate,
+    #[serde(default)]
+    pub start: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub end: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub aggregation: Option<Aggregation>,
+    // Return results grouped by series, rather than as one timestamp-ordered list.
+    #[serde(default)]
+    pub by_series: bool,
 }
 impl Select {
-    pub fn eval(&self, shared_block: &Arc<RwLock<Block>>) -> ResultSet {
-        self.predicate.condition.eval(shared_block)
+    pub fn eval<B: BlockView>(&self, block: &B) -> ResultSet {
+        self.predicate.condition.eval(block, &self.get_time_range())
+    }
+
+    // Check the select can be run, e.g. that its regexes compile.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.predicate.condition.validate()
+    }
+
+    // Returns false if the packed block cannot contain any matching series.
+    pub fn may_match(&self, packed_block: &PackedBlock) -> bool {
+        let overlaps = match (
+            packed_block.get_start_timestamp(),
+            packed_block.get_end_timestamp(),
+        ) {
+            (Some(start), Some(end)) => self.get_time_range().overlaps(start, end),
+            _ => true,
+        };
+        overlaps && self.predicate.condition.may_match(packed_block)
+    }
+
+    // Run the aggregation stage, if any, over the timestamp-ordered results.
+    pub fn aggregate(&self, records: Vec<Record>) -> Vec<Record> {
+        match &self.aggregation {
+            Some(aggregation) => aggregation.apply(records),
+            None => records,
+        }
+    }
+
+    // Returns true if the predicate only matches on labels, so whole series either
+    // match or don't, regardless of their values.
+    pub fn is_label_only(&self) -> bool {
+        self.predicate.condition.is_label_only()
+    }
+
+    // Get the time range this select is bounded by.
+    pub fn get_time_range(&self) -> TimeRange {
+        TimeRange {
+            start: self.start,
+            end: self.end,
+        }
+    }
+}
+
+// TimeRange Struct. Both bounds are inclusive; None means unbounded.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct TimeRange {
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+}
+impl TimeRange {
+    // Returns true if the timestamp falls within the range.
+    pub fn contains(&self, timestamp: DateTime<Utc>) -> bool {
+        !self.is_before(timestamp) && !self.is_after(timestamp)
+    }
+
+    // Returns true if [start, end] intersects the range.
+    pub fn overlaps(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+        !self.is_before(end) && !self.is_after(start)
+    }
+
+    // Returns true if the timestamp comes before the start of the range.
+    fn is_before(&self, timestamp: DateTime<Utc>) -> bool {
+        self.start.is_some_and(|start| timestamp < start)
+    }
+
+    // Returns true if the timestamp comes after the end of the range.
+    fn is_after(&self, timestamp: DateTime<Utc>) -> bool {
+        self.end.is_some_and(|end| timestamp > end)
     }
 }
 
@@ -31,21 +112,26 @@ pub enum Type {
     LabelValue(String),
     Variable(String),
     Metric(f64),
+    Expr(Expr),
 }
-impl Type {
-    fn to_string(&self) -> String {
+impl fmt::Display for Type {
+    // Formatter. Labels and variables are written bare.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Type::LabelKey(s) => String::from(s),
-            Type::LabelValue(s) => String::from(s),
-            Type::Variable(s) => String::from(s),
-            Type::Metric(v) => format!("{}", v),
+            Type::LabelKey(s) | Type::LabelValue(s) | Type::Variable(s) => write!(f, "{}", s),
+            Type::Metric(v) => write!(f, "{}", v),
+            Type::Expr(e) => write!(f, "{}", e),
         }
     }
-
-    fn extract_metric(&self) -> f64 {
+}
+impl Type {
+    // Get a numeric type as an expression.
+    fn to_expr(&self) -> Option<Expr> {
         match self {
-            Type::Metric(v) => *v,
-            _ => panic!(),
+            Type::Variable(s) => Some(Expr::Variable(String::from(s))),
+            Type::Metric(v) => Some(Expr::Metric(*v)),
+            Type::Expr(e) => Some(e.clone()),
+            _ => None,
         }
     }
 
@@ -62,23 +148,69 @@ impl Type {
             _ => false,
         }
     }
+}
 
-    fn is_variable(&self) -> bool {
+// Expr Enum. Arithmetic over a record's variables.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub enum Expr {
+    Variable(String),
+    Metric(f64),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+    Div(Box<Expr>, Box<Expr>),
+}
+impl Expr {
+    // Evaluate against a record. Returns None if the record lacks a variable.
+    pub fn eval(&self, record: &Record) -> Option<f64> {
         match self {
-            Type::Variable(_) => true,
-            _ => false,
+            Expr::Variable(s) => record.get_metric(String::from(s)).copied(),
+            Expr::Metric(v) => Some(*v),
+            Expr::Add(a, b) => Some(a.eval(record)? + b.eval(record)?),
+            Expr::Sub(a, b) => Some(a.eval(record)? - b.eval(record)?),
+            Expr::Mul(a, b) => Some(a.eval(record)? * b.eval(record)?),
+            Expr::Div(a, b) => Some(a.eval(record)? / b.eval(record)?),
         }
     }
 
-    fn is_metric(&self) -> bool {
+    // Get the variables the expression reads, without duplicates.
+    pub fn get_variables(&self) -> Vec<String> {
+        let mut variables = vec![];
+        self.collect_variables(&mut variables);
+        variables
+    }
+
+    fn collect_variables(&self, variables: &mut Vec<String>) {
         match self {
-            Type::Metric(_) => true,
-            _ => false,
+            Expr::Variable(s) => {
+                if !variables.contains(s) {
+                    variables.push(String::from(s));
+                }
+            }
+            Expr::Metric(_) => (),
+            Expr::Add(a, b) | Expr::Sub(a, b) | Expr::Mul(a, b) | Expr::Div(a, b) => {
+                a.collect_variables(variables);
+                b.collect_variables(variables);
+            }
+        }
+    }
+}
+impl fmt::Display for Expr {
+    // Formatter. Binary operations are parenthesized.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Variable(s) => write!(f, "{}", s),
+            Expr::Metric(v) => write!(f, "{}", v),
+            Expr::Add(a, b) => write!(f, "({} + {})", a, b),
+            Expr::Sub(a, b) => write!(f, "({} - {})", a, b),
+            Expr::Mul(a, b) => write!(f, "({} * {})", a, b),
+            Expr::Div(a, b) => write!(f, "({} / {})", a, b),
         }
     }
 }
 
-// Op Enum.
+// Op Enum. Match, NMatch (regex) and Prefix only apply to labels. Exists ignores the
+// right-hand side, checking only that a label or variable is present.
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub enum Op {
     Eq,
@@ -87,10 +219,23 @@ pub enum Op {
     Lt,
     GtEq,
     LtEq,
+    Match,
+    NMatch,
+    Prefix,
+    Exists,
 }
 
-fn make_filter(op: Box<dyn Fn(f64, f64) -> bool>, val: f64) -> Box<dyn Fn(f64) -> bool> {
-    Box::new(move |x| op(x, val))
+// Filter Type. A check on a single record, delayed until a result set is unpacked.
+type Filter = Box<dyn Fn(&Record) -> bool>;
+
+// Compile a comparison between two expressions into a filter. Records missing any
+// of the variables never pass.
+fn make_filter(lhs: Expr, rhs: Expr, op: &Op) -> Filter {
+    let op = op.get_op();
+    Box::new(move |record| match (lhs.eval(record), rhs.eval(record)) {
+        (Some(a), Some(b)) => op(a, b),
+        _ => false,
+    })
 }
 
 impl Op {
@@ -103,6 +248,78 @@ impl Op {
             Op::Lt => Box::new(move |a, b| a < b),
             Op::GtEq => Box::new(move |a, b| a >= b),
             Op::LtEq => Box::new(move |a, b| a <= b),
+            Op::Match | Op::NMatch | Op::Prefix => Box::new(move |_, _| false),
+            Op::Exists => Box::new(move |_, _| true),
+        }
+    }
+
+    // Get the op matching exactly the values this one doesn't, if there is one.
+    pub fn negate(&self) -> Option<Op> {
+        match self {
+            Op::Eq => Some(Op::NEq),
+            Op::NEq => Some(Op::Eq),
+            Op::Gt => Some(Op::LtEq),
+            Op::LtEq => Some(Op::Gt),
+            Op::Lt => Some(Op::GtEq),
+            Op::GtEq => Some(Op::Lt),
+            Op::Match => Some(Op::NMatch),
+            Op::NMatch => Some(Op::Match),
+            Op::Prefix | Op::Exists => None,
+        }
+    }
+}
+
+// LabelMatcher Enum. Picks out values of a label key.
+pub enum LabelMatcher {
+    Eq(String),
+    Regex(Regex, String), // Fully anchored, with a literal prefix of every match.
+    Prefix(String),
+    Exists,
+}
+impl LabelMatcher {
+    // Constructor for a regex matcher. Fails if the pattern is invalid.
+    pub fn regex(pattern: &str) -> Result<Self, Error> {
+        let regex = Regex::new(&format!("^(?:{})$", pattern))
+            .map_err(|e| Error::InvalidQuery(format!("invalid regex {}: {}", pattern, e)))?;
+        // Narrows the range of the index that has to be scanned, e.g. "web-" for "web-.*".
+        let prefix = Parser::new()
+            .parse(pattern)
+            .ok()
+            .map(|hir| Literals::prefixes(&hir).longest_common_prefix().to_vec())
+            .and_then(|x| String::from_utf8(x).ok())
+            .unwrap_or_default();
+        Ok(LabelMatcher::Regex(regex, prefix))
+    }
+
+    // Returns true if the label value is matched.
+    pub fn is_match(&self, value: &str) -> bool {
+        match self {
+            LabelMatcher::Eq(s) => value == s,
+            LabelMatcher::Regex(regex, _) => regex.is_match(value),
+            LabelMatcher::Prefix(s) => value.starts_with(s.as_str()),
+            LabelMatcher::Exists => true,
+        }
+    }
+
+    // Get a prefix that every matched value starts with.
+    pub fn get_prefix(&self) -> &str {
+        match self {
+            LabelMatcher::Eq(s) | LabelMatcher::Prefix(s) => s,
+            LabelMatcher::Regex(_, prefix) => prefix,
+            LabelMatcher::Exists => "",
+        }
+    }
+}
+
+// Get the series with a label key whose value is matched.
+fn search_label<B: BlockView>(block: &B, key: &str, matcher: &LabelMatcher) -> Bitmap {
+    match matcher {
+        LabelMatcher::Eq(value) => block
+            .search_index(format!("{}={}", key, value))
+            .unwrap_or_else(Bitmap::create),
+        _ => {
+            let prefix = format!("{}={}", key, matcher.get_prefix());
+            block.search_prefix(&prefix, &|x| matcher.is_match(&x[key.len() + 1..]))
         }
     }
 }
@@ -113,32 +330,77 @@ pub enum Conditions {
     Leaf(Condition),
     And(Box<Conditions>, Box<Conditions>),
     Or(Box<Conditions>, Box<Conditions>),
+    Not(Box<Conditions>),
 }
 impl Conditions {
-    fn eval(&self, shared_block: &Arc<RwLock<Block>>) -> ResultSet {
+    // Check every leaf can be evaluated.
+    pub fn validate(&self) -> Result<(), Error> {
         match self {
-            // If a Leaf, return results.
-            Conditions::Leaf(cond) => {
-                let mut r = cond.eval(shared_block);
-                // r.unpack(shared_block); // NOTE: Remove this.
-                r
+            Conditions::Leaf(cond) => cond.validate(),
+            Conditions::And(b1, b2) | Conditions::Or(b1, b2) => {
+                b1.validate()?;
+                b2.validate()
             }
+            Conditions::Not(b) => b.validate(),
+        }
+    }
+
+    fn eval<B: BlockView>(&self, block: &B, time_range: &TimeRange) -> ResultSet {
+        match self {
+            // If a Leaf, return results.
+            Conditions::Leaf(cond) => cond.eval(block, time_range),
             // If an And, intersect the results.
             Conditions::And(b1, b2) => {
-                let mut r1 = (*b1).eval(shared_block);
-                let r2 = (*b2).eval(shared_block);
-                r1.intersection(r2, shared_block);
-                // r1.unpack(shared_block); // NOTE: Remove this.
+                let mut r1 = (*b1).eval(block, time_range);
+                let r2 = (*b2).eval(block, time_range);
+                r1.intersection(r2, block);
                 r1
             }
             // If an or, union the results.
             Conditions::Or(b1, b2) => {
-                let mut r1 = (*b1).eval(shared_block);
-                let r2 = (*b2).eval(shared_block);
-                r1.union(r2, shared_block);
-                // r1.unpack(shared_block); // NOTE: Remove this.
+                let mut r1 = (*b1).eval(block, time_range);
+                let r2 = (*b2).eval(block, time_range);
+                r1.union(r2, block);
                 r1
             }
+            // If a Not, take the complement of the results.
+            Conditions::Not(b) => {
+                let mut r = (*b).eval(block, time_range);
+                r.complement(block);
+                r
+            }
+        }
+    }
+
+    // Returns true if a single record satisfies the predicate; agrees with eval.
+    pub fn matches(&self, record: &Record) -> bool {
+        match self {
+            Conditions::Leaf(cond) => cond.matches(record),
+            Conditions::And(b1, b2) => b1.matches(record) && b2.matches(record),
+            Conditions::Or(b1, b2) => b1.matches(record) || b2.matches(record),
+            Conditions::Not(b) => !b.matches(record),
+        }
+    }
+
+    // Returns true if every leaf is a label condition.
+    fn is_label_only(&self) -> bool {
+        match self {
+            Conditions::Leaf(cond) => cond.lhs.is_labelkey(),
+            Conditions::And(b1, b2) | Conditions::Or(b1, b2) => {
+                b1.is_label_only() && b2.is_label_only()
+            }
+            Conditions::Not(b) => b.is_label_only(),
+        }
+    }
+
+    // Checks the packed block's index to see if this predicate could match anything.
+    fn may_match(&self, packed_block: &PackedBlock) -> bool {
+        match self {
+            Conditions::Leaf(cond) => cond.may_match(packed_block),
+            Conditions::And(b1, b2) => b1.may_match(packed_block) && b2.may_match(packed_block),
+            Conditions::Or(b1, b2) => b1.may_match(packed_block) || b2.may_match(packed_block),
+            // Whatever the inner predicate rules out, its negation may match.
+            Conditions::Not(_) => true,
         }
     }
 }
@@ -152,76 +414,187 @@ pub struct Condition {
 }
 
 impl Condition {
-    fn eval(&self, shared_block: &Arc<RwLock<Block>>) -> ResultSet {
-        // In the label=value case, the only operation is equality.
+    // Check the condition can be evaluated: a regex on a label must compile.
+    fn validate(&self) -> Result<(), Error> {
+        match self.op {
+            Op::Match | Op::NMatch if self.lhs.is_labelkey() && self.rhs.is_labelvalue() => {
+                LabelMatcher::regex(&self.rhs.to_string()).map(|_| ())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Get the matcher for a label condition, and whether it is negated. Returns None
+    // for conditions that aren't on labels, or whose op doesn't apply to them.
+    fn get_label_matcher(&self) -> Option<(LabelMatcher, bool)> {
+        if !self.lhs.is_labelkey() || !self.rhs.is_labelvalue() {
+            return None;
+        }
+        let value = self.rhs.to_string();
+        let matcher = match self.op {
+            Op::Eq | Op::NEq => LabelMatcher::Eq(value),
+            Op::Match | Op::NMatch => LabelMatcher::regex(&value).ok()?,
+            Op::Prefix => LabelMatcher::Prefix(value),
+            Op::Exists => LabelMatcher::Exists,
+            _ => return None,
+        };
+        Some((matcher, self.op == Op::NEq || self.op == Op::NMatch))
+    }
+
+    // Get conditions matching exactly what this one doesn't, if there are any. Negated
+    // label ops already match series without the label, but a flipped comparison
+    // doesn't match records without its variables, so NOT x > 5 becomes
+    // x <= 5 OR NOT EXISTS x.
+    pub fn negate(&self) -> Option<Conditions> {
+        let flipped = Conditions::Leaf(Condition {
+            lhs: self.lhs.clone(),
+            rhs: self.rhs.clone(),
+            op: self.op.negate()?,
+        });
         if self.lhs.is_labelkey() && self.rhs.is_labelvalue() {
-            match self.op {
-                // Equality case.
-                Op::Eq => {
-                    // Get label and block.
-                    let label = format!("{}={}", self.lhs.to_string(), self.rhs.to_string());
-                    let block = shared_block.read().expect("RwLock poisoned");
-
-                    // Append all relevant series given the label key and value.
-                    if let Some(rb) = block.search_index(label) {
-                        return ResultSet {
-                            unpacked: false,
-                            data: vec![],
-                            series: rb.clone(),
-                            filters: vec![],
-                        };
-                    }
-                    return ResultSet {
-                        unpacked: false,
-                        data: vec![],
-                        series: Bitmap::create(),
-                        filters: vec![],
-                    };
+            return match self.op {
+                Op::Eq | Op::NEq | Op::Match | Op::NMatch => Some(flipped),
+                _ => None,
+            };
+        }
+        if self.get_exprs().is_none() || matches!(self.op, Op::Match | Op::NMatch) {
+            return None;
+        }
+        let exists = self
+            .get_variables()
+            .into_iter()
+            .map(|x| {
+                Conditions::Leaf(Condition {
+                    lhs: Type::Variable(x),
+                    rhs: Type::Metric(0.0),
+                    op: Op::Exists,
+                })
+            })
+            .reduce(|a, b| Conditions::And(Box::new(a), Box::new(b)))?;
+        Some(Conditions::Or(
+            Box::new(flipped),
+            Box::new(Conditions::Not(Box::new(exists))),
+        ))
+    }
+
+    // Get both sides of a comparison between expressions. Returns None unless at
+    // least one side reads a variable.
+    fn get_exprs(&self) -> Option<(Expr, Expr)> {
+        let lhs = self.lhs.to_expr()?;
+        let rhs = self.rhs.to_expr()?;
+        if lhs.get_variables().is_empty() && rhs.get_variables().is_empty() {
+            return None;
+        }
+        Some((lhs, rhs))
+    }
+
+    // Get the variables read by either side of the condition, without duplicates.
+    fn get_variables(&self) -> Vec<String> {
+        let mut variables = vec![];
+        for expr in self.lhs.to_expr().iter().chain(self.rhs.to_expr().iter()) {
+            expr.collect_variables(&mut variables);
+        }
+        variables
+    }
+
+    fn eval<B: BlockView>(&self, block: &B, time_range: &TimeRange) -> ResultSet {
+        // In the label case, match values through the index.
+        if self.lhs.is_labelkey() && self.rhs.is_labelvalue() {
+            let series = match self.get_label_matcher() {
+                // Negations also match series without the label at all.
+                Some((matcher, true)) => {
+                    let mut series = block.get_all_series();
+                    series.andnot_inplace(&search_label(block, &self.lhs.to_string(), &matcher));
+                    series
                 }
+                Some((matcher, false)) => search_label(block, &self.lhs.to_string(), &matcher),
                 // No other cases are permitted.
-                _ => ResultSet {
-                    unpacked: false,
-                    data: vec![],
-                    series: Bitmap::create(),
-                    filters: vec![],
-                },
+                None => Bitmap::create(),
+            };
+            ResultSet {
+                unpacked: false,
+                data: vec![],
+                series,
+                filters: vec![],
+                time_range: *time_range,
             }
         }
-        // In the variable=metric case, we extract the operation and iterate.
-        else if self.lhs.is_variable() && self.rhs.is_metric() {
-            // Get block.
-            let metric = self.lhs.to_string();
-            let block = shared_block.read().expect("RwLock poisoned");
-
-            // Return all blocks that contain the given metric
-            if let Some(rb) = block.search_index(metric.clone()) {
-                let op = self.op.get_op();
-                let filter = make_filter(op, self.rhs.extract_metric());
-                return ResultSet {
-                    unpacked: false,
-                    data: vec![],
-                    series: rb.clone(),
-                    // Delay filtering until the set is unpacked
-                    filters: vec![(metric, filter)],
+        // In the expression case, we compile a filter and iterate.
+        else if let Some((lhs, rhs)) = self.get_exprs() {
+            // Only series with every variable involved can match.
+            let mut series: Option<Bitmap> = None;
+            for variable in self.get_variables() {
+                let rb = block.search_index(variable).unwrap_or_else(Bitmap::create);
+                series = match series {
+                    Some(mut s) => {
+                        s.and_inplace(&rb);
+                        Some(s)
+                    }
+                    None => Some(rb),
                 };
             }
             return ResultSet {
                 unpacked: false,
                 data: vec![],
-                series: Bitmap::create(),
-                filters: vec![],
+                series: series.unwrap_or_else(Bitmap::create),
+                // Delay filtering until the set is unpacked
+                filters: vec![make_filter(lhs, rhs, &self.op)],
+                time_range: *time_range,
             };
         }
-        // We disallow everything that isn't label=value or variable=metric.
+        // We disallow everything that isn't on a label or over variables.
         else {
             return ResultSet {
                 unpacked: false,
                 data: vec![],
                 series: Bitmap::create(),
                 filters: vec![],
+                time_range: *time_range,
             };
         }
     }
+
+    // Returns true if a single record satisfies the condition.
+    fn matches(&self, record: &Record) -> bool {
+        if self.lhs.is_labelkey() && self.rhs.is_labelvalue() {
+            match self.get_label_matcher() {
+                Some((matcher, negated)) => {
+                    let value = record.get_label(&self.lhs.to_string());
+                    value.is_some_and(|x| matcher.is_match(&x)) != negated
+                }
+                None => false,
+            }
+        } else if let Some((lhs, rhs)) = self.get_exprs() {
+            make_filter(lhs, rhs, &self.op)(record)
+        } else {
+            false
+        }
+    }
+
+    // Returns false only if the packed block's index rules out any match.
+    fn may_match(&self, packed_block: &PackedBlock) -> bool {
+        if self.lhs.is_labelkey() && self.rhs.is_labelvalue() {
+            let key = self.lhs.to_string();
+            match self.get_label_matcher() {
+                Some((_, true)) => true,
+                Some((LabelMatcher::Eq(value), false)) => {
+                    packed_block.contains_key(&format!("{}={}", key, value))
+                }
+                Some((matcher, false)) => {
+                    let prefix = format!("{}={}", key, matcher.get_prefix());
+                    packed_block
+                        .contains_prefix(&prefix, &|x| matcher.is_match(&x[key.len() + 1..]))
+                }
+                None => false,
+            }
+        } else if self.get_exprs().is_some() {
+            self.get_variables()
+                .iter()
+                .all(|x| packed_block.contains_key(x))
+        } else {
+            false
+        }
+    }
 }
 
 // ResultSet Struct.
@@ -229,63 +602,64 @@ pub struct ResultSet {
     unpacked: bool,
     pub data: Vec<Record>, // Assumed sorted.
     series: Bitmap,
-    filters: Vec<(String, Box<dyn Fn(f64) -> bool>)>,
+    filters: Vec<Filter>,
+    time_range: TimeRange,
 }
 
-fn pass_filters(record: &Record, filters: &Vec<(String, Box<dyn Fn(f64) -> bool>)>) -> bool {
-    for (metric, filter) in filters.iter() {
-        match record.get_metric(metric.to_string()) {
-            Some(val) => {
-                if !filter(*val) {
-                    return false;
-                }
-            }
-            None => return false,
+fn pass_filters(record: &Record, filters: &Vec<Filter>) -> bool {
+    for filter in filters.iter() {
+        if !filter(record) {
+            return false;
         }
     }
     return true;
 }
 
 impl ResultSet {
-    pub fn unpack(&mut self, shared_block: &Arc<RwLock<Block>>) {
+    // Constructor for an unpacked set, from records sorted as unpack leaves them.
+    pub fn from_records(data: Vec<Record>) -> Self {
+        ResultSet {
+            unpacked: true,
+            data,
+            series: Bitmap::create(),
+            filters: vec![],
+            time_range: TimeRange::default(),
+        }
+    }
+
+    pub fn unpack<B: BlockView>(&mut self, block: &B) {
         // Unpacking happens only once
         if self.unpacked {
             return;
         }
-        let block = shared_block.read().expect("RwLock poisoned");
         let mut data: Vec<Record> = vec![];
-        let mut all_series: Vec<Vec<Record>> = vec![];
-        let mut positions: Vec<usize> = vec![];
+        let mut all_series: Vec<Box<dyn Iterator<Item = Record> + '_>> = vec![];
         let mut pq = PriorityQueue::new();
-        let mut counter: usize = 0;
-        // Get pointers to the start of all relevant series
+        // Start streaming all relevant series
         for id in self.series.iter() {
-            let series = block.get_storage()[id as usize].get_records();
-            all_series.push(series.clone());
-            positions.push(0);
-            pq.push(counter, series[0].clone());
-            counter += 1;
+            let mut series = block.get_series_records(id);
+            if let Some(first) = series.next() {
+                pq.push(all_series.len(), first);
+                all_series.push(series);
+            }
         }
         // Perform a timestamp-sorted multi-merge of all series
-        while !pq.is_empty() {
-            match pq.pop() {
-                Some((i, _)) => {
-                    let pos = positions[i];
-                    let entry = all_series[i][pos].clone();
-                    // Apply filters as we go
-                    if pass_filters(&entry, &self.filters) {
-                        if data.len() == 0 || entry != data[data.len() - 1] {
-                            data.push(entry);
-                        }
-                    }
-                    // Advance the pointer for this series
-                    positions[i] += 1;
-                    if pos + 1 >= all_series[i].len() {
-                        continue;
-                    }
-                    pq.push(i, all_series[i][pos + 1].clone());
-                }
-                None => continue,
+        while let Some((i, entry)) = pq.pop() {
+            // Series are sorted, so nothing past the end of the range can match,
+            // and the rest of this series needn't be decoded
+            if self.time_range.is_after(entry.get_timestamp()) {
+                continue;
+            }
+            // Apply filters as we go, skipping duplicates
+            if self.time_range.contains(entry.get_timestamp())
+                && pass_filters(&entry, &self.filters)
+                && data.last() != Some(&entry)
+            {
+                data.push(entry);
+            }
+            // Advance this series
+            if let Some(next) = all_series[i].next() {
+                pq.push(i, next);
             }
         }
         self.data = data;
@@ -293,7 +667,7 @@ impl ResultSet {
     }
 
     // Union two RSs. Assumes both are sorted by timestamp.
-    pub fn union(&mut self, mut other: ResultSet, shared_block: &Arc<RwLock<Block>>) {
+    pub fn union<B: BlockView>(&mut self, mut other: ResultSet, block: &B) {
         // Check if both sets are unpacked and filterless
         if !self.unpacked && !other.unpacked && self.filters.len() == 0 && other.filters.len() == 0
         {
@@ -301,8 +675,14 @@ impl ResultSet {
             return;
         }
         // Unpack both sets
-        self.unpack(shared_block);
-        other.unpack(shared_block);
+        self.unpack(block);
+        other.unpack(block);
+        self.merge(other);
+    }
+
+    // Merge an unpacked RS (e.g. from another block) into this one. Assumes both are sorted by timestamp.
+    pub fn merge(&mut self, other: ResultSet) {
+        assert!(self.unpacked && other.unpacked);
         let mut res = Vec::with_capacity(self.data.len() + other.data.len());
         let mut i = 0;
         let mut j = 0;
@@ -329,8 +709,44 @@ impl ResultSet {
         self.data = res;
     }
 
+    // Replace the results with every other record in the block.
+    pub fn complement<B: BlockView>(&mut self, block: &B) {
+        // Without filters, whole series match or don't, so only the bitmap changes.
+        if !self.unpacked && self.filters.is_empty() {
+            let mut series = block.get_all_series();
+            series.andnot_inplace(&self.series);
+            self.series = series;
+            return;
+        }
+        // Otherwise, remove the matches from every record in the block.
+        self.unpack(block);
+        let mut all = ResultSet {
+            unpacked: false,
+            data: vec![],
+            series: block.get_all_series(),
+            filters: vec![],
+            time_range: self.time_range,
+        };
+        all.unpack(block);
+        let mut res = Vec::with_capacity(all.data.len());
+        let mut j = 0;
+
+        // TODO: Once record ordering is fixed, fix the ordering here too
+        for record in all.data.into_iter() {
+            while j < self.data.len() && self.data[j] > record {
+                j += 1;
+            }
+            if j < self.data.len() && self.data[j] == record {
+                j += 1;
+            } else {
+                res.push(record);
+            }
+        }
+        self.data = res;
+    }
+
     // Intersect two RSs. Assumes both are sorted by timestamp.
-    pub fn intersection(&mut self, mut other: ResultSet, shared_block: &Arc<RwLock<Block>>) {
+    pub fn intersection<B: BlockView>(&mut self, mut other: ResultSet, block: &B) {
         // Check if both result sets are unpacked
         if !self.unpacked && !other.unpacked {
             self.series.and_inplace(&other.series);
@@ -338,8 +754,8 @@ impl ResultSet {
             return;
         }
         // If either result set is unpacked, unpack the other
-        self.unpack(shared_block);
-        other.unpack(shared_block);
+        self.unpack(block);
+        other.unpack(block);
         let mut res = Vec::with_capacity(self.data.len() + other.data.len());
         let mut i = 0;
         let mut j = 0;
@@ -360,6 +776,37 @@ impl ResultSet {
         self.data = res;
     }
 
+    // Add a filter that every record must pass.
+    pub fn add_filter(&mut self, filter: Filter) {
+        self.filters.push(filter);
+    }
+
+    // Get the results grouped by series. If the set hasn't been unpacked, each series
+    // is read straight from the block, without merging them by timestamp.
+    pub fn to_series<B: BlockView>(&self, block: &B) -> Vec<SeriesResult> {
+        if self.unpacked {
+            return SeriesResult::from_records(self.into_vec());
+        }
+        let mut results = vec![];
+        for id in self.series.iter() {
+            let mut result: Option<SeriesResult> = None;
+            for record in block.get_series_records(id) {
+                if self.time_range.is_after(record.get_timestamp()) {
+                    break;
+                }
+                if self.time_range.contains(record.get_timestamp())
+                    && pass_filters(&record, &self.filters)
+                {
+                    result
+                        .get_or_insert_with(|| SeriesResult::new(&record))
+                        .push(&record);
+                }
+            }
+            results.extend(result);
+        }
+        results
+    }
+
     // Converts a ResultSet into a Vector.
     pub fn into_vec(&self) -> Vec<Record> {
         self.data.clone()
@@ -434,4 +881,160 @@ mod test {
         );
         assert_eq!(d, exp);
     }
+
+    #[test]
+    fn deserialize_select_time_range() {
+        let data = r#"
+        {
+            "name": "s",
+            "predicate": {
+                "name": "p",
+                "condition": {
+                    "Leaf": { "lhs": {"LabelKey": "Key"}, "rhs": {"LabelValue": "Value"}, "op": "Eq" }
+                }
+            },
+            "start": "2016-06-13T17:43:50+00:00"
+        }
+        "#;
+        let d: Select = serde_json::from_str(data).unwrap();
+        let start = DateTime::parse_from_rfc3339("2016-06-13T17:43:50+00:00")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(d.start, Some(start));
+        assert_eq!(d.end, None);
+        assert!(d.get_time_range().contains(start));
+        assert!(!d
+            .get_time_range()
+            .contains(start - chrono::Duration::seconds(1)));
+    }
+
+    #[test]
+    fn test_label_matcher() {
+        let matcher = LabelMatcher::regex("web-.*").unwrap();
+        assert_eq!(matcher.get_prefix(), "web-");
+        assert!(matcher.is_match("web-1"));
+        assert!(!matcher.is_match("my-web-1"));
+        assert_eq!(LabelMatcher::regex("(web|db)-1").unwrap().get_prefix(), "");
+        assert_eq!(
+            LabelMatcher::regex("host_(0|1)").unwrap().get_prefix(),
+            "host_"
+        );
+        assert!(LabelMatcher::regex("web-(").is_err());
+
+        // Selects with an invalid regex are rejected up front.
+        let condition = Conditions::Leaf(Condition {
+            lhs: Type::LabelKey(String::from("hostname")),
+            rhs: Type::LabelValue(String::from("web-(")),
+            op: Op::NMatch,
+        });
+        match Conditions::Not(Box::new(condition)).validate() {
+            Err(Error::InvalidQuery(_)) => (),
+            _ => panic!("invalid regex was not reported"),
+        }
+    }
+
+    #[test]
+    fn test_variable_exprs() {
+        use crate::server::store::Block;
+        use std::collections::HashMap;
+
+        let mut block = Block::new();
+        for (hostname, user, system) in
+            [("a", 50.0, 45.0), ("b", 30.0, 40.0), ("c", 20.0, 5.0)].iter()
+        {
+            let mut labels = HashMap::new();
+            labels.insert("hostname".to_string(), hostname.to_string());
+            let mut variables = HashMap::new();
+            variables.insert("usage_user".to_string(), *user);
+            variables.insert("usage_system".to_string(), *system);
+            block
+                .insert(Record::new(
+                    "cpu".to_string(),
+                    labels,
+                    variables,
+                    Utc::now(),
+                ))
+                .unwrap();
+        }
+        let get_hostnames = |data: &str| -> Vec<String> {
+            let condition: Conditions = serde_json::from_str(data).unwrap();
+            let mut result = condition.eval(&block, &TimeRange::default());
+            result.unpack(&block);
+            let mut hostnames: Vec<String> = result
+                .into_vec()
+                .iter()
+                .map(|r| r.get_populated_labels()["hostname"].clone())
+                .collect();
+            hostnames.sort();
+            hostnames
+        };
+
+        // usage_user > usage_system
+        let data = r#"{ "Leaf": { "lhs": {"Variable": "usage_user"}, "rhs": {"Variable": "usage_system"}, "op": "Gt" } }"#;
+        assert_eq!(get_hostnames(data), vec!["a", "c"]);
+
+        // usage_user + usage_system > 90
+        let data = r#"{ "Leaf": {
+            "lhs": {"Expr": {"Add": [{"Variable": "usage_user"}, {"Variable": "usage_system"}]}},
+            "rhs": {"Metric": 90.0},
+            "op": "Gt"
+        } }"#;
+        assert_eq!(get_hostnames(data), vec!["a"]);
+
+        // usage_system / 2 >= usage_user - 10, then over a variable no series has
+        let data = r#"{ "Leaf": {
+            "lhs": {"Expr": {"Div": [{"Variable": "usage_system"}, {"Metric": 2.0}]}},
+            "rhs": {"Expr": {"Sub": [{"Variable": "usage_user"}, {"Metric": 10.0}]}},
+            "op": "GtEq"
+        } }"#;
+        assert_eq!(get_hostnames(data), vec!["b"]);
+        let data = r#"{ "Leaf": { "lhs": {"Variable": "usage_idle"}, "rhs": {"Variable": "usage_user"}, "op": "Lt" } }"#;
+        assert!(get_hostnames(data).is_empty());
+    }
+
+    #[test]
+    fn test_not() {
+        use crate::server::store::Block;
+        use std::collections::HashMap;
+
+        let mut block = Block::new();
+        for (team, usage) in [("infra", 10.0), ("web", 20.0), ("web", 30.0)].iter() {
+            let mut labels = HashMap::new();
+            labels.insert("team".to_string(), team.to_string());
+            labels.insert("hostname".to_string(), format!("host_{}", usage));
+            let mut variables = HashMap::new();
+            variables.insert("usage_user".to_string(), *usage);
+            block
+                .insert(Record::new(
+                    "cpu".to_string(),
+                    labels,
+                    variables,
+                    Utc::now(),
+                ))
+                .unwrap();
+        }
+        let get_usages = |data: &str| -> Vec<f64> {
+            let condition: Conditions = serde_json::from_str(data).unwrap();
+            let mut result = condition.eval(&block, &TimeRange::default());
+            result.unpack(&block);
+            let mut usages: Vec<f64> = result
+                .into_vec()
+                .iter()
+                .map(|r| *r.get_metric("usage_user".to_string()).unwrap())
+                .collect();
+            usages.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            usages
+        };
+
+        // Label negation, answered from the bitmaps alone.
+        let data = r#"{ "Not": { "Leaf": { "lhs": {"LabelKey": "team"}, "rhs": {"LabelValue": "web"}, "op": "Eq" } } }"#;
+        assert_eq!(get_usages(data), vec![10.0]);
+
+        // Negating a filtered result removes its records from the whole block.
+        let data = r#"{ "Not": { "And": [
+            { "Leaf": { "lhs": {"LabelKey": "team"}, "rhs": {"LabelValue": "web"}, "op": "Eq" } },
+            { "Leaf": { "lhs": {"Variable": "usage_user"}, "rhs": {"Metric": 25.0}, "op": "Gt" } }
+        ] } }"#;
+        assert_eq!(get_usages(data), vec![10.0, 20.0]);
+    }
 }
@@ -0,0 +1,348 @@
+use crate::error::Error;
+use crate::server::promql::{
+    AggregateOp, BinaryOp, Expr, Function, Grouping, Labels, Selector, Series, LOOKBACK_MILLIS,
+};
+use crate::server::record::NAME_LABEL;
+use std::collections::{BTreeMap, HashMap};
+
+// Value Enum. The result of evaluating an expression at a single time.
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    Scalar(f64),
+    Vector(Vec<(Labels, f64)>),
+    Matrix(Vec<Series>),
+}
+impl Value {
+    // Get the value as series, stamping scalar and vector samples with time.
+    pub fn into_series(self, time: i64) -> Vec<Series> {
+        match self {
+            Value::Scalar(v) => vec![Series {
+                labels: Labels::new(),
+                samples: vec![(time, v)],
+            }],
+            Value::Vector(samples) => samples
+                .into_iter()
+                .map(|(labels, v)| Series {
+                    labels,
+                    samples: vec![(time, v)],
+                })
+                .collect(),
+            Value::Matrix(series) => series,
+        }
+    }
+}
+
+// Get the key a selector's fetched series are stored under.
+pub fn get_key(selector: &Selector, window: i64) -> String {
+    format!("{:?}[{}]", selector, window)
+}
+
+// Evaluator Struct. Evaluates expressions over series fetched ahead of time.
+pub struct Evaluator<'a> {
+    fetched: &'a HashMap<String, Vec<Series>>,
+}
+impl<'a> Evaluator<'a> {
+    // Constructor.
+    pub fn new(fetched: &'a HashMap<String, Vec<Series>>) -> Self {
+        Evaluator { fetched }
+    }
+
+    // Get the fetched series of a selector.
+    fn get_series(&self, selector: &Selector, window: i64) -> &[Series] {
+        self.fetched
+            .get(&get_key(selector, window))
+            .map_or(&[], |x| x.as_slice())
+    }
+
+    // Get the samples of each series in (time - window, time].
+    fn get_window(&self, selector: &Selector, window: i64, time: i64) -> Vec<Series> {
+        self.get_series(selector, window)
+            .iter()
+            .map(|series| Series {
+                labels: series.labels.clone(),
+                samples: series
+                    .samples
+                    .iter()
+                    .filter(|x| time - window < x.0 && x.0 <= time)
+                    .cloned()
+                    .collect(),
+            })
+            .filter(|series| !series.samples.is_empty())
+            .collect()
+    }
+
+    // Evaluate an expression at a time (millis).
+    pub fn eval(&self, expr: &Expr, time: i64) -> Result<Value, Error> {
+        match expr {
+            Expr::Number(v) => Ok(Value::Scalar(*v)),
+            // Instant selectors take the latest sample in the lookback window.
+            Expr::Selector(selector) => Ok(Value::Vector(
+                self.get_window(selector, LOOKBACK_MILLIS, time)
+                    .into_iter()
+                    .map(|series| (series.labels, series.samples.last().unwrap().1))
+                    .collect(),
+            )),
+            Expr::Range(selector, range) => {
+                Ok(Value::Matrix(self.get_window(selector, *range, time)))
+            }
+            Expr::Call(function, arg) => match &**arg {
+                Expr::Range(selector, range) => Ok(Value::Vector(
+                    self.get_window(selector, *range, time)
+                        .into_iter()
+                        .filter_map(|series| {
+                            let value =
+                                apply_function(*function, &series.samples, time - range, time)?;
+                            Some((drop_name(series.labels), value))
+                        })
+                        .collect(),
+                )),
+                _ => Err(Error::InvalidQuery(format!(
+                    "{:?} expects a range vector selector",
+                    function
+                ))),
+            },
+            Expr::Aggregate(op, grouping, arg) => match self.eval(arg, time)? {
+                Value::Vector(samples) => Ok(Value::Vector(aggregate(*op, grouping, samples))),
+                _ => Err(Error::InvalidQuery(format!(
+                    "{:?} expects an instant vector",
+                    op
+                ))),
+            },
+            Expr::Binary(op, lhs, rhs) => binary(*op, self.eval(lhs, time)?, self.eval(rhs, time)?),
+            Expr::Neg(arg) => match self.eval(arg, time)? {
+                Value::Scalar(v) => Ok(Value::Scalar(-v)),
+                Value::Vector(samples) => Ok(Value::Vector(
+                    samples
+                        .into_iter()
+                        .map(|(labels, v)| (drop_name(labels), -v))
+                        .collect(),
+                )),
+                Value::Matrix(_) => Err(Error::InvalidQuery(String::from(
+                    "can't negate a range vector",
+                ))),
+            },
+        }
+    }
+}
+
+// Remove the metric name from labels, as any computed value no longer has it.
+fn drop_name(mut labels: Labels) -> Labels {
+    labels.remove(NAME_LABEL);
+    labels
+}
+
+// Apply a function to the samples of a series in [start, end]. Returns None if
+// there aren't enough samples.
+fn apply_function(function: Function, samples: &[(i64, f64)], start: i64, end: i64) -> Option<f64> {
+    let values = samples.iter().map(|x| x.1);
+    let count = samples.len() as f64;
+    match function {
+        Function::Rate => {
+            extrapolated_increase(samples, start, end).map(|x| x / ((end - start) as f64 / 1000.0))
+        }
+        Function::Increase => extrapolated_increase(samples, start, end),
+        Function::AvgOverTime => Some(values.sum::<f64>() / count),
+        Function::SumOverTime => Some(values.sum()),
+        Function::MinOverTime => values.fold(None, |acc: Option<f64>, x| {
+            Some(acc.map_or(x, |y| y.min(x)))
+        }),
+        Function::MaxOverTime => values.fold(None, |acc: Option<f64>, x| {
+            Some(acc.map_or(x, |y| y.max(x)))
+        }),
+        Function::CountOverTime => Some(count),
+    }
+}
+
+// Get the increase of a counter over [start, end], correcting for resets and
+// extrapolating to the edges of the range the way Prometheus does.
+fn extrapolated_increase(samples: &[(i64, f64)], start: i64, end: i64) -> Option<f64> {
+    if samples.len() < 2 {
+        return None;
+    }
+    let (first_time, first) = samples[0];
+    let (last_time, last) = samples[samples.len() - 1];
+    let mut result = last - first;
+    for pair in samples.windows(2) {
+        if pair[1].1 < pair[0].1 {
+            result += pair[0].1;
+        }
+    }
+
+    // Extrapolate to the edges of the range, unless the series seems to start or end
+    // within it, and never to before the counter would have been zero. Samples that
+    // all share a time have no rate to extrapolate.
+    let sampled = (last_time - first_time) as f64 / 1000.0;
+    if sampled <= 0.0 {
+        return None;
+    }
+    let average = sampled / (samples.len() - 1) as f64;
+    let mut to_start = (first_time - start) as f64 / 1000.0;
+    let to_end = (end - last_time) as f64 / 1000.0;
+    if result > 0.0 && first >= 0.0 {
+        to_start = to_start.min(sampled * (first / result));
+    }
+    let threshold = average * 1.1;
+    let mut extrapolated = sampled;
+    extrapolated += if to_start < threshold {
+        to_start
+    } else {
+        average / 2.0
+    };
+    extrapolated += if to_end < threshold {
+        to_end
+    } else {
+        average / 2.0
+    };
+    Some(result * extrapolated / sampled)
+}
+
+// Aggregate samples into groups.
+fn aggregate(
+    op: AggregateOp,
+    grouping: &Grouping,
+    samples: Vec<(Labels, f64)>,
+) -> Vec<(Labels, f64)> {
+    let mut groups: BTreeMap<Labels, Vec<f64>> = BTreeMap::new();
+    for (labels, value) in samples {
+        let key: Labels = match grouping {
+            Grouping::All => Labels::new(),
+            Grouping::By(keep) => labels
+                .into_iter()
+                .filter(|(k, _)| keep.contains(k))
+                .collect(),
+            Grouping::Without(drop) => drop_name(labels)
+                .into_iter()
+                .filter(|(k, _)| !drop.contains(k))
+                .collect(),
+        };
+        groups.entry(key).or_default().push(value);
+    }
+    groups
+        .into_iter()
+        .map(|(labels, values)| {
+            let count = values.len() as f64;
+            let value = match op {
+                AggregateOp::Sum => values.iter().sum(),
+                AggregateOp::Avg => values.iter().sum::<f64>() / count,
+                AggregateOp::Min => values.iter().cloned().fold(f64::INFINITY, f64::min),
+                AggregateOp::Max => values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+                AggregateOp::Count => count,
+            };
+            (labels, value)
+        })
+        .collect()
+}
+
+// Apply an arithmetic operator. Returns None for comparisons.
+fn arithmetic(op: BinaryOp, a: f64, b: f64) -> Option<f64> {
+    match op {
+        BinaryOp::Add => Some(a + b),
+        BinaryOp::Sub => Some(a - b),
+        BinaryOp::Mul => Some(a * b),
+        BinaryOp::Div => Some(a / b),
+        _ => None,
+    }
+}
+
+// Apply a comparison operator.
+fn compare(op: BinaryOp, a: f64, b: f64) -> bool {
+    match op {
+        BinaryOp::Eq => a == b,
+        BinaryOp::NEq => a != b,
+        BinaryOp::Gt => a > b,
+        BinaryOp::Lt => a < b,
+        BinaryOp::GtEq => a >= b,
+        BinaryOp::LtEq => a <= b,
+        _ => false,
+    }
+}
+
+// Apply an operator to one vector sample and the value on the other side. The
+// vector sample is on the left unless flipped. Comparisons keep or drop the sample.
+fn binary_sample(
+    op: BinaryOp,
+    labels: Labels,
+    v: f64,
+    other: f64,
+    flipped: bool,
+) -> Option<(Labels, f64)> {
+    let (a, b) = if flipped { (other, v) } else { (v, other) };
+    match arithmetic(op, a, b) {
+        Some(result) => Some((drop_name(labels), result)),
+        None if compare(op, a, b) => Some((labels, v)),
+        None => None,
+    }
+}
+
+// Apply a binary operator. Vectors are matched one-to-one on their labels, ignoring
+// the metric name.
+fn binary(op: BinaryOp, lhs: Value, rhs: Value) -> Result<Value, Error> {
+    match (lhs, rhs) {
+        (Value::Scalar(a), Value::Scalar(b)) => match arithmetic(op, a, b) {
+            Some(result) => Ok(Value::Scalar(result)),
+            None => Err(Error::InvalidQuery(String::from(
+                "comparisons between scalars aren't supported",
+            ))),
+        },
+        (Value::Vector(samples), Value::Scalar(b)) => Ok(Value::Vector(
+            samples
+                .into_iter()
+                .filter_map(|(labels, a)| binary_sample(op, labels, a, b, false))
+                .collect(),
+        )),
+        (Value::Scalar(a), Value::Vector(samples)) => Ok(Value::Vector(
+            samples
+                .into_iter()
+                .filter_map(|(labels, b)| binary_sample(op, labels, b, a, true))
+                .collect(),
+        )),
+        (Value::Vector(lhs), Value::Vector(rhs)) => {
+            let mut matches: HashMap<Labels, f64> = HashMap::new();
+            for (labels, b) in rhs {
+                if matches.insert(drop_name(labels), b).is_some() {
+                    return Err(Error::InvalidQuery(String::from(
+                        "many-to-many matching isn't supported",
+                    )));
+                }
+            }
+            Ok(Value::Vector(
+                lhs.into_iter()
+                    .filter_map(|(labels, a)| {
+                        let b = *matches.get(&drop_name(labels.clone()))?;
+                        binary_sample(op, labels, a, b, false)
+                    })
+                    .collect(),
+            ))
+        }
+        _ => Err(Error::InvalidQuery(String::from(
+            "binary operators need scalars or instant vectors",
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extrapolated_increase() {
+        // Samples every 10s over a 60s range, with a counter reset.
+        let samples = vec![
+            (5000, 10.0),
+            (15000, 20.0),
+            (25000, 5.0),
+            (35000, 15.0),
+            (45000, 25.0),
+            (55000, 35.0),
+        ];
+        // 10 before the reset, 5 + 30 after, over 50s sampled, extrapolated by 5s each side.
+        let increase = extrapolated_increase(&samples, 0, 60000).unwrap();
+        assert!((increase - 45.0 * 60.0 / 50.0).abs() < 1e-9);
+        assert_eq!(extrapolated_increase(&samples[..1], 0, 60000), None);
+        let same_time = vec![(5000, 10.0), (5000, 20.0)];
+        assert_eq!(extrapolated_increase(&same_time, 0, 60000), None);
+        assert_eq!(apply_function(Function::Rate, &same_time, 0, 60000), None);
+        let rate = apply_function(Function::Rate, &samples, 0, 60000).unwrap();
+        assert!((rate - increase / 60.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,437 @@
+pub mod eval;
+pub mod parser;
+
+use crate::error::Error;
+use crate::server::operators::{
+    aggregate::millis_to_datetime,
+    select::{Condition, Conditions, Op, Predicate, Type},
+    Select,
+};
+use crate::server::record::{Record, NAME_LABEL};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+// PromQL subset. Series are flattened the way Prometheus exporters flatten
+// multi-field measurements: each variable of a record becomes its own series, named
+// {record name}_{variable}, with the record's labels. A selector like
+// cpu_usage_user{team="CHI"} is compiled into a Select over the inverted index, so
+// it is answered by the same ResultSet evaluation as any other select.
+
+// CONSTANTS
+// How far back an instant selector looks for a sample (5 minutes, in millis).
+pub const LOOKBACK_MILLIS: i64 = 5 * 60 * 1000;
+
+// PromQuery Struct. An instant query at time (default now), or a range query if start
+// and end are given, evaluated every step millis.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct PromQuery {
+    pub query: String,
+    #[serde(default)]
+    pub time: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub start: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub end: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub step: Option<i64>,
+}
+impl PromQuery {
+    // Constructor for an instant query at now.
+    pub fn new(query: String) -> Self {
+        PromQuery {
+            query,
+            time: None,
+            start: None,
+            end: None,
+            step: None,
+        }
+    }
+}
+
+// Expr Enum. A PromQL expression.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr {
+    Number(f64),
+    Selector(Selector),
+    Range(Selector, i64), // Range in millis.
+    Call(Function, Box<Expr>),
+    Aggregate(AggregateOp, Grouping, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Neg(Box<Expr>),
+}
+impl Expr {
+    // Get every selector in the expression, with how far back it reads.
+    pub fn get_selectors(&self) -> Vec<(&Selector, i64)> {
+        match self {
+            Expr::Number(_) => vec![],
+            Expr::Selector(s) => vec![(s, LOOKBACK_MILLIS)],
+            Expr::Range(s, range) => vec![(s, *range)],
+            Expr::Call(_, e) | Expr::Aggregate(_, _, e) | Expr::Neg(e) => e.get_selectors(),
+            Expr::Binary(_, lhs, rhs) => {
+                let mut selectors = lhs.get_selectors();
+                selectors.append(&mut rhs.get_selectors());
+                selectors
+            }
+        }
+    }
+}
+
+// Selector Struct. A metric name and/or label matchers.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Selector {
+    pub metric: Option<String>,
+    pub matchers: Vec<Condition>,
+}
+impl Selector {
+    // Build the select for this selector's records over [start, end] (millis).
+    pub fn to_select(&self, start: i64, end: i64) -> Select {
+        let mut conditions: Vec<Conditions> = vec![];
+
+        // The metric is either a record name, or a record name and a variable joined
+        // by an underscore. Every way of splitting it is looked up in the index.
+        if let Some(metric) = &self.metric {
+            let mut candidates = vec![label_leaf(NAME_LABEL, metric, Op::Eq)];
+            for (i, _) in metric.match_indices('_') {
+                let variable = Conditions::Leaf(Condition {
+                    lhs: Type::Variable(String::from(&metric[i + 1..])),
+                    rhs: Type::Metric(0.0),
+                    op: Op::Exists,
+                });
+                candidates.push(Conditions::And(
+                    Box::new(label_leaf(NAME_LABEL, &metric[..i], Op::Eq)),
+                    Box::new(variable),
+                ));
+            }
+            conditions.push(join(candidates, Conditions::Or));
+        }
+        for matcher in self.matchers.iter() {
+            conditions.push(Conditions::Leaf(matcher.clone()));
+        }
+        Select {
+            name: String::from("promql"),
+            predicate: Predicate {
+                name: String::from("promql"),
+                condition: join(conditions, Conditions::And),
+            },
+            start: Some(millis_to_datetime(start)),
+            end: Some(millis_to_datetime(end)),
+            aggregation: None,
+            by_series: false,
+        }
+    }
+
+    // Returns true if the series for a record's variable is selected by the metric.
+    pub fn matches_name(&self, name: &str, variable: &str) -> bool {
+        match &self.metric {
+            Some(metric) => {
+                metric == name
+                    || (metric.len() == name.len() + variable.len() + 1
+                        && metric.starts_with(name)
+                        && metric[name.len()..].starts_with('_')
+                        && metric.ends_with(variable))
+            }
+            None => true,
+        }
+    }
+}
+
+// Make a label condition.
+fn label_leaf(key: &str, value: &str, op: Op) -> Conditions {
+    Conditions::Leaf(Condition {
+        lhs: Type::LabelKey(String::from(key)),
+        rhs: Type::LabelValue(String::from(value)),
+        op,
+    })
+}
+
+// Join conditions with And or Or. There must be at least one.
+fn join(
+    conditions: Vec<Conditions>,
+    op: fn(Box<Conditions>, Box<Conditions>) -> Conditions,
+) -> Conditions {
+    let mut conditions = conditions.into_iter();
+    let first = conditions.next().expect("ERROR: joining no conditions.");
+    conditions.fold(first, |acc, x| op(Box::new(acc), Box::new(x)))
+}
+
+// Function Enum. Functions over range vectors.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Function {
+    Rate,
+    Increase,
+    AvgOverTime,
+    SumOverTime,
+    MinOverTime,
+    MaxOverTime,
+    CountOverTime,
+}
+
+// AggregateOp Enum.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AggregateOp {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+// Grouping Enum. Which labels an aggregation keeps.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Grouping {
+    All,
+    By(Vec<String>),
+    Without(Vec<String>),
+}
+
+// BinaryOp Enum. Comparisons filter, rather than returning 0 or 1.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    NEq,
+    Gt,
+    Lt,
+    GtEq,
+    LtEq,
+}
+
+// Labels Type. Sorted, so they can be compared and used as keys.
+pub type Labels = BTreeMap<String, String>;
+
+// Series Struct. Timestamped (millis) samples of one series, in time order.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Series {
+    pub labels: Labels,
+    pub samples: Vec<(i64, f64)>,
+}
+
+// Flatten the records fetched for a selector into its series.
+pub fn flatten(selector: &Selector, records: Vec<Record>) -> Vec<Series> {
+    let mut series: BTreeMap<Labels, Vec<(i64, f64)>> = BTreeMap::new();
+    for record in records {
+        let name = record.get_name();
+        let timestamp = record.get_timestamp().timestamp_millis();
+        for (variable, value) in record.get_populated_variables() {
+            if !selector.matches_name(&name, &variable) {
+                continue;
+            }
+            let mut labels: Labels = record.get_populated_labels().into_iter().collect();
+            labels.insert(String::from(NAME_LABEL), format!("{}_{}", name, variable));
+            series.entry(labels).or_default().push((timestamp, value));
+        }
+    }
+    series
+        .into_iter()
+        .map(|(labels, mut samples)| {
+            samples.sort_by_key(|x| x.0);
+            Series { labels, samples }
+        })
+        .collect()
+}
+
+// Run a query, fetching records for its selectors with fetch. Results come back as
+// records named after their series, with the sample in the "value" variable.
+pub fn execute(
+    query: &PromQuery,
+    now: DateTime<Utc>,
+    fetch: &mut dyn FnMut(Select) -> Result<Vec<Record>, Error>,
+) -> Result<Vec<Record>, Error> {
+    let expr = parser::parse(&query.query)?;
+    if let Expr::Range(..) = expr {
+        if query.start.is_some() {
+            return Err(Error::InvalidQuery(String::from(
+                "range queries need an instant vector or scalar expression",
+            )));
+        }
+    }
+    let (start, end, step) = match (query.start, query.end) {
+        (Some(start), Some(end)) => {
+            let step = query.step.unwrap_or(60 * 1000);
+            if step <= 0 || end < start {
+                return Err(Error::InvalidQuery(String::from(
+                    "range queries need start <= end and a positive step",
+                )));
+            }
+            (start.timestamp_millis(), end.timestamp_millis(), step)
+        }
+        (None, None) => {
+            let time = query.time.unwrap_or(now).timestamp_millis();
+            (time, time, 1)
+        }
+        _ => {
+            return Err(Error::InvalidQuery(String::from(
+                "range queries need both start and end",
+            )))
+        }
+    };
+
+    // Fetch every selector once, for the whole range.
+    let mut fetched: HashMap<String, Vec<Series>> = HashMap::new();
+    for (selector, window) in expr.get_selectors() {
+        // Windows reaching past the representable dates can't be fetched.
+        let from = start
+            .checked_sub(window)
+            .filter(|x| NaiveDateTime::from_timestamp_opt(x.div_euclid(1000), 0).is_some())
+            .ok_or_else(|| {
+                Error::InvalidQuery(String::from("range reaches out of the supported time span"))
+            })?;
+        let records = fetch(selector.to_select(from, end))?;
+        fetched.insert(eval::get_key(selector, window), flatten(selector, records));
+    }
+
+    // Evaluate at each step, then group the samples by series.
+    let evaluator = eval::Evaluator::new(&fetched);
+    let mut results: BTreeMap<Labels, Vec<(i64, f64)>> = BTreeMap::new();
+    let mut time = start;
+    while time <= end {
+        for series in evaluator.eval(&expr, time)?.into_series(time) {
+            results
+                .entry(series.labels)
+                .or_default()
+                .extend(series.samples);
+        }
+        time = match time.checked_add(step) {
+            Some(time) => time,
+            None => break,
+        };
+    }
+    let mut records = vec![];
+    for (mut labels, samples) in results {
+        let name = labels.remove(NAME_LABEL).unwrap_or_default();
+        for (timestamp, value) in samples {
+            let mut variables = HashMap::new();
+            variables.insert(String::from("value"), value);
+            records.push(Record::new(
+                name.clone(),
+                labels.clone().into_iter().collect(),
+                variables,
+                millis_to_datetime(timestamp),
+            ));
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::store::Block;
+
+    // Three hosts whose usage_user counters grow at 1, 2 and 3 per second, sampled
+    // every 10s for a minute.
+    fn make_block() -> Block {
+        let mut block = Block::new();
+        for t in 0..7 {
+            for (i, (hostname, team)) in [("h1", "CHI"), ("h2", "CHI"), ("h3", "NYC")]
+                .iter()
+                .enumerate()
+            {
+                let mut labels = HashMap::new();
+                labels.insert(String::from("hostname"), String::from(*hostname));
+                labels.insert(String::from("team"), String::from(*team));
+                let mut variables = HashMap::new();
+                variables.insert(String::from("usage_user"), (10 * t * (i + 1)) as f64);
+                variables.insert(String::from("usage_system"), 5.0);
+                let timestamp = millis_to_datetime(10_000 * t as i64);
+                block
+                    .insert(Record::new(
+                        String::from("cpu"),
+                        labels,
+                        variables,
+                        timestamp,
+                    ))
+                    .unwrap();
+            }
+        }
+        block
+    }
+
+    // A result record, as (name, labels, value, millis).
+    type Row = (String, Vec<(String, String)>, f64, i64);
+
+    // Run a query at 60s, sorted by labels and time.
+    fn run(block: &Block, query: PromQuery) -> Vec<Row> {
+        let mut fetch = |select: Select| {
+            let mut result = select.eval(block);
+            result.unpack(block);
+            Ok(result.into_vec())
+        };
+        let now = millis_to_datetime(60_000);
+        let mut results: Vec<_> = execute(&query, now, &mut fetch)
+            .unwrap()
+            .into_iter()
+            .map(|r| {
+                let labels: Labels = r.get_populated_labels().into_iter().collect();
+                let value = *r.get_metric(String::from("value")).unwrap();
+                let millis = r.get_timestamp().timestamp_millis();
+                (r.get_name(), labels.into_iter().collect(), value, millis)
+            })
+            .collect();
+        results.sort_by(|a, b| (&a.1, a.3).cmp(&(&b.1, b.3)));
+        results
+    }
+
+    fn labels(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (String::from(*k), String::from(*v)))
+            .collect()
+    }
+
+    #[test]
+    fn test_execute() {
+        let block = make_block();
+        let query = |q: &str| PromQuery::new(String::from(q));
+        let h1 = labels(&[("hostname", "h1"), ("team", "CHI")]);
+        let h2 = labels(&[("hostname", "h2"), ("team", "CHI")]);
+        let h3 = labels(&[("hostname", "h3"), ("team", "NYC")]);
+
+        // Instant selectors take the latest sample of each flattened series.
+        let name = String::from("cpu_usage_user");
+        assert_eq!(
+            run(
+                &block,
+                query(r#"cpu_usage_user{team="CHI",hostname=~"h.*"}"#)
+            ),
+            vec![
+                (name.clone(), h1.clone(), 60.0, 60_000),
+                (name, h2, 120.0, 60_000)
+            ]
+        );
+
+        // Rates are aggregated by label.
+        assert_eq!(
+            run(&block, query("sum by (team) (rate(cpu_usage_user[1m]))")),
+            vec![
+                (String::new(), labels(&[("team", "CHI")]), 3.0, 60_000),
+                (String::new(), labels(&[("team", "NYC")]), 3.0, 60_000)
+            ]
+        );
+
+        // Vectors are matched on labels, and comparisons filter.
+        assert_eq!(
+            run(
+                &block,
+                query(r#"cpu_usage_user{hostname="h1"} / cpu_usage_system"#)
+            ),
+            vec![(String::new(), h1, 12.0, 60_000)]
+        );
+        assert_eq!(
+            run(&block, query("avg_over_time(cpu_usage_user[30s]) > 100")),
+            vec![(String::new(), h3, 150.0, 60_000)]
+        );
+
+        // Range queries evaluate at every step.
+        let mut range = query("sum(cpu_usage_user)");
+        range.start = Some(millis_to_datetime(0));
+        range.end = Some(millis_to_datetime(60_000));
+        range.step = Some(30_000);
+        let values: Vec<(f64, i64)> = run(&block, range).iter().map(|x| (x.2, x.3)).collect();
+        assert_eq!(values, vec![(0.0, 0), (180.0, 30_000), (360.0, 60_000)]);
+    }
+}
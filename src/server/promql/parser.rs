@@ -0,0 +1,607 @@
+use crate::error::Error;
+use crate::server::operators::select::{Condition, Op, Type};
+use crate::server::promql::{AggregateOp, BinaryOp, Expr, Function, Grouping, Selector};
+use crate::server::record::NAME_LABEL;
+use regex::Regex;
+
+// PromQL parser. The grammar, loosest binding first, is:
+//   expr       := additive (("==" | "!=" | ">" | "<" | ">=" | "<=") additive)*
+//   additive   := term (("+" | "-") term)*
+//   term       := unary (("*" | "/") unary)*
+//   unary      := ("-" | "+") unary | postfix
+//   postfix    := primary ["[" duration "]"]
+//   primary    := number | "(" expr ")" | selector
+//               | function "(" expr ")"
+//               | aggregation [grouping] "(" expr ")" [grouping]
+//   selector   := metric ["{" [matcher ("," matcher)* [","]] "}"] | "{" matchers "}"
+//   matcher    := label ("=" | "!=" | "=~" | "!~") string
+//   grouping   := ("by" | "without") "(" [label ("," label)*] ")"
+// Durations may be compound, as in 1h30m, with units ms, s, m, h, d and w.
+
+// CONSTANTS
+// Symbols, longest first so that e.g. ">=" isn't read as ">".
+const SYMBOLS: [&str; 20] = [
+    "==", "!=", "=~", "!~", ">=", "<=", "=", ">", "<", "+", "-", "*", "/", "(", ")", "{", "}", "[",
+    "]", ",",
+];
+
+// Token Enum.
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Number(f64),
+    Duration(i64),
+    Symbol(&'static str),
+}
+impl Token {
+    // Describe the token for error messages.
+    fn describe(&self) -> String {
+        match self {
+            Token::Ident(s) => format!("'{}'", s),
+            Token::Str(s) => format!("string \"{}\"", s),
+            Token::Number(v) => format!("number {}", v),
+            Token::Duration(_) => String::from("duration"),
+            Token::Symbol(s) => format!("'{}'", s),
+        }
+    }
+}
+
+// ParseError Struct. A message, and the (0-based) character position it refers to.
+#[derive(Debug, PartialEq)]
+struct ParseError {
+    pos: usize,
+    message: String,
+}
+impl ParseError {
+    fn into_error(self) -> Error {
+        Error::QueryParse(self.pos + 1, self.message)
+    }
+}
+
+// Parse a PromQL expression.
+pub fn parse(query: &str) -> Result<Expr, Error> {
+    let tokens = tokenize(query).map_err(ParseError::into_error)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        end: query.chars().count(),
+    };
+    let expr = parser.parse_expr().map_err(ParseError::into_error)?;
+    if parser.peek().is_some() {
+        return Err(parser.error("end of query").into_error());
+    }
+    Ok(expr)
+}
+
+// Get the millis in a duration unit.
+fn unit_millis(unit: &str) -> Option<i64> {
+    match unit {
+        "ms" => Some(1),
+        "s" => Some(1000),
+        "m" => Some(60 * 1000),
+        "h" => Some(60 * 60 * 1000),
+        "d" => Some(24 * 60 * 60 * 1000),
+        "w" => Some(7 * 24 * 60 * 60 * 1000),
+        _ => None,
+    }
+}
+
+// Split a query into tokens, each with its character position.
+fn tokenize(query: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        // Metric names, label names and keywords.
+        if c.is_alphabetic() || c == '_' {
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == ':')
+            {
+                i += 1;
+            }
+            let ident: String = chars[start..i].iter().collect();
+            tokens.push((Token::Ident(ident), start));
+        }
+        // Numbers, which become durations if a unit follows.
+        else if c.is_ascii_digit()
+            || (c == '.' && chars.get(i + 1).is_some_and(|x| x.is_ascii_digit()))
+        {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                let mut j = i + 1;
+                if j < chars.len() && (chars[j] == '+' || chars[j] == '-') {
+                    j += 1;
+                }
+                if j < chars.len() && chars[j].is_ascii_digit() {
+                    i = j;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            if i == chars.len() || !chars[i].is_alphabetic() {
+                let number: String = chars[start..i].iter().collect();
+                let value: f64 = number.parse().map_err(|_| ParseError {
+                    pos: start,
+                    message: format!("invalid number {}", number),
+                })?;
+                tokens.push((Token::Number(value), start));
+                continue;
+            }
+
+            // Durations are integers with units, any number of times over.
+            i = start;
+            let mut millis = 0;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                let digits_start = i;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let unit_start = i;
+                while i < chars.len() && chars[i].is_alphabetic() {
+                    i += 1;
+                }
+                let digits: String = chars[digits_start..unit_start].iter().collect();
+                let unit: String = chars[unit_start..i].iter().collect();
+                let value: i64 = digits.parse().map_err(|_| ParseError {
+                    pos: digits_start,
+                    message: format!("invalid duration {}", digits),
+                })?;
+                let unit = unit_millis(&unit).ok_or_else(|| ParseError {
+                    pos: unit_start,
+                    message: format!("unknown duration unit {}", unit),
+                })?;
+                millis = value
+                    .checked_mul(unit)
+                    .and_then(|x| x.checked_add(millis))
+                    .ok_or_else(|| ParseError {
+                        pos: digits_start,
+                        message: String::from("duration is too long"),
+                    })?;
+            }
+            tokens.push((Token::Duration(millis), start));
+        }
+        // Quoted strings, with backslash escapes.
+        else if c == '"' || c == '\'' || c == '`' {
+            i += 1;
+            let mut s = String::new();
+            loop {
+                match chars.get(i) {
+                    None => {
+                        return Err(ParseError {
+                            pos: start,
+                            message: String::from("unterminated string"),
+                        })
+                    }
+                    Some(x) if *x == c => break,
+                    Some('\\') if c != '`' => {
+                        i += 1;
+                        match chars.get(i) {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some(x) => s.push(*x),
+                            None => continue,
+                        }
+                    }
+                    Some(x) => s.push(*x),
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push((Token::Str(s), start));
+        }
+        // Symbols.
+        else {
+            let symbol = SYMBOLS.iter().find(|symbol| {
+                symbol
+                    .chars()
+                    .enumerate()
+                    .all(|(j, x)| chars.get(i + j) == Some(&x))
+            });
+            match symbol {
+                Some(symbol) => {
+                    i += symbol.chars().count();
+                    tokens.push((Token::Symbol(symbol), start));
+                }
+                None => {
+                    return Err(ParseError {
+                        pos: start,
+                        message: format!("unexpected character '{}'", c),
+                    })
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// Get the function with a name.
+fn get_function(name: &str) -> Option<Function> {
+    match name {
+        "rate" => Some(Function::Rate),
+        "increase" => Some(Function::Increase),
+        "avg_over_time" => Some(Function::AvgOverTime),
+        "sum_over_time" => Some(Function::SumOverTime),
+        "min_over_time" => Some(Function::MinOverTime),
+        "max_over_time" => Some(Function::MaxOverTime),
+        "count_over_time" => Some(Function::CountOverTime),
+        _ => None,
+    }
+}
+
+// Get the aggregation with a name.
+fn get_aggregate_op(name: &str) -> Option<AggregateOp> {
+    match name {
+        "sum" => Some(AggregateOp::Sum),
+        "avg" => Some(AggregateOp::Avg),
+        "min" => Some(AggregateOp::Min),
+        "max" => Some(AggregateOp::Max),
+        "count" => Some(AggregateOp::Count),
+        _ => None,
+    }
+}
+
+// Parser Struct. A recursive descent parser over the tokens of an expression.
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    end: usize,
+}
+impl Parser {
+    // Get the next token, without consuming it.
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|x| &x.0)
+    }
+
+    // Get the character position of the next token.
+    fn position(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.end, |x| x.1)
+    }
+
+    // Make an error at the next token.
+    fn error(&self, expected: &str) -> ParseError {
+        let found = match self.peek() {
+            Some(token) => token.describe(),
+            None => String::from("end of query"),
+        };
+        ParseError {
+            pos: self.position(),
+            message: format!("expected {}, found {}", expected, found),
+        }
+    }
+
+    // Consume the next token if it is the given symbol.
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        match self.peek() {
+            Some(Token::Symbol(s)) if *s == symbol => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Consume the given symbol, or fail.
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), ParseError> {
+        match self.eat_symbol(symbol) {
+            true => Ok(()),
+            false => Err(self.error(&format!("'{}'", symbol))),
+        }
+    }
+
+    // Consume a name.
+    fn expect_ident(&mut self, expected: &str) -> Result<String, ParseError> {
+        match self.peek() {
+            Some(Token::Ident(s)) => {
+                let s = s.clone();
+                self.pos += 1;
+                Ok(s)
+            }
+            _ => Err(self.error(expected)),
+        }
+    }
+
+    // Apply binary operators, left-associatively, over operands parsed by operand.
+    fn parse_binary(
+        &mut self,
+        ops: &[(&str, BinaryOp)],
+        operand: fn(&mut Self) -> Result<Expr, ParseError>,
+    ) -> Result<Expr, ParseError> {
+        let mut expr = operand(self)?;
+        'outer: loop {
+            for (symbol, op) in ops {
+                if self.eat_symbol(symbol) {
+                    expr = Expr::Binary(*op, Box::new(expr), Box::new(operand(self)?));
+                    continue 'outer;
+                }
+            }
+            return Ok(expr);
+        }
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr, ParseError> {
+        let ops = [
+            ("==", BinaryOp::Eq),
+            ("!=", BinaryOp::NEq),
+            (">", BinaryOp::Gt),
+            ("<", BinaryOp::Lt),
+            (">=", BinaryOp::GtEq),
+            ("<=", BinaryOp::LtEq),
+        ];
+        self.parse_binary(&ops, Self::parse_additive)
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr, ParseError> {
+        let ops = [("+", BinaryOp::Add), ("-", BinaryOp::Sub)];
+        self.parse_binary(&ops, Self::parse_term)
+    }
+
+    fn parse_term(&mut self) -> Result<Expr, ParseError> {
+        let ops = [("*", BinaryOp::Mul), ("/", BinaryOp::Div)];
+        self.parse_binary(&ops, Self::parse_unary)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        if self.eat_symbol("-") {
+            return Ok(match self.parse_unary()? {
+                Expr::Number(v) => Expr::Number(-v),
+                expr => Expr::Neg(Box::new(expr)),
+            });
+        }
+        if self.eat_symbol("+") {
+            return self.parse_unary();
+        }
+        self.parse_postfix()
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr, ParseError> {
+        let expr_pos = self.position();
+        let expr = self.parse_primary()?;
+        if !self.eat_symbol("[") {
+            return Ok(expr);
+        }
+        let selector = match expr {
+            Expr::Selector(selector) => selector,
+            _ => {
+                return Err(ParseError {
+                    pos: expr_pos,
+                    message: String::from("ranges can only be taken of vector selectors"),
+                })
+            }
+        };
+        let range = match self.peek() {
+            Some(Token::Duration(millis)) if *millis > 0 => *millis,
+            _ => return Err(self.error("a duration")),
+        };
+        self.pos += 1;
+        self.expect_symbol("]")?;
+        Ok(Expr::Range(selector, range))
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
+        match self.peek().cloned() {
+            Some(Token::Number(v)) => {
+                self.pos += 1;
+                Ok(Expr::Number(v))
+            }
+            Some(Token::Symbol("(")) => {
+                self.pos += 1;
+                let expr = self.parse_expr()?;
+                self.expect_symbol(")")?;
+                Ok(expr)
+            }
+            Some(Token::Symbol("{")) => Ok(Expr::Selector(self.parse_selector(None)?)),
+            Some(Token::Ident(name)) => {
+                let name_pos = self.position();
+                self.pos += 1;
+                let is_call = self.peek() == Some(&Token::Symbol("("));
+                let is_grouped = match self.peek() {
+                    Some(Token::Ident(s)) => s == "by" || s == "without",
+                    _ => false,
+                };
+                if let Some(op) = get_aggregate_op(&name).filter(|_| is_call || is_grouped) {
+                    return self.parse_aggregation(op);
+                }
+                if is_call {
+                    let function = get_function(&name).ok_or_else(|| ParseError {
+                        pos: name_pos,
+                        message: format!("unknown function {}", name),
+                    })?;
+                    self.pos += 1;
+                    let arg = self.parse_expr()?;
+                    self.expect_symbol(")")?;
+                    return Ok(Expr::Call(function, Box::new(arg)));
+                }
+                Ok(Expr::Selector(self.parse_selector(Some(name))?))
+            }
+            _ => Err(self.error("an expression")),
+        }
+    }
+
+    // Parse an aggregation after its name. The grouping may come before or after.
+    fn parse_aggregation(&mut self, op: AggregateOp) -> Result<Expr, ParseError> {
+        let mut grouping = self.parse_grouping()?;
+        self.expect_symbol("(")?;
+        let arg = self.parse_expr()?;
+        self.expect_symbol(")")?;
+        if grouping == Grouping::All {
+            grouping = self.parse_grouping()?;
+        }
+        Ok(Expr::Aggregate(op, grouping, Box::new(arg)))
+    }
+
+    fn parse_grouping(&mut self) -> Result<Grouping, ParseError> {
+        let by = match self.peek() {
+            Some(Token::Ident(s)) if s == "by" => true,
+            Some(Token::Ident(s)) if s == "without" => false,
+            _ => return Ok(Grouping::All),
+        };
+        self.pos += 1;
+        self.expect_symbol("(")?;
+        let mut labels = vec![];
+        while !self.eat_symbol(")") {
+            labels.push(self.expect_ident("a label name")?);
+            if !self.eat_symbol(",") {
+                self.expect_symbol(")")?;
+                break;
+            }
+        }
+        Ok(match by {
+            true => Grouping::By(labels),
+            false => Grouping::Without(labels),
+        })
+    }
+
+    // Parse the label matchers of a selector, if any, after its metric name.
+    fn parse_selector(&mut self, mut metric: Option<String>) -> Result<Selector, ParseError> {
+        let start = self.position();
+        let mut matchers = vec![];
+        if self.eat_symbol("{") {
+            while !self.eat_symbol("}") {
+                let key_pos = self.position();
+                let key = self.expect_ident("a label name")?;
+                let op = match self.peek() {
+                    Some(Token::Symbol("=")) => Op::Eq,
+                    Some(Token::Symbol("!=")) => Op::NEq,
+                    Some(Token::Symbol("=~")) => Op::Match,
+                    Some(Token::Symbol("!~")) => Op::NMatch,
+                    _ => return Err(self.error("'=', '!=', '=~' or '!~'")),
+                };
+                self.pos += 1;
+                let value_pos = self.position();
+                let value = match self.peek() {
+                    Some(Token::Str(s)) => s.clone(),
+                    _ => return Err(self.error("a string")),
+                };
+                self.pos += 1;
+                if op == Op::Match || op == Op::NMatch {
+                    if let Err(e) = Regex::new(&value) {
+                        return Err(ParseError {
+                            pos: value_pos,
+                            message: format!("invalid regex: {}", e),
+                        });
+                    }
+                }
+
+                // The metric name can also be given as a label, but only exactly.
+                if key == NAME_LABEL {
+                    if op != Op::Eq || metric.is_some() {
+                        return Err(ParseError {
+                            pos: key_pos,
+                            message: format!("only one {}=\"...\" matcher is supported", key),
+                        });
+                    }
+                    metric = Some(value);
+                } else {
+                    matchers.push(Condition {
+                        lhs: Type::LabelKey(key),
+                        rhs: Type::LabelValue(value),
+                        op,
+                    });
+                }
+                if !self.eat_symbol(",") {
+                    self.expect_symbol("}")?;
+                    break;
+                }
+            }
+        }
+        if metric.is_none() && matchers.is_empty() {
+            return Err(ParseError {
+                pos: start,
+                message: String::from("selectors need a metric name or a label matcher"),
+            });
+        }
+        Ok(Selector { metric, matchers })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn label(key: &str, value: &str, op: Op) -> Condition {
+        Condition {
+            lhs: Type::LabelKey(String::from(key)),
+            rhs: Type::LabelValue(String::from(value)),
+            op,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let selector = Selector {
+            metric: Some(String::from("cpu")),
+            matchers: vec![
+                label("team", "CHI", Op::Eq),
+                label("hostname", "h.*", Op::Match),
+            ],
+        };
+        assert_eq!(
+            parse(r#"cpu{team="CHI",hostname=~"h.*"}[1h30m]"#).unwrap(),
+            Expr::Range(selector.clone(), 90 * 60 * 1000)
+        );
+        assert_eq!(
+            parse(r#"{__name__="cpu", team="CHI", hostname=~"h.*",}"#).unwrap(),
+            Expr::Selector(selector.clone())
+        );
+
+        // Groupings go before or after, and * binds tighter than + and >.
+        let rate = Expr::Call(
+            Function::Rate,
+            Box::new(Expr::Range(selector.clone(), 5 * 60 * 1000)),
+        );
+        let by = Grouping::By(vec![String::from("team")]);
+        let sum = Expr::Aggregate(AggregateOp::Sum, by, Box::new(rate));
+        let query = r#"sum by (team) (rate(cpu{team="CHI",hostname=~"h.*"}[5m]))"#;
+        assert_eq!(parse(query).unwrap(), sum);
+        let query = r#"sum(rate(cpu{team="CHI",hostname=~"h.*"}[5m])) by (team)"#;
+        assert_eq!(parse(query).unwrap(), sum);
+        assert_eq!(
+            parse("1 + 2 * -x > 3").unwrap(),
+            Expr::Binary(
+                BinaryOp::Gt,
+                Box::new(Expr::Binary(
+                    BinaryOp::Add,
+                    Box::new(Expr::Number(1.0)),
+                    Box::new(Expr::Binary(
+                        BinaryOp::Mul,
+                        Box::new(Expr::Number(2.0)),
+                        Box::new(Expr::Neg(Box::new(Expr::Selector(Selector {
+                            metric: Some(String::from("x")),
+                            matchers: vec![],
+                        })))),
+                    )),
+                )),
+                Box::new(Expr::Number(3.0)),
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let get_error = |query: &str| match parse(query) {
+            Err(Error::QueryParse(column, message)) => (column, message),
+            x => panic!("expected a parse error, got {:?}", x),
+        };
+        assert_eq!(get_error("foo(x[5m])").0, 1);
+        assert_eq!(get_error("rate(x)[5m]").0, 1);
+        assert_eq!(get_error("x[5q]").0, 4);
+        assert_eq!(get_error(r#"x{a=~"("}"#).0, 6);
+        assert_eq!(get_error(r#"x{__name__=~"y"}"#).0, 3);
+        assert_eq!(get_error("{}").0, 1);
+        assert_eq!(
+            get_error("sum(x"),
+            (6, String::from("expected ')', found end of query"))
+        );
+    }
+}
@@ -0,0 +1,704 @@
+use crate::error::Error;
+use crate::server::operators::select::{Condition, Conditions, Expr, Op, Predicate, Select, Type};
+use chrono::{DateTime, Duration, Utc};
+use regex::Regex;
+
+// Text query language. Parses queries such as
+//   SELECT cpu WHERE team="CHI" AND (usage_user > 50 OR region="us-west-1") FROM -1h
+// into the same Select that the JSON form deserializes into. The grammar is:
+//   query      := SELECT name WHERE or [FROM time [TO time]]
+//   or         := and (OR and)*
+//   and        := unary (AND unary)*
+//   unary      := NOT unary | EXISTS label | "(" or ")" | comparison
+//   comparison := label ("=" | "!=" | "=~" | "!~" | "^=") string
+//               | arith ("=" | "!=" | ">" | "<" | ">=" | "<=") arith
+//   arith      := term (("+" | "-") term)*
+//   term       := factor (("*" | "/") factor)*
+//   factor     := number | variable | "-" factor | "(" arith ")"
+//   time       := string (RFC 3339) | NOW ["-" duration] | "-" duration
+// Keywords are case-insensitive, "=~" / "!~" take a regex matching the whole value,
+// "^=" takes a prefix, and durations are a number with a unit (ms, s, m, h, d, w).
+
+// CONSTANTS
+const KEYWORDS: [&str; 9] = [
+    "SELECT", "WHERE", "FROM", "TO", "AND", "OR", "NOT", "EXISTS", "NOW",
+];
+// Symbols, longest first so that e.g. ">=" isn't read as ">".
+const SYMBOLS: [&str; 15] = [
+    "!=", "=~", "!~", "^=", ">=", "<=", "=", ">", "<", "+", "-", "*", "/", "(", ")",
+];
+
+// Token Enum.
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Number(f64),
+    Duration(Duration),
+    Symbol(&'static str),
+}
+impl Token {
+    // Describe the token for error messages.
+    fn describe(&self) -> String {
+        match self {
+            Token::Ident(s) => format!("'{}'", s),
+            Token::Str(s) => format!("string \"{}\"", s),
+            Token::Number(v) => format!("number {}", v),
+            Token::Duration(_) => String::from("duration"),
+            Token::Symbol(s) => format!("'{}'", s),
+        }
+    }
+}
+
+// ParseError Struct. A message, and the (0-based) character position it refers to.
+#[derive(Debug, PartialEq)]
+struct ParseError {
+    pos: usize,
+    message: String,
+}
+impl ParseError {
+    fn into_error(self) -> Error {
+        Error::QueryParse(self.pos + 1, self.message)
+    }
+}
+
+// Parse a text query, resolving relative times against now.
+pub fn parse(query: &str, now: DateTime<Utc>) -> Result<Select, Error> {
+    let tokens = tokenize(query).map_err(ParseError::into_error)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        end: query.chars().count(),
+        now,
+    };
+    parser.parse_query().map_err(ParseError::into_error)
+}
+
+// Split a query into tokens, each with its character position.
+fn tokenize(query: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        // Identifiers and keywords.
+        if c.is_alphabetic() || c == '_' {
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+            {
+                i += 1;
+            }
+            let ident: String = chars[start..i].iter().collect();
+            tokens.push((Token::Ident(ident), start));
+        }
+        // Numbers, which become durations if a unit follows.
+        else if c.is_ascii_digit()
+            || (c == '.' && chars.get(i + 1).is_some_and(|x| x.is_ascii_digit()))
+        {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                let mut j = i + 1;
+                if j < chars.len() && (chars[j] == '+' || chars[j] == '-') {
+                    j += 1;
+                }
+                if j < chars.len() && chars[j].is_ascii_digit() {
+                    i = j;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            let number: String = chars[start..i].iter().collect();
+            let value: f64 = number.parse().map_err(|_| ParseError {
+                pos: start,
+                message: format!("invalid number {}", number),
+            })?;
+            let unit_start = i;
+            while i < chars.len() && chars[i].is_alphabetic() {
+                i += 1;
+            }
+            if unit_start == i {
+                tokens.push((Token::Number(value), start));
+            } else {
+                let unit: String = chars[unit_start..i].iter().collect();
+                let millis = match unit.as_str() {
+                    "ms" => 1.0,
+                    "s" => 1000.0,
+                    "m" => 60.0 * 1000.0,
+                    "h" => 60.0 * 60.0 * 1000.0,
+                    "d" => 24.0 * 60.0 * 60.0 * 1000.0,
+                    "w" => 7.0 * 24.0 * 60.0 * 60.0 * 1000.0,
+                    _ => {
+                        return Err(ParseError {
+                            pos: unit_start,
+                            message: format!("unknown duration unit {}", unit),
+                        })
+                    }
+                };
+                let duration = Duration::milliseconds((value * millis) as i64);
+                tokens.push((Token::Duration(duration), start));
+            }
+        }
+        // Quoted strings, with backslash escapes.
+        else if c == '"' || c == '\'' {
+            i += 1;
+            let mut s = String::new();
+            loop {
+                match chars.get(i) {
+                    None => {
+                        return Err(ParseError {
+                            pos: start,
+                            message: String::from("unterminated string"),
+                        })
+                    }
+                    Some(x) if *x == c => break,
+                    Some('\\') => {
+                        i += 1;
+                        match chars.get(i) {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some(x) => s.push(*x),
+                            None => continue,
+                        }
+                    }
+                    Some(x) => s.push(*x),
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push((Token::Str(s), start));
+        }
+        // Symbols.
+        else {
+            let symbol = SYMBOLS.iter().find(|symbol| {
+                symbol
+                    .chars()
+                    .enumerate()
+                    .all(|(j, x)| chars.get(i + j) == Some(&x))
+            });
+            match symbol {
+                Some(symbol) => {
+                    i += symbol.chars().count();
+                    tokens.push((Token::Symbol(symbol), start));
+                }
+                None => {
+                    return Err(ParseError {
+                        pos: start,
+                        message: format!("unexpected character '{}'", c),
+                    })
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// Parser Struct. A recursive descent parser over the tokens of a query.
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    end: usize,
+    now: DateTime<Utc>,
+}
+impl Parser {
+    // Get the next token, without consuming it.
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|x| &x.0)
+    }
+
+    // Get the character position of the next token.
+    fn position(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.end, |x| x.1)
+    }
+
+    // Make an error at the next token.
+    fn error(&self, expected: &str) -> ParseError {
+        let found = match self.peek() {
+            Some(token) => token.describe(),
+            None => String::from("end of query"),
+        };
+        ParseError {
+            pos: self.position(),
+            message: format!("expected {}, found {}", expected, found),
+        }
+    }
+
+    // Consume the next token if it is the given keyword.
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(s)) if s.eq_ignore_ascii_case(keyword) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Consume the next token if it is the given symbol.
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        match self.peek() {
+            Some(Token::Symbol(s)) if *s == symbol => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Consume a name, which can't be a keyword.
+    fn expect_ident(&mut self, expected: &str) -> Result<String, ParseError> {
+        match self.peek() {
+            Some(Token::Ident(s)) if !is_keyword(s) => {
+                let s = s.clone();
+                self.pos += 1;
+                Ok(s)
+            }
+            _ => Err(self.error(expected)),
+        }
+    }
+
+    fn parse_query(&mut self) -> Result<Select, ParseError> {
+        if !self.eat_keyword("SELECT") {
+            return Err(self.error("SELECT"));
+        }
+        let name = self.expect_ident("a name")?;
+        if !self.eat_keyword("WHERE") {
+            return Err(self.error("WHERE"));
+        }
+        let condition = self.parse_or()?;
+        let mut start = None;
+        let mut end = None;
+        if self.eat_keyword("FROM") {
+            start = Some(self.parse_time()?);
+            if self.eat_keyword("TO") {
+                end = Some(self.parse_time()?);
+            }
+        }
+        if self.peek().is_some() {
+            return Err(self.error("end of query"));
+        }
+        Ok(Select {
+            name: name.clone(),
+            predicate: Predicate { name, condition },
+            start,
+            end,
+            aggregation: None,
+            by_series: false,
+        })
+    }
+
+    fn parse_or(&mut self) -> Result<Conditions, ParseError> {
+        let mut condition = self.parse_and()?;
+        while self.eat_keyword("OR") {
+            let rhs = self.parse_and()?;
+            condition = Conditions::Or(Box::new(condition), Box::new(rhs));
+        }
+        Ok(condition)
+    }
+
+    fn parse_and(&mut self) -> Result<Conditions, ParseError> {
+        let mut condition = self.parse_unary()?;
+        while self.eat_keyword("AND") {
+            let rhs = self.parse_unary()?;
+            condition = Conditions::And(Box::new(condition), Box::new(rhs));
+        }
+        Ok(condition)
+    }
+
+    fn parse_unary(&mut self) -> Result<Conditions, ParseError> {
+        if self.eat_keyword("NOT") {
+            return Ok(Conditions::Not(Box::new(self.parse_unary()?)));
+        }
+        if self.eat_keyword("EXISTS") {
+            let key = self.expect_ident("a label name")?;
+            return Ok(Conditions::Leaf(Condition {
+                lhs: Type::LabelKey(key),
+                rhs: Type::LabelValue(String::new()),
+                op: Op::Exists,
+            }));
+        }
+
+        // A parenthesis either groups conditions or starts an arithmetic expression, as
+        // in "(a + b) > 1". Try the former first, and keep whichever error got further.
+        if self.peek() == Some(&Token::Symbol("(")) {
+            let start = self.pos;
+            self.pos += 1;
+            let grouped = self
+                .parse_or()
+                .and_then(|condition| match self.eat_symbol(")") {
+                    true => Ok(condition),
+                    false => Err(self.error("')'")),
+                });
+            let grouped_err = match grouped {
+                Ok(condition) if !self.at_arith_symbol() => return Ok(condition),
+                Ok(_) => None,
+                Err(e) => Some(e),
+            };
+            self.pos = start;
+            return match (self.parse_comparison(), grouped_err) {
+                (Ok(condition), _) => Ok(condition),
+                (Err(e), Some(grouped_err)) if grouped_err.pos > e.pos => Err(grouped_err),
+                (Err(e), _) => Err(e),
+            };
+        }
+        self.parse_comparison()
+    }
+
+    // Returns true if the next token continues an arithmetic expression or comparison.
+    fn at_arith_symbol(&self) -> bool {
+        match self.peek() {
+            Some(Token::Symbol(s)) => *s != "(" && *s != ")",
+            _ => false,
+        }
+    }
+
+    fn parse_comparison(&mut self) -> Result<Conditions, ParseError> {
+        let lhs_pos = self.position();
+        let lhs = self.parse_arith()?;
+        let op_pos = self.position();
+        let op = match self.peek() {
+            Some(Token::Symbol(s)) => match *s {
+                "=" => Op::Eq,
+                "!=" => Op::NEq,
+                ">" => Op::Gt,
+                "<" => Op::Lt,
+                ">=" => Op::GtEq,
+                "<=" => Op::LtEq,
+                "=~" => Op::Match,
+                "!~" => Op::NMatch,
+                "^=" => Op::Prefix,
+                _ => return Err(self.error("a comparison")),
+            },
+            _ => return Err(self.error("a comparison")),
+        };
+        self.pos += 1;
+
+        // Strings are label values; the left-hand side must then be a label name.
+        if let Some(Token::Str(value)) = self.peek() {
+            let value = value.clone();
+            let value_pos = self.position();
+            let key = match lhs {
+                Expr::Variable(key) => key,
+                _ => {
+                    return Err(ParseError {
+                        pos: lhs_pos,
+                        message: String::from("expected a label name before a string"),
+                    })
+                }
+            };
+            match op {
+                Op::Eq | Op::NEq | Op::Prefix => (),
+                Op::Match | Op::NMatch => {
+                    if let Err(e) = Regex::new(&value) {
+                        return Err(ParseError {
+                            pos: value_pos,
+                            message: format!("invalid regex: {}", e),
+                        });
+                    }
+                }
+                _ => {
+                    return Err(ParseError {
+                        pos: op_pos,
+                        message: String::from(
+                            "labels can only be compared with =, !=, =~, !~ or ^=",
+                        ),
+                    })
+                }
+            }
+            self.pos += 1;
+            return Ok(Conditions::Leaf(Condition {
+                lhs: Type::LabelKey(key),
+                rhs: Type::LabelValue(value),
+                op,
+            }));
+        }
+
+        // Otherwise, compare numeric expressions.
+        match op {
+            Op::Match | Op::NMatch | Op::Prefix => Err(self.error("a string")),
+            _ => {
+                let rhs = self.parse_arith()?;
+                Ok(Conditions::Leaf(Condition {
+                    lhs: to_type(lhs),
+                    rhs: to_type(rhs),
+                    op,
+                }))
+            }
+        }
+    }
+
+    fn parse_arith(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.parse_term()?;
+        loop {
+            if self.eat_symbol("+") {
+                expr = Expr::Add(Box::new(expr), Box::new(self.parse_term()?));
+            } else if self.eat_symbol("-") {
+                expr = Expr::Sub(Box::new(expr), Box::new(self.parse_term()?));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_term(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.parse_factor()?;
+        loop {
+            if self.eat_symbol("*") {
+                expr = Expr::Mul(Box::new(expr), Box::new(self.parse_factor()?));
+            } else if self.eat_symbol("/") {
+                expr = Expr::Div(Box::new(expr), Box::new(self.parse_factor()?));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_factor(&mut self) -> Result<Expr, ParseError> {
+        if self.eat_symbol("-") {
+            return Ok(match self.parse_factor()? {
+                Expr::Metric(v) => Expr::Metric(-v),
+                expr => Expr::Sub(Box::new(Expr::Metric(0.0)), Box::new(expr)),
+            });
+        }
+        if self.eat_symbol("(") {
+            let expr = self.parse_arith()?;
+            if !self.eat_symbol(")") {
+                return Err(self.error("')'"));
+            }
+            return Ok(expr);
+        }
+        match self.peek() {
+            Some(Token::Number(v)) => {
+                let v = *v;
+                self.pos += 1;
+                Ok(Expr::Metric(v))
+            }
+            Some(Token::Ident(_)) => Ok(Expr::Variable(self.expect_ident("a name")?)),
+            _ => Err(self.error("a name or number")),
+        }
+    }
+
+    fn parse_time(&mut self) -> Result<DateTime<Utc>, ParseError> {
+        if self.eat_keyword("NOW") {
+            if self.eat_symbol("-") {
+                return self.parse_ago();
+            }
+            return Ok(self.now);
+        }
+        if self.eat_symbol("-") {
+            return self.parse_ago();
+        }
+        match self.peek() {
+            Some(Token::Str(s)) => match DateTime::parse_from_rfc3339(s) {
+                Ok(time) => {
+                    self.pos += 1;
+                    Ok(time.with_timezone(&Utc))
+                }
+                Err(e) => Err(ParseError {
+                    pos: self.position(),
+                    message: format!("invalid timestamp: {}", e),
+                }),
+            },
+            _ => Err(self.error("a time")),
+        }
+    }
+
+    // Parse a duration, as the time that long before now.
+    fn parse_ago(&mut self) -> Result<DateTime<Utc>, ParseError> {
+        let pos = self.position();
+        let duration = self.parse_duration()?;
+        self.now
+            .checked_sub_signed(duration)
+            .ok_or_else(|| ParseError {
+                pos,
+                message: String::from("time out of range"),
+            })
+    }
+
+    fn parse_duration(&mut self) -> Result<Duration, ParseError> {
+        match self.peek() {
+            Some(Token::Duration(d)) => {
+                let d = *d;
+                self.pos += 1;
+                Ok(d)
+            }
+            _ => Err(self.error("a duration, e.g. 1h")),
+        }
+    }
+}
+
+// Returns true if the identifier is a keyword.
+fn is_keyword(s: &str) -> bool {
+    KEYWORDS.iter().any(|x| x.eq_ignore_ascii_case(s))
+}
+
+// Convert an arithmetic expression into the side of a condition.
+fn to_type(expr: Expr) -> Type {
+    match expr {
+        Expr::Variable(s) => Type::Variable(s),
+        Expr::Metric(v) => Type::Metric(v),
+        expr => Type::Expr(expr),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::NaiveDateTime;
+
+    fn now() -> DateTime<Utc> {
+        DateTime::from_utc(NaiveDateTime::from_timestamp(100000, 0), Utc)
+    }
+
+    fn parse_condition(query: &str) -> Conditions {
+        parse(&format!("SELECT s WHERE {}", query), now())
+            .unwrap()
+            .predicate
+            .condition
+    }
+
+    fn get_error(query: &str) -> (usize, String) {
+        match parse(query, now()) {
+            Err(Error::QueryParse(column, message)) => (column, message),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_example() {
+        // The text form of evaluation/testdata/select_example.json.
+        let data = r#"{
+            "name": "s",
+            "predicate": {
+                "name": "s",
+                "condition": {
+                    "And": [
+                        {
+                            "Or": [
+                                {
+                                    "And": [
+                                        { "Leaf": { "lhs": {"LabelKey": "service_environment"}, "rhs": {"LabelValue": "test"}, "op": "Eq" } },
+                                        { "Leaf": { "lhs": {"Variable": "processes_forked"}, "rhs": {"Metric": 0.0}, "op": "Gt" } }
+                                    ]
+                                },
+                                { "Leaf": { "lhs": {"Variable": "context_switches"}, "rhs": {"Metric": 0.0}, "op": "GtEq" } }
+                            ]
+                        },
+                        { "Leaf": { "lhs": {"LabelKey": "team"}, "rhs": {"LabelValue": "CHI"}, "op": "Eq" } }
+                    ]
+                }
+            },
+            "start": "1970-01-02T03:46:40Z"
+        }"#;
+        let expected: Select = serde_json::from_str(data).unwrap();
+        let query = r#"select s where (service_environment = "test" and processes_forked > 0
+            or context_switches >= 0) and team = 'CHI' from -0s"#;
+        assert_eq!(parse(query, now()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_parse_conditions() {
+        let leaf = |lhs: Type, rhs: Type, op: Op| Conditions::Leaf(Condition { lhs, rhs, op });
+        let label = |key: &str, value: &str, op: Op| {
+            leaf(
+                Type::LabelKey(String::from(key)),
+                Type::LabelValue(String::from(value)),
+                op,
+            )
+        };
+
+        // AND binds tighter than OR.
+        assert_eq!(
+            parse_condition(r#"a="1" OR b="2" AND c="3""#),
+            Conditions::Or(
+                Box::new(label("a", "1", Op::Eq)),
+                Box::new(Conditions::And(
+                    Box::new(label("b", "2", Op::Eq)),
+                    Box::new(label("c", "3", Op::Eq)),
+                )),
+            )
+        );
+
+        // Label matchers and negation.
+        assert_eq!(
+            parse_condition(r#"NOT hostname =~ "web-.*" AND EXISTS region"#),
+            Conditions::And(
+                Box::new(Conditions::Not(Box::new(label(
+                    "hostname",
+                    "web-.*",
+                    Op::Match
+                )))),
+                Box::new(label("region", "", Op::Exists)),
+            )
+        );
+        assert_eq!(parse_condition(r#"a ^= "x""#), label("a", "x", Op::Prefix));
+
+        // Arithmetic, including a parenthesized left-hand side.
+        let var = |s: &str| Box::new(Expr::Variable(String::from(s)));
+        assert_eq!(
+            parse_condition("(usage_user + usage_system) * 2 > -90"),
+            leaf(
+                Type::Expr(Expr::Mul(
+                    Box::new(Expr::Add(var("usage_user"), var("usage_system"))),
+                    Box::new(Expr::Metric(2.0)),
+                )),
+                Type::Metric(-90.0),
+                Op::Gt,
+            )
+        );
+        assert_eq!(
+            parse_condition("usage_user <= usage_system"),
+            leaf(
+                Type::Variable(String::from("usage_user")),
+                Type::Variable(String::from("usage_system")),
+                Op::LtEq,
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_times() {
+        let select = parse(r#"SELECT s WHERE a="1" FROM now - 1h TO -90s"#, now()).unwrap();
+        assert_eq!(select.start.unwrap().timestamp(), 100000 - 3600);
+        assert_eq!(select.end.unwrap().timestamp(), 100000 - 90);
+        let select = parse(r#"SELECT s WHERE a="1" FROM "2016-06-13T17:43:50Z""#, now()).unwrap();
+        assert_eq!(select.start.unwrap().timestamp(), 1465839830);
+        assert_eq!(select.end, None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            get_error(r#"SELECT s WHERE a="1" AND"#),
+            (
+                25,
+                String::from("expected a name or number, found end of query")
+            )
+        );
+        assert_eq!(
+            get_error(r#"SELECT s WHERE a > "1""#),
+            (
+                18,
+                String::from("labels can only be compared with =, !=, =~, !~ or ^=")
+            )
+        );
+        assert_eq!(
+            get_error(r#"SELECT s WHERE (a="1" OR b="2" FROM -1h"#),
+            (32, String::from("expected ')', found 'FROM'"))
+        );
+        assert_eq!(
+            get_error(r#"SELECT s WHERE a="1" FROM -1y"#),
+            (29, String::from("unknown duration unit y"))
+        );
+        assert_eq!(get_error(r#"SELECT s WHERE a=~"(""#).0, 19);
+        assert_eq!(get_error(r#"SELECT s WHERE a="1" b"#).0, 22);
+    }
+}
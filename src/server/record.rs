@@ -1,12 +1,53 @@
 extern crate bincode;
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::hash::{Hash, Hasher};
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+// CONSTANTS
+// Label key the record name is indexed and matched under.
+pub const NAME_LABEL: &str = "__name__";
+
+// Build a series key from its name, labels and sorted variable names. Each part is
+// escaped and delimited, so different series can't produce the same key.
+pub fn series_key(name: &str, labels: &HashMap<String, String>, variables: &[String]) -> String {
+    // Start with name.
+    let mut temp_key: String = escape_key(name);
+
+    // Sort labels and add to key.
+    let mut sorted_labels: Vec<_> = labels.iter().collect();
+    sorted_labels.sort_by_key(|x| x.0);
+    for (key, value) in sorted_labels.iter() {
+        temp_key.push(',');
+        temp_key.push_str(&escape_key(key));
+        temp_key.push('=');
+        temp_key.push_str(&escape_key(value));
+    }
+
+    // Add variable names.
+    temp_key.push(';');
+    let variables: Vec<String> = variables.iter().map(|x| escape_key(x)).collect();
+    temp_key.push_str(&variables.join(","));
+
+    // Return.
+    temp_key
+}
+
+// Escape the key delimiters, and the escape character itself, in a key part.
+fn escape_key(part: &str) -> String {
+    let mut escaped = String::with_capacity(part.len());
+    for c in part.chars() {
+        if matches!(c, '\\' | ',' | '=' | ';') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
 // Record struct.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Record {
@@ -34,26 +75,9 @@ impl Record {
 
     // Get key.
     pub fn get_key(&self) -> String {
-        // Start with name.
-        let mut temp_key: String = self.name.clone();
-
-        // Sort labels and add to key.
-        let mut sorted_labels: Vec<_> = self.labels.iter().collect();
-        sorted_labels.sort_by_key(|x| x.0);
-        for (key, value) in sorted_labels.iter() {
-            temp_key.push_str(key);
-            temp_key.push_str(value);
-        }
-
-        // Sort variables and add to key.
-        let mut sorted_variables: Vec<_> = self.get_variables().clone();
-        sorted_variables.sort();
-        for variable in sorted_variables.iter() {
-            temp_key.push_str(variable);
-        }
-
-        // Return.
-        temp_key
+        let mut variables = self.get_variable_keys();
+        variables.sort();
+        series_key(&self.name, &self.labels, &variables)
     }
 
     // Get name,
@@ -66,6 +90,14 @@ impl Record {
         self.labels.clone()
     }
 
+    // Get the value of a label; the name can be read as NAME_LABEL.
+    pub fn get_label(&self, key: &str) -> Option<String> {
+        match key {
+            NAME_LABEL => Some(self.name.clone()),
+            _ => self.labels.get(key).cloned(),
+        }
+    }
+
     // Get labels.
     pub fn get_labels(&self) -> Vec<String> {
         self.labels
@@ -176,6 +208,80 @@ impl Hash for Record {
     }
 }
 
+// SeriesResult Struct. The points of one series: its name, labels and variables once,
+// then each timestamp with its values, in the order of variables.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+pub struct SeriesResult {
+    pub name: String,
+    pub labels: HashMap<String, String>,
+    pub variables: Vec<String>,
+    pub points: Vec<(DateTime<Utc>, Vec<f64>)>,
+}
+impl SeriesResult {
+    // Constructor. Takes the series from one of its records, without its point.
+    pub fn new(record: &Record) -> Self {
+        let mut variables = record.get_variable_keys();
+        variables.sort();
+        SeriesResult {
+            name: record.get_name(),
+            labels: record.get_populated_labels(),
+            variables,
+            points: vec![],
+        }
+    }
+
+    // Get key, matching the key of the series' records.
+    pub fn get_key(&self) -> String {
+        series_key(&self.name, &self.labels, &self.variables)
+    }
+
+    // Append a record of this series. Records must come in timestamp order; a record
+    // at the same time as the last point is a duplicate and is skipped.
+    pub fn push(&mut self, record: &Record) {
+        let timestamp = record.get_timestamp();
+        if self.points.last().is_some_and(|x| x.0 == timestamp) {
+            return;
+        }
+        let values = self
+            .variables
+            .iter()
+            .map(|x| record.variables.get(x).cloned().unwrap_or(f64::NAN))
+            .collect();
+        self.points.push((timestamp, values));
+    }
+
+    // Group timestamp-ordered records by series.
+    pub fn from_records(records: Vec<Record>) -> Vec<SeriesResult> {
+        let mut series: BTreeMap<String, SeriesResult> = BTreeMap::new();
+        for record in records.iter() {
+            series
+                .entry(record.get_key())
+                .or_insert_with(|| SeriesResult::new(record))
+                .push(record);
+        }
+        series.into_values().collect()
+    }
+
+    // Combine results from several blocks, merging the points of each series. Where
+    // two results have a point at the same time, the earlier result's point is kept.
+    pub fn merge_all(results: Vec<SeriesResult>) -> Vec<SeriesResult> {
+        let mut series: BTreeMap<String, SeriesResult> = BTreeMap::new();
+        for result in results {
+            match series.get_mut(&result.get_key()) {
+                Some(existing) => {
+                    existing.points.extend(result.points);
+                    existing.points.sort_by_key(|x| x.0);
+                    existing.points.dedup_by_key(|x| x.0);
+                }
+                None => {
+                    series.insert(result.get_key(), result);
+                }
+            }
+        }
+        series.into_values().collect()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -216,4 +322,47 @@ mod test {
 
         assert_eq!(exp, d);
     }
+
+    #[test]
+    fn test_get_key() {
+        let make = |labels: &[(&str, &str)], variables: &[&str]| {
+            Record::new(
+                "cpu".to_string(),
+                labels
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+                variables.iter().map(|x| (x.to_string(), 1.0)).collect(),
+                Utc::now(),
+            )
+        };
+
+        // Same series, labels in any order.
+        assert_eq!(
+            make(&[("a", "1"), ("b", "2")], &["x", "y"]).get_key(),
+            make(&[("b", "2"), ("a", "1")], &["y", "x"]).get_key()
+        );
+
+        // Parts that concatenate the same must still differ.
+        assert_ne!(
+            make(&[], &["ab", "c"]).get_key(),
+            make(&[], &["a", "bc"]).get_key()
+        );
+        assert_ne!(
+            make(&[("ab", "c")], &[]).get_key(),
+            make(&[("a", "bc")], &[]).get_key()
+        );
+        assert_ne!(
+            make(&[("a", "b,c=d")], &[]).get_key(),
+            make(&[("a", "b"), ("c", "d")], &[]).get_key()
+        );
+        assert_ne!(
+            make(&[("a", "b;x")], &[]).get_key(),
+            make(&[("a", "b")], &["x"]).get_key()
+        );
+
+        // A series result has the key of its records.
+        let record = make(&[("a", "1"), ("b", "2")], &["y", "x"]);
+        assert_eq!(SeriesResult::new(&record).get_key(), record.get_key());
+    }
 }
@@ -0,0 +1,157 @@
+use crate::error::{Error, ErrorCode};
+use crate::server::execute::SelectResult;
+use bincode::{deserialize_from, serialize_into, ErrorKind};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+// CONSTANTS
+// Marks a handshake. Requests start with their length, which is never this.
+const HANDSHAKE: u64 = u64::MAX;
+
+// Format Enum. How a client wants responses encoded. Clients may send it (in bincode)
+// once, when they connect; those that don't get bincode.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+pub enum Format {
+    Bincode,
+    Json,
+}
+impl Format {
+    // Send the handshake asking for this format.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> bincode::Result<()> {
+        serialize_into(&mut *writer, &HANDSHAKE)?;
+        serialize_into(writer, self)
+    }
+
+    // Receive a client's handshake, if it sent one. If not, the bytes read are
+    // returned too, as they start the client's first request.
+    pub fn read_from<R: Read>(reader: &mut R) -> bincode::Result<(Self, Vec<u8>)> {
+        let mut prefix = [0; 8];
+        reader.read_exact(&mut prefix)?;
+        match u64::from_le_bytes(prefix) {
+            HANDSHAKE => Ok((deserialize_from(reader)?, vec![])),
+            _ => Ok((Format::Bincode, prefix.to_vec())),
+        }
+    }
+}
+
+// Response Enum. The server's reply to each request.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub enum Response {
+    // Results of a select or query.
+    Ok(SelectResult),
+    // How many records were written.
+    Written(usize),
+    Error { code: ErrorCode, message: String },
+    // A delete was applied.
+    Deleted,
+}
+impl Response {
+    // Constructor for an error response.
+    pub fn from_error(error: &Error) -> Self {
+        Response::Error {
+            code: error.code(),
+            message: error.to_string(),
+        }
+    }
+
+    // Send the response in a format. JSON is sent as a (bincode) length-prefixed
+    // string, so responses stay framed either way.
+    pub fn write_to<W: Write>(&self, writer: &mut W, format: Format) -> bincode::Result<()> {
+        match format {
+            Format::Bincode => serialize_into(writer, self),
+            Format::Json => {
+                let json = serde_json::to_string(self).map_err(to_bincode_error)?;
+                serialize_into(writer, &json)
+            }
+        }
+    }
+
+    // Receive a response sent in a format.
+    pub fn read_from<R: Read>(reader: &mut R, format: Format) -> bincode::Result<Self> {
+        match format {
+            Format::Bincode => deserialize_from(reader),
+            Format::Json => {
+                let json: String = deserialize_from(reader)?;
+                serde_json::from_str(&json).map_err(to_bincode_error)
+            }
+        }
+    }
+}
+
+// Report a JSON error as a bincode one, since bincode does the framing.
+fn to_bincode_error(error: serde_json::Error) -> bincode::Error {
+    Box::new(ErrorKind::Custom(error.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::record::Record;
+    use chrono::{DateTime, Utc};
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_round_trip() {
+        let mut variables = HashMap::new();
+        variables.insert("usage_user".to_string(), 58.0);
+        let timestamp = DateTime::parse_from_rfc3339("2016-06-13T17:43:50.1004002+00:00")
+            .unwrap()
+            .with_timezone(&Utc);
+        let record = Record::new("cpu".to_string(), HashMap::new(), variables, timestamp);
+        let responses = [
+            Response::Ok(SelectResult::Records(vec![record])),
+            Response::Written(3),
+            Response::Deleted,
+            Response::from_error(&Error::QueryParse(4, String::from("expected WHERE"))),
+        ];
+        for format in [Format::Bincode, Format::Json].iter() {
+            let mut buffer = vec![];
+            for response in responses.iter() {
+                response.write_to(&mut buffer, *format).unwrap();
+            }
+            let mut reader = Cursor::new(buffer);
+            for response in responses.iter() {
+                assert_eq!(
+                    &Response::read_from(&mut reader, *format).unwrap(),
+                    response
+                );
+            }
+        }
+        assert_eq!(
+            responses[3],
+            Response::Error {
+                code: ErrorCode::BadRequest,
+                message: String::from("query parse error at column 4: expected WHERE"),
+            }
+        );
+    }
+
+    #[test]
+    fn test_handshake() {
+        // A handshake picks the format.
+        let mut buffer = vec![];
+        Format::Json.write_to(&mut buffer).unwrap();
+        serialize_into(&mut buffer, "SELECT").unwrap();
+        let mut reader = Cursor::new(buffer);
+        let (format, prefix) = Format::read_from(&mut reader).unwrap();
+        assert_eq!(format, Format::Json);
+        assert!(prefix.is_empty());
+        assert_eq!(
+            deserialize_from::<_, String>(&mut reader).unwrap(),
+            "SELECT"
+        );
+
+        // Without one, the client gets bincode, and its first request is kept whole.
+        let mut buffer = vec![];
+        serialize_into(&mut buffer, "SELECT").unwrap();
+        let mut reader = Cursor::new(buffer);
+        let (format, prefix) = Format::read_from(&mut reader).unwrap();
+        assert_eq!(format, Format::Bincode);
+        let mut reader = Cursor::new(prefix).chain(reader);
+        assert_eq!(
+            deserialize_from::<_, String>(&mut reader).unwrap(),
+            "SELECT"
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use crate::config::Config;
+use crate::server::{
+    record::Record,
+    store::{replace_blocks, Block, BlockIndex, BlockView},
+};
+use chrono::{DateTime, Duration, Utc};
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, RwLock,
+    },
+};
+
+// CONSTANTS
+// How often expired data is looked for.
+const RETENTION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+// RetentionPolicy Struct. How long to keep data, globally and per metric name.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    default: Option<Duration>,
+    per_metric: HashMap<String, Duration>,
+}
+impl RetentionPolicy {
+    // Constructor using the retention and retention_by_metric settings, which the
+    // config has already checked are in range.
+    pub fn from_config(config: &Config) -> Self {
+        let to_duration = |secs: &u64| Duration::seconds(*secs as i64);
+        RetentionPolicy {
+            default: config.retention.as_ref().map(to_duration),
+            per_metric: config
+                .retention_by_metric
+                .iter()
+                .map(|(name, secs)| (name.clone(), to_duration(secs)))
+                .collect(),
+        }
+    }
+
+    // Whether any data ever expires.
+    pub fn is_enabled(&self) -> bool {
+        self.default.is_some() || !self.per_metric.is_empty()
+    }
+
+    // Get the time before which data for a metric has expired, if it ever does.
+    fn get_cutoff(&self, name: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.per_metric
+            .get(name)
+            .or(self.default.as_ref())
+            .map(|x| now - *x)
+    }
+
+    // Get the latest cutoff of any metric; data at or after it never needs checking.
+    fn get_latest_cutoff(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.default
+            .iter()
+            .chain(self.per_metric.values())
+            .min()
+            .map(|x| now - *x)
+    }
+}
+
+// Retention Enum. What to do with a flushed block.
+enum Retention {
+    Keep,
+    Drop,
+    Rewrite(Box<Block>),
+}
+
+// Periodically drop expired data. Runs until stop_rx is signalled or closed.
+pub fn db_retain(
+    shared_index: Arc<RwLock<BlockIndex>>,
+    policy: RetentionPolicy,
+    stop_rx: Receiver<()>,
+) {
+    if !policy.is_enabled() {
+        return;
+    }
+    while stop_rx.recv_timeout(RETENTION_INTERVAL) == Err(RecvTimeoutError::Timeout) {
+        enforce(&shared_index, &policy, Utc::now());
+    }
+}
+
+// Drop expired blocks, and rewrite partially expired ones without their expired points.
+pub fn enforce(
+    shared_index: &Arc<RwLock<BlockIndex>>,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+) {
+    let latest_cutoff = match policy.get_latest_cutoff(now) {
+        Some(cutoff) => cutoff,
+        None => return,
+    };
+    let packed_blocks = shared_index
+        .read()
+        .expect("RwLock poisoned")
+        .get_packed_blocks();
+
+    for packed_block in packed_blocks {
+        // Blocks that started after every cutoff have nothing to expire.
+        if packed_block.get_start_timestamp().unwrap() >= latest_cutoff {
+            continue;
+        }
+
+        // Without per-metric rules, the block's time range is enough to decide.
+        let retention = if policy.per_metric.is_empty()
+            && packed_block.get_end_timestamp().unwrap() < latest_cutoff
+        {
+            Retention::Drop
+        } else {
+            match packed_block.unpack() {
+                Ok(block) => retain(&block, policy, now),
+                Err(e) => {
+                    println!("Not expiring {}: {}", packed_block.get_filepath(), e);
+                    continue;
+                }
+            }
+        };
+
+        // Swap in the result, unless the block has gone away (e.g. been compacted).
+        let replaced = match retention {
+            Retention::Keep => continue,
+            Retention::Drop => replace_blocks(shared_index, &[packed_block], None, &[]),
+            Retention::Rewrite(mut block) => {
+                replace_blocks(shared_index, &[packed_block], Some(&mut block), &[])
+            }
+        };
+        match replaced {
+            Ok(true) => println!("Expired data from a block."),
+            Ok(false) => (),
+            Err(e) => println!("Failed to expire data from a block: {}", e),
+        }
+    }
+}
+
+// Decide what to do with a block, given which of its points have expired.
+fn retain(block: &Block, policy: &RetentionPolicy, now: DateTime<Utc>) -> Retention {
+    let mut kept: Vec<Record> = vec![];
+    let mut expired = false;
+    for id in 0..block.get_storage().len() {
+        for record in block.get_series_records(id as u32) {
+            match policy.get_cutoff(&record.get_name(), now) {
+                Some(cutoff) if record.get_timestamp() < cutoff => expired = true,
+                _ => kept.push(record),
+            }
+        }
+    }
+    if !expired {
+        return Retention::Keep;
+    }
+    if kept.is_empty() {
+        return Retention::Drop;
+    }
+
+    // Each series' points stay in order, as they are inserted series by series.
+    let mut rewritten = Block::new();
+    rewritten.set_sequence(block.get_sequence());
+    for record in kept {
+        if let Err(e) = rewritten.insert(record) {
+            println!("Dropping record while expiring data: {}", e);
+        }
+    }
+    Retention::Rewrite(Box::new(rewritten))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::test_util::make_metric_record;
+    use chrono::NaiveDateTime;
+
+    fn make_policy(default: Option<i64>, per_metric: &[(&str, i64)]) -> RetentionPolicy {
+        RetentionPolicy {
+            default: default.map(Duration::seconds),
+            per_metric: per_metric
+                .iter()
+                .map(|(k, v)| (k.to_string(), Duration::seconds(*v)))
+                .collect(),
+        }
+    }
+
+    fn get_timestamps(block: &Block) -> Vec<i64> {
+        let mut timestamps: Vec<i64> = (0..block.get_storage().len())
+            .flat_map(|id| block.get_series_records(id as u32))
+            .map(|r| r.get_timestamp().timestamp())
+            .collect();
+        timestamps.sort();
+        timestamps
+    }
+
+    #[test]
+    fn test_retain() {
+        let now = DateTime::from_utc(NaiveDateTime::from_timestamp(1000, 0), Utc);
+        let mut block = Block::new();
+        for secs in [100, 200, 900].iter() {
+            block
+                .insert(make_metric_record("cpu", "host_0", 1.0, *secs))
+                .unwrap();
+            block
+                .insert(make_metric_record("mem", "host_0", 1.0, *secs + 1))
+                .unwrap();
+        }
+
+        match retain(&block, &make_policy(Some(5000), &[]), now) {
+            Retention::Keep => (),
+            _ => panic!("unexpired block was changed"),
+        }
+        match retain(&block, &make_policy(Some(10), &[]), now) {
+            Retention::Drop => (),
+            _ => panic!("expired block was not dropped"),
+        }
+
+        // Points older than the cutoff are dropped, each metric with its own cutoff.
+        match retain(&block, &make_policy(Some(850), &[("mem", 500)]), now) {
+            Retention::Rewrite(rewritten) => {
+                assert_eq!(get_timestamps(&rewritten), vec![200, 900, 901])
+            }
+            _ => panic!("partially expired block was not rewritten"),
+        }
+        match retain(&block, &make_policy(None, &[("cpu", 500)]), now) {
+            Retention::Rewrite(rewritten) => {
+                assert_eq!(get_timestamps(&rewritten), vec![101, 201, 900, 901])
+            }
+            _ => panic!("partially expired block was not rewritten"),
+        }
+    }
+
+    #[test]
+    fn test_cutoffs() {
+        let now = DateTime::from_utc(NaiveDateTime::from_timestamp(1000, 0), Utc);
+        let policy = make_policy(Some(100), &[("cpu", 300), ("mem", 50)]);
+        assert_eq!(policy.get_cutoff("cpu", now).unwrap().timestamp(), 700);
+        assert_eq!(policy.get_cutoff("disk", now).unwrap().timestamp(), 900);
+        assert_eq!(policy.get_latest_cutoff(now).unwrap().timestamp(), 950);
+
+        let policy = make_policy(None, &[("cpu", 300)]);
+        assert_eq!(policy.get_cutoff("disk", now), None);
+        assert!(!make_policy(None, &[]).is_enabled());
+    }
+}
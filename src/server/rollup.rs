@@ -0,0 +1,195 @@
+use crate::error::Error;
+use crate::server::{
+    operators::{
+        aggregate::{millis_to_datetime, summarize},
+        Select,
+    },
+    record::Record,
+    store::{write_block_at, Block, BlockView},
+};
+use std::{fs, io::ErrorKind, path::Path};
+
+// Tier Struct. A rollup granularity. Each flushed block gets a rollup file per tier,
+// holding the summary (min/max/sum/count) of every series variable per bucket.
+pub struct Tier {
+    pub name: &'static str,
+    pub width: i64,
+}
+
+// CONSTANTS
+// Rollup tiers, finest first. Widths are in millis.
+pub const TIERS: [Tier; 2] = [
+    Tier {
+        name: "1m",
+        width: 60 * 1000,
+    },
+    Tier {
+        name: "1h",
+        width: 60 * 60 * 1000,
+    },
+];
+
+impl Tier {
+    // Get the path of the rollup of a block file: {DATAROOT}/rollups/{tier}/{file}.
+    pub fn get_path(&self, filepath: &str) -> String {
+        let path = Path::new(filepath);
+        let dataroot = path.parent().and_then(|x| x.parent()).unwrap();
+        let rollup = dataroot
+            .join("rollups")
+            .join(self.name)
+            .join(path.file_name().unwrap());
+        String::from(rollup.to_str().unwrap())
+    }
+}
+
+// Write the rollups of a block that was flushed to filepath.
+pub fn write_rollups(block: &Block, filepath: &str) -> Result<(), Error> {
+    let mut records: Vec<Record> = vec![];
+    for id in 0..block.get_storage().len() {
+        records.extend(block.get_series_records(id as u32));
+    }
+    for tier in TIERS.iter() {
+        // Summaries come out ordered by bucket, so each series stays in order.
+        let mut rollup = Block::new();
+        for record in summarize(records.clone(), tier.width) {
+            rollup.insert(record).unwrap();
+        }
+        let rollup_filename = tier.get_path(filepath);
+        let rollup_dir = Path::new(&rollup_filename).parent().unwrap();
+        fs::create_dir_all(rollup_dir)?;
+        write_block_at(&rollup_filename, &rollup.to_bytes())?;
+    }
+    Ok(())
+}
+
+// Remove the rollups of a block file, if it has any.
+pub fn remove_rollups(filepath: &str) -> Result<(), Error> {
+    for tier in TIERS.iter() {
+        match fs::remove_file(tier.get_path(filepath)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(Error::Io(e)),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// RollupPlan Struct. Splits a select into the whole buckets of a tier, which can be
+// answered from rollups, and the partial buckets at either edge, which can't.
+pub struct RollupPlan {
+    pub tier: &'static Tier,
+    pub rollup: Select,
+    pub edges: Vec<Select>,
+}
+
+// Plan to answer a select from the coarsest usable tier, if there is one. That needs
+// a bounded time range covering at least one whole bucket, a label-only predicate,
+// and an aggregation that can be computed from summaries.
+pub fn plan(select: &Select) -> Option<RollupPlan> {
+    let aggregation = select.aggregation.as_ref()?;
+    let start = select.start?.timestamp_millis();
+    let end = select.end?.timestamp_millis();
+    if !select.is_label_only() {
+        return None;
+    }
+    for tier in TIERS.iter().rev() {
+        if !aggregation.can_use_summaries(tier.width) {
+            continue;
+        }
+
+        // Round the (inclusive) range inwards to whole buckets.
+        let rollup_start = start + (tier.width - start.rem_euclid(tier.width)) % tier.width;
+        let rollup_end = end + 1 - (end + 1).rem_euclid(tier.width);
+        if rollup_start >= rollup_end {
+            continue;
+        }
+
+        // The edges before and after are read raw.
+        let make_select = |start: i64, end: i64| Select {
+            start: Some(millis_to_datetime(start)),
+            end: Some(millis_to_datetime(end)),
+            ..select.clone()
+        };
+        let mut edges = vec![];
+        if start < rollup_start {
+            edges.push(make_select(start, rollup_start - 1));
+        }
+        if rollup_end <= end {
+            edges.push(make_select(rollup_end, end));
+        }
+        return Some(RollupPlan {
+            tier,
+            rollup: make_select(rollup_start, rollup_end - 1),
+            edges,
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_select(start: i64, end: i64, group_by_time: i64) -> Select {
+        let data = format!(
+            r#"{{
+                "name": "s",
+                "predicate": {{
+                    "name": "p",
+                    "condition": {{
+                        "Leaf": {{ "lhs": {{"LabelKey": "hostname"}}, "rhs": {{"LabelValue": "host_0"}}, "op": "Eq" }}
+                    }}
+                }},
+                "aggregation": {{ "group_by_time": {}, "functions": {{ "usage_user": "Mean" }} }}
+            }}"#,
+            group_by_time
+        );
+        let mut select: Select = serde_json::from_str(&data).unwrap();
+        select.start = Some(millis_to_datetime(start));
+        select.end = Some(millis_to_datetime(end));
+        select
+    }
+
+    fn get_range(select: &Select) -> (i64, i64) {
+        (
+            select.start.unwrap().timestamp_millis(),
+            select.end.unwrap().timestamp_millis(),
+        )
+    }
+
+    #[test]
+    fn test_plan() {
+        let hour = 60 * 60 * 1000;
+
+        // Unaligned bounds are read raw on either side of the whole hours.
+        let p = plan(&make_select(hour / 2, 5 * hour + 10, hour)).unwrap();
+        assert_eq!(p.tier.name, "1h");
+        assert_eq!(get_range(&p.rollup), (hour, 5 * hour - 1));
+        let edges: Vec<_> = p.edges.iter().map(get_range).collect();
+        assert_eq!(edges, vec![(hour / 2, hour - 1), (5 * hour, 5 * hour + 10)]);
+
+        // Aligned bounds need no edges.
+        let p = plan(&make_select(0, 2 * hour - 1, hour)).unwrap();
+        assert_eq!(get_range(&p.rollup), (0, 2 * hour - 1));
+        assert!(p.edges.is_empty());
+
+        // Fall back to a finer tier when buckets don't nest, or the range is too short.
+        assert_eq!(plan(&make_select(0, hour, 60_000)).unwrap().tier.name, "1m");
+        assert_eq!(
+            plan(&make_select(hour / 2, hour + 10, hour))
+                .unwrap()
+                .tier
+                .name,
+            "1m"
+        );
+        assert!(plan(&make_select(0, 30_000, 60_000)).is_none());
+        assert!(plan(&make_select(0, hour, 1000)).is_none());
+    }
+
+    #[test]
+    fn test_get_path() {
+        assert_eq!(
+            TIERS[1].get_path("./data/blocks/abc.rdb"),
+            "./data/rollups/1h/abc.rdb"
+        );
+    }
+}
@@ -1,46 +1,79 @@
+use crate::config::Config;
+use crate::error::Error;
 use crate::server::{
-    execute::{execute, SelectRequest},
-    record::Record,
+    execute::{execute, SelectRequest, WriteRequest},
+    operators::Op,
+    promql::PromQuery,
+    query,
+    response::{Format, Response},
+    signal,
     store::db_open,
 };
-use bincode::{deserialize_from, serialize_into};
+use bincode::deserialize_from;
+use chrono::Utc;
 use std::{
-    io,
+    io::{self, Cursor, Read},
     net::{Shutdown, TcpListener, TcpStream},
     sync::mpsc::{channel, Sender},
-    thread,
+    thread::{self, JoinHandle},
+    time::Duration,
 };
 
-// Process a vector of records into a String.
-fn postprocess(result: Vec<Record>) -> String {
-    format!("{:?}", result)
+// CONSTANTS
+// How often the listener checks for a shutdown signal while waiting for connections.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Parse input as a JSON operation, a text query if it starts with SELECT, or else an
+// instant PromQL query.
+fn parse_input(data: &str) -> Result<Op, Error> {
+    let data = data.trim();
+    if data.starts_with('{') {
+        return serde_json::from_str(data)
+            .map_err(|e| Error::QueryParse(e.column(), format!("invalid JSON operation: {}", e)));
+    }
+    let is_select = data
+        .split_whitespace()
+        .next()
+        .is_some_and(|x| x.eq_ignore_ascii_case("SELECT"));
+    if !is_select {
+        return Ok(Op::PromQL(PromQuery::new(String::from(data))));
+    }
+    query::parse(data, Utc::now()).map(Op::Select)
 }
 
 // Takes a new client connection and executes input.
 fn handle_tcp_connection(
     mut stream: TcpStream,
     read_tx: Sender<SelectRequest>,
-    write_tx: Sender<Record>,
+    write_tx: Sender<WriteRequest>,
 ) {
-    let addr = stream.peer_addr().unwrap();
-    while match deserialize_from::<_, String>(&mut stream) {
-        Ok(data) => {
-            if let Ok(op) = serde_json::from_str(&data) {
-                let response = match execute(op, &read_tx, &write_tx) {
-                    Ok(Some(result)) => postprocess(result),
-                    Ok(None) => String::from("Operation completed"),
-                    Err(error) => format!("Error: {}", error),
-                };
-                serialize_into(&mut stream, &response).unwrap();
-                true
-            } else {
-                let response = format!("Unrecognized input: {}", &data);
-                serialize_into(&mut stream, &response).unwrap();
-                true
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr.to_string(),
+        Err(_) => String::from("unknown peer"),
+    };
+
+    // Clients may say how they want responses encoded before sending any requests.
+    // Requests are read from a clone of the stream, starting with any bytes read while
+    // looking for the handshake.
+    let handshake = stream
+        .try_clone()
+        .map_err(bincode::Error::from)
+        .and_then(|mut reader| {
+            let (format, prefix) = Format::read_from(&mut reader)?;
+            Ok((format, Cursor::new(prefix).chain(reader)))
+        });
+    if let Ok((format, mut reader)) = handshake {
+        while let Ok(data) = deserialize_from::<_, String>(&mut reader) {
+            let response = match parse_input(&data).and_then(|op| execute(op, &read_tx, &write_tx))
+            {
+                Ok(response) => response,
+                Err(error) => Response::from_error(&error),
+            };
+            if response.write_to(&mut stream, format).is_err() {
+                break;
             }
         }
-        Err(_) => false,
-    } {}
+    }
 
     // Shut down the connection.
     println!("Terminating connection with {}", addr);
@@ -48,29 +81,85 @@ fn handle_tcp_connection(
         Ok(_) => println!("Connection terminated"),
         Err(err) => match err.kind() {
             io::ErrorKind::NotConnected => println!("Connection already terminated"),
-            _ => panic!("Shutdown problem"),
+            _ => println!("Shutdown problem: {}", err),
         },
     }
 }
 
-// Opens the server.
-pub fn server() {
+// Opens the server. If the database can't be opened, requests get errors saying so.
+// Runs until SIGINT or SIGTERM, then stops taking connections, lets requests in flight
+// finish, and waits for the database to flush before returning.
+pub fn server(config: Config) -> Result<(), Error> {
+    // Start listening for new connections, so a taken address fails fast.
+    let listener = TcpListener::bind(&config.listen_addr)?;
+    listener.set_nonblocking(true)?;
+    signal::install()?;
+    println!("Listening on {}", config.listen_addr);
+
     // Open the db and create read/write channels
     let (read_tx, read_rx) = channel();
     let (write_tx, write_rx) = channel();
-    thread::spawn(move || db_open(read_rx, write_rx));
+    let db_thr = thread::spawn(move || {
+        let result = db_open(&config, read_rx, write_rx);
+        if let Err(e) = &result {
+            println!("Database failed: {}", e);
+        }
+        result
+    });
 
-    // Start listening for new connections.
-    let listener = TcpListener::bind("127.0.0.1:12345").unwrap();
-    for stream in listener.incoming() {
+    let mut connections: Vec<(TcpStream, JoinHandle<()>)> = vec![];
+    while !signal::is_shutting_down() {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                println!("failed: {}", e);
+                continue;
+            }
+        };
+
+        // Keep a handle on the connection, so it can be closed on shutdown.
+        let handle = match stream
+            .set_nonblocking(false)
+            .and_then(|_| stream.try_clone())
+        {
+            Ok(handle) => handle,
+            Err(e) => {
+                println!("failed: {}", e);
+                continue;
+            }
+        };
         let write_tx_clone = write_tx.clone();
         let read_tx_clone = read_tx.clone();
+        let connection_thr =
+            thread::spawn(move || handle_tcp_connection(stream, read_tx_clone, write_tx_clone));
+        connections.retain(|x| !x.1.is_finished());
+        connections.push((handle, connection_thr));
+    }
 
-        match stream {
-            Err(e) => println!("failed: {}", e),
-            Ok(stream) => {
-                thread::spawn(move || handle_tcp_connection(stream, read_tx_clone, write_tx_clone));
-            }
+    // Stop taking connections, and stop reading requests from open ones. Responses to
+    // requests already read are still sent.
+    println!("Shutting down.");
+    drop(listener);
+    for (stream, connection_thr) in connections {
+        let _ = stream.shutdown(Shutdown::Read);
+        if connection_thr.join().is_err() {
+            println!("Connection handler panicked.");
         }
     }
+
+    // With every sender gone, the database drains its channels, flushes the in-memory
+    // block and returns. If it couldn't flush, the error is passed on, so the process
+    // exits non-zero.
+    drop(read_tx);
+    drop(write_tx);
+    match db_thr.join() {
+        Ok(result) => result?,
+        Err(_) => println!("Database panicked."),
+    }
+    println!("Shut down cleanly.");
+    Ok(())
 }
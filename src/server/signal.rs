@@ -0,0 +1,32 @@
+use crate::error::Error;
+use std::{
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// Set once SIGINT or SIGTERM is received.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+// Signal handler. Only flips the flag, which is all that is safe to do here. A second
+// signal exits straight away, leaving the write-ahead log to recover unflushed writes.
+extern "C" fn handle_signal(_: libc::c_int) {
+    if SHUTDOWN.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(1) };
+    }
+}
+
+// Catch SIGINT and SIGTERM, so the server can shut down cleanly.
+pub fn install() -> Result<(), Error> {
+    let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    for signal in [libc::SIGINT, libc::SIGTERM].iter() {
+        if unsafe { libc::signal(*signal, handler) } == libc::SIG_ERR {
+            return Err(Error::Io(io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+// Check whether the server has been asked to shut down.
+pub fn is_shutting_down() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
@@ -1,44 +1,185 @@
 extern crate bincode;
-use crate::server::{execute::SelectRequest, operators::process::dnf, record::Record};
-use chrono::{DateTime, NaiveDateTime, Utc};
+use crate::config::Config;
+use crate::error::Error;
+use crate::server::{
+    block_file::{
+        decode_timestamp, read_entry, read_offset_range, write_block_file, write_entries,
+        write_offsets, BlockFileReader, Section,
+    },
+    chunk::{ChunkDecoder, ChunkEncoder},
+    compact::db_compact,
+    execute::{Mutation, SelectRequest, SelectResult, WriteRequest},
+    operators::{
+        aggregate::summarize,
+        process::dnf,
+        select::{ResultSet, TimeRange},
+        Delete, Select,
+    },
+    record::{series_key, Record, SeriesResult, NAME_LABEL},
+    retention::{db_retain, RetentionPolicy},
+    rollup::{self, remove_rollups, write_rollups, Tier},
+    signal,
+    wal::{self, Wal},
+};
+use chrono::{DateTime, Utc};
 use croaring::bitmap::Bitmap;
-use dotenv;
-use fst::{IntoStreamer, Map, MapBuilder, Streamer};
+use fst::{
+    automaton::{Automaton, Str},
+    IntoStreamer, Map, MapBuilder, Streamer,
+};
+use memmap2::Mmap;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use serde_json::{Result, Value};
+use serde_json::Value;
 use std::{
-    collections::{BTreeMap, HashMap},
-    convert::TryInto,
+    collections::{BTreeMap, HashMap, HashSet},
     fs::{self, File},
-    io::Read,
-    mem::size_of,
-    str,
-    sync::{mpsc::Receiver, Arc, RwLock, RwLockWriteGuard},
+    io::{ErrorKind, Read, Write},
+    mem,
+    ops::Range,
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, RwLock, RwLockWriteGuard,
+    },
     thread,
+    time::{Duration, Instant},
 };
 use uuid::Uuid;
 
 // CONSTANTS
-// TODO: Put these in their own file
-const HEADER_SIZE: usize = 7;
-const FLUSH_FREQUENCY: u32 = 50000;
+// How often the writer checks whether the in-memory block is due a flush.
+const FLUSH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// How long the flusher waits before retrying a frozen block that failed to write.
+const FLUSH_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+// How many times a frozen block is retried once shutting down, before giving up on it.
+const SHUTDOWN_FLUSH_RETRIES: usize = 3;
+// Suffix of the temp files written before being renamed into place.
+const TEMP_SUFFIX: &str = ".tmp";
 
 // TODO: Break this file up.
 
+// HeadState Struct. The writes the in-memory block has taken since it was opened.
+pub struct HeadState {
+    pub writes: u64,
+    pub opened: Instant,
+    pub last_write: Instant,
+}
+impl HeadState {
+    // Constructor.
+    pub fn new() -> Self {
+        HeadState {
+            writes: 0,
+            opened: Instant::now(),
+            last_write: Instant::now(),
+        }
+    }
+
+    // Count a write to the block; the first one opens it.
+    pub fn add_write(&mut self) {
+        let now = Instant::now();
+        if self.writes == 0 {
+            self.opened = now;
+        }
+        self.writes += 1;
+        self.last_write = now;
+    }
+}
+
+// FlushJob Struct. A frozen block waiting to be written to filepath, and the sealed WAL
+// segments that back it until then.
+pub struct FlushJob {
+    pub filepath: String,
+    pub block: Arc<Block>,
+    pub segments: Vec<String>,
+}
+
+// FlushPolicy Struct. When the in-memory block is flushed: after a number of writes,
+// once it has grown too big, too old, too long a span or idle for too long, or once
+// its time window is over. Blocks are aligned to fixed windows of wall-clock time.
+pub struct FlushPolicy {
+    pub count: u64,
+    pub bytes: Option<u64>,
+    pub age_secs: Option<u64>,
+    pub span_secs: Option<u64>,
+    pub idle_secs: Option<u64>,
+    pub window_millis: i64,
+}
+impl FlushPolicy {
+    // Constructor from the flush settings of a config.
+    pub fn from_config(config: &Config) -> Self {
+        FlushPolicy {
+            count: config.flush_count,
+            bytes: config.flush_bytes,
+            age_secs: config.flush_age_secs,
+            span_secs: config.flush_span_secs,
+            idle_secs: config.flush_idle_secs,
+            window_millis: config.get_window_millis(),
+        }
+    }
+
+    // Get the window a time falls in.
+    pub fn get_window(&self, timestamp: DateTime<Utc>) -> i64 {
+        timestamp.timestamp_millis().div_euclid(self.window_millis)
+    }
+
+    // Returns true if the block should be cut before a point at timestamp goes in,
+    // because the point is from a later window. Late points from earlier windows
+    // join the block, and compaction merges them with their window's block.
+    pub fn is_cut_by(&self, block: &Block, timestamp: DateTime<Utc>) -> bool {
+        block
+            .end_timestamp
+            .is_some_and(|end| self.get_window(timestamp) > self.get_window(end))
+    }
+
+    // Returns true if the block should be flushed now. Its window is only over once
+    // the wall clock is past it and it has gone a check interval without writes, so
+    // backfills of old data aren't cut into tiny blocks.
+    pub fn is_due(&self, block: &Block, head: &HeadState, now: DateTime<Utc>) -> bool {
+        let (start, end) = match (block.start_timestamp, block.end_timestamp) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return false,
+        };
+        let span_secs = (end - start).num_seconds() as u64;
+        let idle_secs = head.last_write.elapsed().as_secs();
+        head.writes >= self.count
+            || self.bytes.is_some_and(|x| block.get_size() as u64 >= x)
+            || self
+                .age_secs
+                .is_some_and(|x| head.opened.elapsed().as_secs() >= x)
+            || self.span_secs.is_some_and(|x| span_secs >= x)
+            || self.idle_secs.is_some_and(|x| idle_secs >= x)
+            || (self.get_window(now) > self.get_window(end)
+                && head.last_write.elapsed() >= FLUSH_CHECK_INTERVAL)
+    }
+}
+
 // BlockIndex Struct.
 pub struct BlockIndex {
     index: BTreeMap<i64, Vec<String>>, // Map from start_timestamp (millis) to filename
+    ends: HashMap<String, i64>,        // Map from filename to end_timestamp (millis), once known
+    tombstones: Vec<Tombstone>,
+    dataroot: String,                  // Folder the index and blocks are kept in
+    frozen: Vec<(String, Arc<Block>)>, // Blocks being flushed, and the files they go to
+    sequence: u64,                     // Last flush order handed out to a block
 }
 impl BlockIndex {
     // Constructor.
-    pub fn new() -> Self {
+    pub fn new(dataroot: &str) -> Self {
         let index = BTreeMap::new();
-        BlockIndex { index }
+        BlockIndex {
+            index,
+            ends: HashMap::new(),
+            tombstones: vec![],
+            dataroot: String::from(dataroot),
+            frozen: vec![],
+            sequence: 0,
+        }
     }
 
-    // Insert into index.
-    pub fn insert(&mut self, key: i64, filepath: String) {
+    // Insert into index, given the block's start and end timestamps (millis).
+    pub fn insert(&mut self, key: i64, end: i64, filepath: String) {
+        self.ends.insert(filepath.clone(), end);
         if self.index.contains_key(&key) {
             self.index.get_mut(&key).unwrap().push(filepath);
         } else {
@@ -46,71 +187,241 @@ impl BlockIndex {
         }
     }
 
-    // Constructor using path to data folder (should be specified in .env).
-    pub fn from_disk(path: String) -> Self {
-        let file = File::open(path);
-        if let Ok(mut f) = file {
-            let mut buffer = vec![];
-            f.read_to_end(&mut buffer)
-                .expect("ERROR: issue reading index from disk.");
-            let index = bincode::deserialize::<BTreeMap<i64, Vec<String>>>(&buffer).unwrap();
-            BlockIndex { index }
-        } else {
-            BlockIndex::new()
+    // Constructor using path to data folder. Tombstones are kept next to the index.
+    // Missing files are treated as empty. Blocks' end timestamps aren't stored, so
+    // they are only known once reconciled.
+    pub fn from_disk(dataroot: &str) -> Result<Self, Error> {
+        let path = Path::new(dataroot).join("index.rdb");
+        let tombstones_path = Path::new(dataroot).join("tombstones.rdb");
+        let tombstones = match fs::read(tombstones_path) {
+            Ok(buffer) => bincode::deserialize::<Vec<Tombstone>>(&buffer)
+                .map_err(|e| Error::Decode(format!("bad tombstones: {}", e)))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(Error::Io(e)),
+        };
+        let index = match File::open(path) {
+            Ok(mut f) => {
+                let mut buffer = vec![];
+                f.read_to_end(&mut buffer)?;
+                bincode::deserialize::<BTreeMap<i64, Vec<String>>>(&buffer)
+                    .map_err(|e| Error::Decode(format!("bad index: {}", e)))?
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        Ok(BlockIndex {
+            index,
+            ends: HashMap::new(),
+            tombstones,
+            dataroot: String::from(dataroot),
+            frozen: vec![],
+            sequence: 0,
+        })
+    }
+
+    // Check the index against the block dir on startup, reporting anything that doesn't
+    // match. Indexed blocks that are missing or unreadable (e.g. truncated) are dropped
+    // from the index. Block files the index doesn't reference are left over from a flush
+    // or compaction cut short, so their points are still in the write-ahead log or the
+    // blocks they were merged from; rather than being adopted, they are moved to the
+    // orphaned dir. Temp files from interrupted writes are removed. New blocks are
+    // flushed after every indexed one.
+    pub fn reconcile(&mut self) -> Result<(), Error> {
+        let blocks_dir = format!("{}/blocks", self.dataroot);
+        fs::create_dir_all(&blocks_dir)?;
+        remove_temp_files(&self.dataroot)?;
+        remove_temp_files(&blocks_dir)?;
+        for tier in rollup::TIERS.iter() {
+            remove_temp_files(&format!("{}/rollups/{}", self.dataroot, tier.name))?;
         }
+
+        // Find indexed blocks that can't be read.
+        let mut unreadable = vec![];
+        for (key, filepaths) in self.index.iter() {
+            for filepath in filepaths.iter() {
+                match PackedBlock::from_filepath(filepath.clone()) {
+                    Ok(packed_block) => {
+                        let end = packed_block.get_end_timestamp().unwrap();
+                        self.ends.insert(filepath.clone(), end.timestamp_millis());
+                        self.sequence = self.sequence.max(packed_block.get_sequence());
+                    }
+                    Err(e) => {
+                        println!("Dropping unreadable block {}: {}", filepath, e);
+                        unreadable.push((*key, filepath.clone()));
+                    }
+                }
+            }
+        }
+        for (key, filepath) in unreadable.iter() {
+            self.remove(*key, filepath);
+        }
+
+        // Set aside every block file that isn't (or is no longer) indexed. Files are
+        // matched by name, so a data dir given by a different path still lines up.
+        let indexed: HashSet<String> = self.index.values().flatten().cloned().collect();
+        let indexed_names: HashSet<String> = indexed.iter().map(|x| get_filename(x)).collect();
+        let mut orphans = vec![];
+        for entry in fs::read_dir(&blocks_dir)? {
+            let filepath = entry?.path().to_string_lossy().into_owned();
+            if !indexed_names.contains(&get_filename(&filepath)) {
+                orphans.push(filepath);
+            }
+        }
+        let orphaned_dir = format!("{}/orphaned", self.dataroot);
+        for filepath in orphans.iter() {
+            println!("Moving orphaned block {} to {}", filepath, orphaned_dir);
+            fs::create_dir_all(&orphaned_dir)?;
+            fs::rename(
+                filepath,
+                Path::new(&orphaned_dir).join(get_filename(filepath)),
+            )?;
+            remove_rollups(filepath)?;
+        }
+
+        // Tombstones against blocks that were dropped, or never installed, go too.
+        let stale: Vec<String> = self
+            .get_tombstoned_filepaths()
+            .into_iter()
+            .filter(|x| !indexed.contains(x))
+            .collect();
+        self.retarget_tombstones(&stale, None, &[]);
+        if !unreadable.is_empty() || !stale.is_empty() {
+            self.persist()?;
+        }
+        if !orphans.is_empty() {
+            sync_dir(&blocks_dir)?;
+        }
+        Ok(())
+    }
+
+    // Remove a block from the index. Returns whether it was there.
+    pub fn remove(&mut self, key: i64, filepath: &str) -> bool {
+        let removed = match self.index.get_mut(&key) {
+            Some(v) => {
+                let len = v.len();
+                v.retain(|x| x != filepath);
+                v.len() < len
+            }
+            None => false,
+        };
+        if self.index.get(&key).is_some_and(|v| v.is_empty()) {
+            self.index.remove(&key);
+        }
+        if removed {
+            self.ends.remove(filepath);
+        }
+        removed
     }
 
-    // Populate using elements in data folder (should not be used).
-    pub fn populate_manually(&mut self) {
-        // For each file in the dir...
-        let filepath = format!("{}/blocks", dotenv::var("DATAROOT").unwrap());
-        fs::create_dir_all(&filepath).expect("ERROR: issue creating block dir.");
-        let dir = fs::read_dir(filepath).unwrap();
-        for f in dir {
-            // Get the filename.
-            let filepath_pathbuf = f.unwrap().path();
-            let filepath_path = filepath_pathbuf.as_path();
-            let filepath = String::from(filepath_path.to_str().unwrap());
+    // Rewrite index (and tombstones) to disk.
+    pub fn persist(&self) -> Result<(), Error> {
+        let index_filename = format!("{}/index.rdb", self.dataroot);
+        write_atomic(&index_filename, &bincode::serialize(&self.index)?)?;
+        let tombstones_filename = format!("{}/tombstones.rdb", self.dataroot);
+        write_atomic(&tombstones_filename, &bincode::serialize(&self.tombstones)?)?;
+        Ok(())
+    }
 
-            // Unpack the given file.
-            let packed_block = PackedBlock::from_filepath(filepath.clone());
-            let key = packed_block.start_timestamp.unwrap().timestamp_millis();
-            self.insert(key, filepath);
+    // Record a delete against every flushed (or frozen) block that may hold matching
+    // points, returning its id if one was needed. If it can't be persisted, it is
+    // dropped again.
+    pub fn add_tombstone(&mut self, delete: Delete) -> Result<Option<u64>, Error> {
+        let select = delete.to_select();
+        let mut filepaths: Vec<String> = self
+            .get_packed_blocks()
+            .iter()
+            .filter(|x| select.may_match(x))
+            .map(|x| x.get_filepath())
+            .collect();
+        let time_range = select.get_time_range();
+        for (filepath, block) in self.frozen.iter() {
+            if let (Some(start), Some(end)) = (block.start_timestamp, block.end_timestamp) {
+                if time_range.overlaps(start, end) {
+                    filepaths.push(filepath.clone());
+                }
+            }
+        }
+        if filepaths.is_empty() {
+            return Ok(None);
+        }
+        let id = self.tombstones.iter().map(|x| x.id + 1).max().unwrap_or(0);
+        self.tombstones.push(Tombstone {
+            id,
+            delete,
+            filepaths,
+        });
+        let persisted = self.persist();
+        if persisted.is_err() {
+            self.tombstones.pop();
         }
+        persisted.map(|_| Some(id))
     }
 
-    // Add a block to the index, write new index to disk.
-    pub fn update(&mut self, block: &mut RwLockWriteGuard<Block>) {
-        // Get block bytes.
-        let block_bytes = block.to_bytes();
+    // Drop a tombstone that was added but whose delete couldn't be logged.
+    pub fn remove_tombstone(&mut self, id: u64) -> Result<(), Error> {
+        self.tombstones.retain(|x| x.id != id);
+        self.persist()
+    }
 
-        // Parse filename.
-        let filepath = format!("{}/blocks", dotenv::var("DATAROOT").unwrap());
-        let block_filename = format!("{}/{}.rdb", filepath, Uuid::new_v4().to_string());
+    // Get the tombstones that apply to a block.
+    pub fn get_tombstones(&self, filepath: &str) -> Vec<Tombstone> {
+        self.tombstones
+            .iter()
+            .filter(|x| x.filepaths.iter().any(|f| f == filepath))
+            .cloned()
+            .collect()
+    }
 
-        // Write bytes to filename.
-        fs::create_dir_all(&filepath).expect("ERROR: issue creating block dir.");
-        fs::write(&block_filename, block_bytes).expect("ERROR: writing block to disk");
+    // Get every block with tombstones against it.
+    pub fn get_tombstoned_filepaths(&self) -> HashSet<String> {
+        self.tombstones
+            .iter()
+            .flat_map(|x| x.filepaths.iter().cloned())
+            .collect()
+    }
 
-        // Insert new block reference into index.
-        self.insert(
-            block.start_timestamp.unwrap().timestamp_millis(),
-            block_filename,
-        );
+    // Move tombstones from replaced blocks onto their replacement, unless they were
+    // applied while rewriting them. Tombstones left with no blocks are dropped.
+    fn retarget_tombstones(&mut self, old: &[String], new: Option<&String>, applied: &[u64]) {
+        for tombstone in self.tombstones.iter_mut() {
+            let len = tombstone.filepaths.len();
+            tombstone.filepaths.retain(|x| !old.contains(x));
+            if tombstone.filepaths.len() < len && !applied.contains(&tombstone.id) {
+                tombstone.filepaths.extend(new.cloned());
+            }
+        }
+        self.tombstones.retain(|x| !x.filepaths.is_empty());
+    }
 
-        // Rewrite index to disk.
-        let index_filename = format!("{}/index.rdb", dotenv::var("DATAROOT").unwrap());
-        fs::write(&index_filename, bincode::serialize(&self.index).unwrap())
-            .expect("ERROR: writing index to disk");
+    // Add a block that is being flushed, returning the file it will go to. Until it's
+    // installed, it's read from memory. Each block is flushed after the last.
+    pub fn freeze(&mut self, mut block: Block) -> (String, Arc<Block>) {
+        block.frozen = true;
+        self.sequence += 1;
+        block.sequence = self.sequence;
+        let filepath = format!("{}/blocks/{}.rdb", self.dataroot, Uuid::new_v4());
+        let block = Arc::new(block);
+        self.frozen.push((filepath.clone(), Arc::clone(&block)));
+        (filepath, block)
+    }
 
-        // Print out block metadata.
-        println!("Number of series: {}", block.storage.len());
-        println!("Start timestamp: {}", block.start_timestamp.unwrap());
-        println!("End timestamp: {}", block.end_timestamp.unwrap());
+    // Get the blocks being flushed, with the files they will go to.
+    pub fn get_frozen(&self) -> &[(String, Arc<Block>)] {
+        &self.frozen
+    }
 
-        // Flush block from memory.
-        block.flush();
-        println!("Flushed block to disk.");
+    // Swap a frozen block for the file it was written to, and write the new index to
+    // disk. On failure, the block stays frozen.
+    pub fn install(&mut self, filepath: &str, block: &Block) -> Result<(), Error> {
+        let key = block.start_timestamp.unwrap().timestamp_millis();
+        let end = block.end_timestamp.unwrap().timestamp_millis();
+        self.insert(key, end, String::from(filepath));
+        if let Err(e) = self.persist() {
+            self.remove(key, filepath);
+            return Err(e);
+        }
+        self.frozen.retain(|x| x.0 != filepath);
+        Ok(())
     }
 
     // Get all blocked in packed form.
@@ -120,35 +431,255 @@ impl BlockIndex {
         for (_, v) in self.index.iter() {
             for f in v.iter() {
                 // Unpack the given file.
-                let packed_block = PackedBlock::from_filepath(f.clone());
-                ret.push(packed_block);
+                match PackedBlock::from_filepath(f.clone()) {
+                    Ok(packed_block) => ret.push(packed_block),
+                    Err(e) => println!("Skipping block {}: {}", f, e),
+                }
             }
         }
         ret
     }
 
-    // Get all blocks in a certain time range (by start times) in packed form.
-    pub fn get_packed_blocked_range(
-        &self,
-        start_timestamp: DateTime<Utc>,
-        end_timestamp: DateTime<Utc>,
-    ) -> Vec<PackedBlock> {
-        // For each block in the index...
+    // Get the blocks that may overlap a time range in packed form. Blocks are only
+    // mapped if they start before its end and, where known, end after its start.
+    pub fn get_packed_blocks_in_range(&self, time_range: &TimeRange) -> Vec<PackedBlock> {
+        let start = time_range.start.map(|x| x.timestamp_millis());
+        let end = time_range.end.map_or(i64::MAX, |x| x.timestamp_millis());
+
+        // For each block in the index starting before the end...
         let mut ret = vec![];
-        for (_, v) in self
-            .index
-            .range(start_timestamp.timestamp_millis()..end_timestamp.timestamp_millis())
-        {
+        for (_, v) in self.index.range(..=end) {
             for f in v.iter() {
+                // Skip it if it ends before the start.
+                if let (Some(start), Some(block_end)) = (start, self.ends.get(f)) {
+                    if *block_end < start {
+                        continue;
+                    }
+                }
+
                 // Unpack the given file.
-                let packed_block = PackedBlock::from_filepath(f.clone());
-                ret.push(packed_block);
+                match PackedBlock::from_filepath(f.clone()) {
+                    Ok(packed_block) => ret.push(packed_block),
+                    Err(e) => println!("Skipping block {}: {}", f, e),
+                }
             }
         }
         ret
     }
 }
 
+// Write a frozen block (and its rollups) to its file without holding any locks, then
+// install it in the index. On failure, the block stays frozen and can be retried.
+pub fn flush_frozen(
+    shared_index: &Arc<RwLock<BlockIndex>>,
+    filepath: &str,
+    block: &Block,
+) -> Result<(), Error> {
+    if let Some(dir) = Path::new(filepath).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let written = write_block_at(filepath, &block.to_bytes())
+        .and_then(|_| write_rollups(block, filepath))
+        .and_then(|_| {
+            shared_index
+                .write()
+                .expect("RwLock poisoned")
+                .install(filepath, block)
+        });
+    if let Err(e) = written {
+        remove_block_files(filepath);
+        return Err(e);
+    }
+
+    // Print out block metadata.
+    println!("Number of series: {}", block.storage.len());
+    println!("Start timestamp: {}", block.start_timestamp.unwrap());
+    println!("End timestamp: {}", block.end_timestamp.unwrap());
+    println!("Flushed block to disk.");
+    Ok(())
+}
+
+// Write a block's bytes to a new file in the block dir, returning its path.
+pub fn write_block(dataroot: &str, block_bytes: &[u8]) -> Result<String, Error> {
+    // Parse filename.
+    let filepath = format!("{}/blocks", dataroot);
+    let block_filename = format!("{}/{}.rdb", filepath, Uuid::new_v4());
+    fs::create_dir_all(&filepath)?;
+    write_block_at(&block_filename, block_bytes)?;
+    Ok(block_filename)
+}
+
+// Write a block's bytes to the given file and sync them.
+pub fn write_block_at(block_filename: &str, block_bytes: &[u8]) -> Result<(), Error> {
+    write_atomic(block_filename, block_bytes)
+}
+
+// Write bytes to a file so that a crash leaves either the old file or the new one,
+// whole: they go to a temp file that is synced, then renamed over it, and the rename
+// is synced too.
+pub fn write_atomic(filename: &str, bytes: &[u8]) -> Result<(), Error> {
+    let temp_filename = format!("{}{}", filename, TEMP_SUFFIX);
+    let written = File::create(&temp_filename)
+        .and_then(|mut f| {
+            f.write_all(bytes)?;
+            f.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_filename, filename));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_filename);
+        return Err(Error::Io(e));
+    }
+    sync_dir(filename)
+}
+
+// Sync the dir holding a file, so that creating, renaming or removing it is durable.
+pub fn sync_dir(filename: &str) -> Result<(), Error> {
+    let dir = match Path::new(filename).parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// Remove temp files left in a dir by writes a crash interrupted.
+fn remove_temp_files(dir: &str) -> Result<(), Error> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(Error::Io(e)),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.to_string_lossy().ends_with(TEMP_SUFFIX) {
+            println!("Removing incomplete write {}", path.display());
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+// Get the name of the file at a path.
+fn get_filename(filepath: &str) -> String {
+    Path::new(filepath)
+        .file_name()
+        .map_or(String::new(), |x| x.to_string_lossy().into_owned())
+}
+
+// Remove a block file and its rollups. Failures only leave unreferenced files behind,
+// so they are logged rather than returned.
+pub fn remove_block_files(filepath: &str) {
+    match fs::remove_file(filepath) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            println!("Leaving block {} behind: {}", filepath, e)
+        }
+        _ => (),
+    }
+    if let Err(e) = remove_rollups(filepath) {
+        println!("Leaving rollups of {} behind: {}", filepath, e);
+    }
+}
+
+// Tombstone Struct. A delete that still has to be applied to some flushed blocks;
+// until they are rewritten, matching points are filtered out at query time.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Tombstone {
+    id: u64,
+    delete: Delete,
+    filepaths: Vec<String>,
+}
+impl Tombstone {
+    // Get id.
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    // Returns true if the tombstone deletes the record.
+    pub fn matches(&self, record: &Record) -> bool {
+        self.delete.matches(record)
+    }
+}
+
+// Swap flushed blocks out of the index, replacing them with a new block if one is
+// given, then delete their files. Tombstones listed in applied are considered
+// applied to the new block. Does nothing and returns false if any of the old blocks
+// has already been removed from the index.
+pub fn replace_blocks(
+    shared_index: &Arc<RwLock<BlockIndex>>,
+    old: &[PackedBlock],
+    new: Option<&mut Block>,
+    applied: &[u64],
+) -> Result<bool, Error> {
+    // Write the new block (and its rollups) without holding any locks.
+    let dataroot = shared_index
+        .read()
+        .expect("RwLock poisoned")
+        .dataroot
+        .clone();
+    let new = match new {
+        Some(block) => {
+            let key = block.start_timestamp.unwrap().timestamp_millis();
+            let end = block.end_timestamp.unwrap().timestamp_millis();
+            let filepath = write_block(&dataroot, &block.to_bytes())?;
+            if let Err(e) = write_rollups(block, &filepath) {
+                remove_block_files(&filepath);
+                return Err(e);
+            }
+            Some((key, end, filepath))
+        }
+        None => None,
+    };
+
+    // Swap the index entries.
+    {
+        let mut index = shared_index.write().expect("RwLock poisoned");
+        let mut removed = vec![];
+        for packed_block in old.iter() {
+            let key = packed_block.start_timestamp.unwrap().timestamp_millis();
+            let end = packed_block.end_timestamp.unwrap().timestamp_millis();
+            if index.remove(key, &packed_block.filepath) {
+                removed.push((key, end, packed_block.get_filepath()));
+            }
+        }
+        let restore = |index: &mut BlockIndex, removed: Vec<(i64, i64, String)>| {
+            for (key, end, filepath) in removed {
+                index.insert(key, end, filepath);
+            }
+        };
+        if removed.len() != old.len() {
+            restore(&mut index, removed);
+            drop(index);
+            if let Some((_, _, filepath)) = new {
+                remove_block_files(&filepath);
+            }
+            return Ok(false);
+        }
+
+        // If the new index can't be persisted, put the old one back.
+        let tombstones = index.tombstones.clone();
+        let old_filepaths: Vec<String> = old.iter().map(|x| x.get_filepath()).collect();
+        index.retarget_tombstones(&old_filepaths, new.as_ref().map(|x| &x.2), applied);
+        if let Some((key, end, filepath)) = &new {
+            index.insert(*key, *end, filepath.clone());
+        }
+        if let Err(e) = index.persist() {
+            if let Some((key, _, filepath)) = &new {
+                index.remove(*key, filepath);
+                remove_block_files(filepath);
+            }
+            restore(&mut index, removed);
+            index.tombstones = tombstones;
+            return Err(e);
+        }
+    }
+
+    // Nothing references the old blocks any more; open mappings stay readable.
+    for packed_block in old.iter() {
+        remove_block_files(&packed_block.filepath);
+    }
+    Ok(true)
+}
+
 // Block Struct.
 pub struct Block {
     index: HashMap<String, Bitmap>,
@@ -160,6 +691,8 @@ pub struct Block {
     frozen: bool,
     compressed_index: Option<Map<Vec<u8>>>,
     compressed_bitmaps: Vec<Bitmap>,
+    size: usize,   // Rough number of bytes inserted, for flushing
+    sequence: u64, // Flush order; a later block holds later writes
 }
 impl Block {
     // Constructor.
@@ -174,6 +707,8 @@ impl Block {
             frozen: false,
             compressed_index: None,
             compressed_bitmaps: vec![],
+            size: 0,
+            sequence: 0,
         }
     }
 
@@ -182,9 +717,117 @@ impl Block {
         &self.storage
     }
 
-    // Get the bitmap for a specific label / metric.
-    pub fn search_index(&self, key: String) -> Option<&Bitmap> {
-        self.index.get(&key)
+    // Get the rough number of bytes the block holds.
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+
+    // Get start timestamp.
+    pub fn get_start_timestamp(&self) -> Option<DateTime<Utc>> {
+        self.start_timestamp
+    }
+
+    // Get the flush order of the block.
+    pub fn get_sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    // Set the flush order of the block, e.g. to that of the blocks it replaces.
+    pub fn set_sequence(&mut self, sequence: u64) {
+        self.sequence = sequence;
+    }
+
+    // Insert a record into the block, creating its series if needed. Fails, leaving
+    // the block untouched, if the record doesn't fit its series' schema.
+    pub fn insert(&mut self, record: Record) -> Result<(), Error> {
+        debug_assert!(!self.frozen, "ERROR: inserting into a frozen block.");
+
+        // Each point costs its timestamp and values; new series also their key.
+        let mut size = 8 + 8 * record.get_populated_variables().len();
+
+        // Check if this series exists in the block
+        let key: String = record.get_key();
+        if let Some(&id) = self.key_map.get(&key) {
+            // A series emptied by a delete rejoins the index.
+            let was_empty = self.storage[id].is_empty();
+            self.storage[id].insert(record.clone())?;
+            if was_empty {
+                self.index_series(id, &record);
+            }
+        }
+        // Key does not exist in the block
+        else {
+            let id = self.storage.len();
+            self.storage.push(Series::new(id, record.clone())?);
+            size += key.len();
+            self.id_map.push(key.clone());
+            self.key_map.insert(key, id);
+            self.index_series(id, &record);
+        }
+
+        // Update block timeranges.
+        let timestamp = record.get_timestamp();
+        if self.start_timestamp.is_none() || self.start_timestamp.unwrap() > timestamp {
+            self.start_timestamp = Some(timestamp);
+        }
+        if self.end_timestamp.is_none() || self.end_timestamp.unwrap() < timestamp {
+            self.end_timestamp = Some(timestamp);
+        }
+        self.size += size;
+        Ok(())
+    }
+
+    // Add a series' label key-value pairs, name and metrics to the index.
+    fn index_series(&mut self, id: usize, record: &Record) {
+        let mut labels = record.get_labels();
+        labels.push(format!("{}={}", NAME_LABEL, record.get_name()));
+        labels.append(&mut record.get_metrics());
+        for label in labels {
+            self.index
+                .entry(label)
+                .or_insert_with(Bitmap::create)
+                .add(id as u32);
+        }
+    }
+
+    // Remove the points matching a delete from the block, in place. Series left empty
+    // leave the index until written to again, and the time range and size shrink with
+    // the points.
+    pub fn delete(&mut self, delete: &Delete) -> Result<(), Error> {
+        let mut emptied = vec![];
+        let mut deleted = false;
+        for (id, series) in self.storage.iter().enumerate() {
+            let removed = series.delete(delete)?;
+            if removed == 0 {
+                continue;
+            }
+            deleted = true;
+            self.size = self.size.saturating_sub(removed * series.get_point_size());
+            if series.is_empty() {
+                emptied.push(id as u32);
+            }
+        }
+        if !deleted {
+            return Ok(());
+        }
+        for bitmap in self.index.values_mut() {
+            for id in emptied.iter() {
+                bitmap.remove(*id);
+            }
+        }
+        self.index.retain(|_, x| !x.is_empty());
+
+        // Series are sorted, so their ends bound the block's range.
+        let ranges: Vec<(i64, i64)> = self.storage.iter().filter_map(|x| x.get_range()).collect();
+        self.start_timestamp = match ranges.iter().map(|x| x.0).min() {
+            Some(start) => Some(decode_timestamp(start)?),
+            None => None,
+        };
+        self.end_timestamp = match ranges.iter().map(|x| x.1).max() {
+            Some(end) => Some(decode_timestamp(end)?),
+            None => None,
+        };
+        Ok(())
     }
 
     // Returns the k/v pairs in the index by lexicographic order
@@ -195,23 +838,28 @@ impl Block {
     }
 
     // Convert a block to bytes.
-    pub fn to_bytes(&mut self) -> Vec<u8> {
-        // Freeze and cap the block.
-        self.frozen = true;
-
-        // Generate the fst
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Generate the fst, mapping each key to the offset of its bitmap.
         let mut fst_builder = MapBuilder::memory();
         let mut bitmaps: Vec<Vec<u8>> = vec![];
-        let mut pos: u64 = 0;
-        for (key, bitmap) in self.get_sorted_index().iter() {
-            println!("{} {:?}", key, bitmap);
-            fst_builder.insert(key, pos).unwrap();
+        for (_, bitmap) in self.get_sorted_index().iter() {
             bitmaps.push(bitmap.serialize());
-            pos += 1;
         }
-        let serialized_bitmaps = bincode::serialize::<Vec<Vec<u8>>>(&bitmaps).unwrap();
+        let (serialized_bitmaps, bitmap_offsets) = write_entries(bitmaps);
+        for ((key, _), pos) in self.get_sorted_index().iter().zip(bitmap_offsets) {
+            fst_builder.insert(key, pos).unwrap();
+        }
         let serialized_fst = fst_builder.into_inner().unwrap();
-        // Serializing the block's parts.
+
+        // Serialize each series separately so they can be read back one at a time.
+        let mut serialized_storage = vec![];
+        let mut series_offsets = vec![];
+        for series in self.storage.iter() {
+            serialized_storage.append(&mut series.into_bytes());
+            series_offsets.push(serialized_storage.len() as u64);
+        }
+
+        // Serializing the block's parts, in block_file::Section order.
         let serialized_start_timestamp = self
             .start_timestamp
             .unwrap()
@@ -224,131 +872,57 @@ impl Block {
             .timestamp_millis()
             .to_le_bytes()
             .to_vec();
-        /*
-        Original index compression for reference:
-         */
-        /*
-        let serialized_index_keys =
-            bincode::serialize::<Vec<String>>(&self.index.keys().map(|x| x.clone()).collect())
-                .unwrap();
-
-        let serialized_index_values = bincode::serialize::<Vec<Vec<u8>>>(
-            &self.index.values().map(|x| x.serialize()).collect(),
-        )
-        .unwrap();
-        */
         let serialized_id_map = bincode::serialize(&self.id_map).unwrap();
         let serialized_key_map = bincode::serialize(&self.key_map).unwrap();
-        let serialized_storage = bincode::serialize(&self.storage).unwrap();
-        let parts = vec![
+        write_block_file(vec![
             serialized_start_timestamp,
             serialized_end_timestamp,
             serialized_fst,
             serialized_bitmaps,
             serialized_id_map,
             serialized_key_map,
+            write_offsets(&series_offsets),
             serialized_storage,
-        ];
-        assert!(parts.len() == HEADER_SIZE);
-
-        // Create a header of pointers.
-        let mut cum: usize = HEADER_SIZE * size_of::<usize>();
-        let mut header = vec![];
-        for p in &parts {
-            cum += p.len();
-            header.append(&mut cum.to_ne_bytes().to_vec());
-        }
-
-        // Construct and return.
-        let mut data: Vec<u8> = vec![];
-        data.append(&mut header);
-        for mut p in parts {
-            data.append(&mut p);
-        }
-        data
+            self.sequence.to_le_bytes().to_vec(),
+        ])
     }
 
     // Create a block from bytes.
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        // Read header (should be fixed size) and figure out array segments
-        let header_size = HEADER_SIZE * size_of::<usize>();
-        let bytes_iter: Vec<u8> = bytes.into_iter().cloned().collect();
-        let header: Vec<usize> = bytes_iter[0..header_size]
-            .to_vec()
-            .chunks(size_of::<usize>())
-            .map(|x| usize::from_ne_bytes(x.try_into().unwrap()))
-            .collect();
-
-        // Deserialize each segment.
-        let bytes_start_timestamp: [u8; 8] = bytes_iter[header_size..header[0] as usize]
-            .try_into()
-            .unwrap();
-        let start_millis = i64::from_le_bytes(bytes_start_timestamp);
-        let start_secs = (start_millis / 1000) as i64;
-        let start_nanos = ((start_millis % 1000) * 1_000_000) as u32;
-        let deserialized_start_timestamp =
-            DateTime::from_utc(NaiveDateTime::from_timestamp(start_secs, start_nanos), Utc);
-
-        let bytes_end_timestamp: [u8; 8] = bytes_iter[header[0] as usize..header[1] as usize]
-            .try_into()
-            .unwrap();
-        let end_millis = i64::from_le_bytes(bytes_end_timestamp);
-        let end_secs = (end_millis / 1000) as i64;
-        let end_nanos = ((end_millis % 1000) * 1_000_000) as u32;
-        let deserialized_end_timestamp =
-            DateTime::from_utc(NaiveDateTime::from_timestamp(end_secs, end_nanos), Utc);
-
-        /*
-        let bytes_index_keys: Vec<u8> = bytes_iter[header[1] as usize..header[2] as usize]
-            .try_into()
-            .unwrap();
-        let deserialized_index_keys =
-            bincode::deserialize::<Vec<String>>(&bytes_index_keys).unwrap();
-
-        let bytes_index_values: Vec<u8> =
-            bytes_iter[header[2] as usize..header[3] as usize].to_vec();
-        let deserialized_index_values = bincode::deserialize::<Vec<Vec<u8>>>(&bytes_index_values)
-            .unwrap()
-            .iter()
-            .map(|x| Bitmap::deserialize(x))
-            .collect::<Vec<Bitmap>>();
-         */
-        let bytes_fst: Vec<u8> = bytes_iter[header[1] as usize..header[2] as usize]
-            .try_into()
-            .unwrap();
-        let deserialized_fst = Map::new(bytes_fst).unwrap();
-        let bytes_bitmaps: Vec<u8> = bytes_iter[header[2] as usize..header[3] as usize].to_vec();
-        let deserialized_bitmaps = bincode::deserialize::<Vec<Vec<u8>>>(&bytes_bitmaps)
-            .unwrap()
-            .iter()
-            .map(|x| Bitmap::deserialize(x))
-            .collect::<Vec<Bitmap>>();
-
-        let bytes_id_map: Vec<u8> = bytes_iter[header[3] as usize..header[4] as usize].to_vec();
-        let deserialized_id_map = bincode::deserialize::<Vec<String>>(&bytes_id_map).unwrap();
-
-        let bytes_key_map: Vec<u8> = bytes_iter[header[4] as usize..header[5] as usize].to_vec();
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        // Read header and deserialize each segment.
+        let reader = BlockFileReader::new(bytes)?;
+        let deserialized_start_timestamp = reader.timestamp(Section::StartTimestamp)?;
+        let deserialized_end_timestamp = reader.timestamp(Section::EndTimestamp)?;
+        let deserialized_id_map =
+            bincode::deserialize::<Vec<String>>(reader.section(Section::IdMap))
+                .map_err(|_| Error::CorruptBlock(String::from("bad id map")))?;
         let deserialized_key_map =
-            bincode::deserialize::<HashMap<String, usize>>(&bytes_key_map).unwrap();
-
-        let bytes_storage: Vec<u8> = bytes_iter[header[5] as usize..header[6] as usize].to_vec();
-        let deserialized_storage = bincode::deserialize::<Vec<Series>>(&bytes_storage).unwrap();
-
-        // Should be empty.
-        assert!(bytes_iter.len() == header[6] as usize);
+            bincode::deserialize::<HashMap<String, usize>>(reader.section(Section::KeyMap))
+                .map_err(|_| Error::CorruptBlock(String::from("bad key map")))?;
+        let mut deserialized_storage = vec![];
+        for id in 0..deserialized_id_map.len() {
+            deserialized_storage.push(read_series(
+                reader.section(Section::SeriesOffsets),
+                reader.section(Section::Storage),
+                id,
+            )?);
+        }
 
         // Create Hashmap
+        let bitmaps = reader.section(Section::Bitmaps);
+        let deserialized_fst = Map::new(reader.section(Section::Fst))
+            .map_err(|_| Error::CorruptBlock(String::from("bad fst")))?;
         let mut deserialized_index: HashMap<String, Bitmap> = HashMap::new();
         let mut stream = deserialized_fst.into_stream();
-        while let Some((key, idx)) = stream.next() {
+        while let Some((key, pos)) = stream.next() {
             deserialized_index.insert(
-                String::from(str::from_utf8(key).unwrap()),
-                deserialized_bitmaps[idx as usize].clone(),
+                String::from_utf8_lossy(key).into_owned(),
+                read_bitmap(bitmaps, pos)?,
             );
         }
 
         // Initialize and return block.
-        Block {
+        Ok(Block {
             index: deserialized_index,
             storage: deserialized_storage,
             id_map: deserialized_id_map,
@@ -358,149 +932,284 @@ impl Block {
             frozen: false,
             compressed_index: None,
             compressed_bitmaps: vec![],
+            size: bytes.len(),
+            sequence: reader.sequence()?,
+        })
+    }
+}
+
+// BlockView Trait. Read access to the index and series of a block, whether it
+// lives in memory or is lazily loaded from disk.
+pub trait BlockView {
+    // Get the bitmap for a specific label / metric.
+    fn search_index(&self, key: String) -> Option<Bitmap>;
+
+    // Union the bitmaps of every key that starts with prefix and passes the filter.
+    fn search_prefix(&self, prefix: &str, filter: &dyn Fn(&str) -> bool) -> Bitmap;
+
+    // Get the ids of every series in the block.
+    fn get_all_series(&self) -> Bitmap;
+
+    // Stream the records of a series, by id, in insertion order.
+    fn get_series_records(&self, id: u32) -> Box<dyn Iterator<Item = Record> + '_>;
+}
+impl BlockView for Block {
+    fn search_index(&self, key: String) -> Option<Bitmap> {
+        self.index.get(&key).cloned()
+    }
+
+    fn search_prefix(&self, prefix: &str, filter: &dyn Fn(&str) -> bool) -> Bitmap {
+        let mut series = Bitmap::create();
+        for (key, bitmap) in self.index.iter() {
+            if key.starts_with(prefix) && filter(key) {
+                series.or_inplace(bitmap);
+            }
         }
+        series
     }
 
-    // Flush block data.
-    pub fn flush(&mut self) {
-        self.index = HashMap::new();
-        self.storage = vec![];
-        self.id_map = vec![];
-        self.key_map = HashMap::new();
-        self.start_timestamp = None;
-        self.end_timestamp = None;
-        self.frozen = false;
+    fn get_all_series(&self) -> Bitmap {
+        let mut series = Bitmap::create();
+        series.add_range(0..self.storage.len() as u64);
+        series
+    }
+
+    fn get_series_records(&self, id: u32) -> Box<dyn Iterator<Item = Record> + '_> {
+        match self.storage[id as usize].get_records() {
+            Ok(records) => Box::new(records.into_iter()),
+            Err(e) => {
+                println!("Skipping series {} in block: {}", id, e);
+                Box::new(std::iter::empty())
+            }
+        }
+    }
+}
+
+// MmapSlice Struct. One section of a memory-mapped block file.
+#[derive(Clone)]
+pub struct MmapSlice {
+    mmap: Arc<Mmap>,
+    range: Range<usize>,
+}
+impl AsRef<[u8]> for MmapSlice {
+    fn as_ref(&self) -> &[u8] {
+        &self.mmap[self.range.clone()]
     }
 }
 
-// PackedBlock Struct
+// PackedBlock Struct. Keeps a block file mapped, decoding bitmaps and series on demand.
 pub struct PackedBlock {
     start_timestamp: Option<DateTime<Utc>>,
     end_timestamp: Option<DateTime<Utc>>,
-    index: HashMap<String, Bitmap>,
+    index: Map<MmapSlice>,
+    bitmaps: MmapSlice,
+    series_offsets: MmapSlice,
+    storage: MmapSlice,
+    mmap: Arc<Mmap>,
     filepath: String,
+    sequence: u64,
 }
 impl PackedBlock {
     // Construct a PackedBlock from file.
-    pub fn from_filepath(filepath: String) -> PackedBlock {
-        // Read file out to bytes.
-        let mut file = File::open(&filepath).expect("ERROR: invalid filepath.");
-        let mut bytes = vec![];
-        file.read_to_end(&mut bytes)
-            .expect("ERROR: issue reading block from disk.");
-
-        // Read header (should be fixed size) and figure out array segments
-        let header_size = HEADER_SIZE * size_of::<usize>();
-        let bytes_iter: Vec<u8> = bytes.into_iter().collect();
-        let header: Vec<usize> = bytes_iter[0..header_size]
-            .to_vec()
-            .chunks(size_of::<usize>())
-            .map(|x| usize::from_ne_bytes(x.try_into().unwrap()))
-            .collect();
-
-        // Deserialize each segment.
-        let bytes_start_timestamp: [u8; 8] = bytes_iter[header_size..header[0] as usize]
-            .try_into()
-            .unwrap();
-        let start_millis = i64::from_le_bytes(bytes_start_timestamp);
-        let start_secs = (start_millis / 1000) as i64;
-        let start_nanos = ((start_millis % 1000) * 1_000_000) as u32;
-        let deserialized_start_timestamp =
-            DateTime::from_utc(NaiveDateTime::from_timestamp(start_secs, start_nanos), Utc);
-
-        let bytes_end_timestamp: [u8; 8] = bytes_iter[header[0] as usize..header[1] as usize]
-            .try_into()
-            .unwrap();
-        let end_millis = i64::from_le_bytes(bytes_end_timestamp);
-        let end_secs = (end_millis / 1000) as i64;
-        let end_nanos = ((end_millis % 1000) * 1_000_000) as u32;
-        let deserialized_end_timestamp =
-            DateTime::from_utc(NaiveDateTime::from_timestamp(end_secs, end_nanos), Utc);
-
-        let bytes_index_keys: Vec<u8> = bytes_iter[header[1] as usize..header[2] as usize]
-            .try_into()
-            .unwrap();
-        let deserialized_index_keys =
-            bincode::deserialize::<Vec<String>>(&bytes_index_keys).unwrap();
-
-        let bytes_index_values: Vec<u8> =
-            bytes_iter[header[2] as usize..header[3] as usize].to_vec();
-        let deserialized_index_values = bincode::deserialize::<Vec<Vec<u8>>>(&bytes_index_values)
-            .unwrap()
-            .iter()
-            .map(|x| Bitmap::deserialize(x))
-            .collect::<Vec<Bitmap>>();
-
-        // Create Hashmap
-        let deserialized_index: HashMap<String, Bitmap> = (deserialized_index_keys
-            .iter()
-            .cloned()
-            .zip(deserialized_index_values))
-        .collect();
+    pub fn from_filepath(filepath: String) -> Result<PackedBlock, Error> {
+        // Map the file. Block files are never modified once written, only replaced.
+        let file = File::open(&filepath)?;
+        let mmap = Arc::new(unsafe { Mmap::map(&file) }?);
+
+        // Read header and locate the segments we need.
+        let reader = BlockFileReader::new(&mmap)?;
+        let deserialized_start_timestamp = reader.timestamp(Section::StartTimestamp)?;
+        let deserialized_end_timestamp = reader.timestamp(Section::EndTimestamp)?;
+        let slice = |section| MmapSlice {
+            mmap: Arc::clone(&mmap),
+            range: reader.section_range(section),
+        };
+        let index = Map::new(slice(Section::Fst))
+            .map_err(|_| Error::CorruptBlock(String::from("bad fst")))?;
 
         // Initialize and return block.
-        PackedBlock {
-            index: deserialized_index,
+        Ok(PackedBlock {
             start_timestamp: Some(deserialized_start_timestamp),
             end_timestamp: Some(deserialized_end_timestamp),
-            filepath: filepath,
-        }
+            index,
+            bitmaps: slice(Section::Bitmaps),
+            series_offsets: slice(Section::SeriesOffsets),
+            storage: slice(Section::Storage),
+            mmap: Arc::clone(&mmap),
+            filepath,
+            sequence: reader.sequence()?,
+        })
     }
 
-    // Return the embedded Block.
-    pub fn unpack(&self) -> Block {
-        // Read file out to bytes.
-        let mut file = File::open(&self.filepath).expect("ERROR: invalid filepath.");
-        let mut bytes = vec![];
-        file.read_to_end(&mut bytes)
-            .expect("ERROR: issue reading block from disk.");
-        Block::from_bytes(&bytes)
+    // Get start timestamp.
+    pub fn get_start_timestamp(&self) -> Option<DateTime<Utc>> {
+        self.start_timestamp
     }
-}
 
-// Series struct.
-#[derive(Serialize, Deserialize)]
-pub struct Series {
-    id: usize,
-    name: String,
-    labels: HashMap<String, String>,
-    variables: Vec<String>,
-    records: RwLock<Vec<SeriesRecord>>,
-}
-impl Series {
-    // Constructor.
-    pub fn new(id: usize, record: Record) -> Self {
-        Series {
-            id: id,
-            name: record.get_name(),
-            labels: record.get_populated_labels(),
-            variables: record.get_variable_keys(),
-            records: RwLock::new(vec![SeriesRecord::from_record(record)]),
-        }
+    // Get end timestamp.
+    pub fn get_end_timestamp(&self) -> Option<DateTime<Utc>> {
+        self.end_timestamp
     }
 
-    // Get key.
-    pub fn get_key(&self) -> String {
-        // Start with name.
-        let mut temp_key: String = self.name.clone();
+    // Get filepath.
+    pub fn get_filepath(&self) -> String {
+        self.filepath.clone()
+    }
 
-        // Sort labels and add to key.
-        let mut sorted_labels: Vec<_> = self.labels.iter().collect();
-        sorted_labels.sort_by_key(|x| x.0);
-        for (key, value) in sorted_labels.iter() {
-            temp_key.push_str(key);
-            temp_key.push_str(value);
-        }
+    // Get the flush order of the block.
+    pub fn get_sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    // Check whether a label / metric appears in the block, without decoding its bitmap.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.index.contains_key(key)
+    }
 
-        // Sort variables and add to key.
-        let mut sorted_variables: Vec<_> = self.variables.clone();
-        sorted_variables.sort();
-        for variable in sorted_variables.iter() {
-            temp_key.push_str(variable);
+    // Check whether any key that starts with prefix passes the filter, without
+    // decoding bitmaps.
+    pub fn contains_prefix(&self, prefix: &str, filter: &dyn Fn(&str) -> bool) -> bool {
+        let mut stream = self
+            .index
+            .search(Str::new(prefix).starts_with())
+            .into_stream();
+        while let Some((key, _)) = stream.next() {
+            if std::str::from_utf8(key).is_ok_and(filter) {
+                return true;
+            }
         }
+        false
+    }
 
-        // Return.
-        temp_key
+    // Get the packed bytes of a single series.
+    fn get_series_bytes(&self, id: u32) -> Result<&[u8], Error> {
+        read_series_bytes(
+            self.series_offsets.as_ref(),
+            self.storage.as_ref(),
+            id as usize,
+        )
+    }
+
+    // Return the embedded Block.
+    pub fn unpack(&self) -> Result<Block, Error> {
+        Block::from_bytes(&self.mmap)
+    }
+}
+impl BlockView for PackedBlock {
+    fn search_index(&self, key: String) -> Option<Bitmap> {
+        let pos = self.index.get(&key)?;
+        match read_bitmap(self.bitmaps.as_ref(), pos) {
+            Ok(bitmap) => Some(bitmap),
+            Err(e) => {
+                println!("Skipping {} in block {}: {}", key, self.filepath, e);
+                None
+            }
+        }
+    }
+
+    fn search_prefix(&self, prefix: &str, filter: &dyn Fn(&str) -> bool) -> Bitmap {
+        // Only the keys in the prefix's range of the fst are visited.
+        let mut series = Bitmap::create();
+        let mut stream = self
+            .index
+            .search(Str::new(prefix).starts_with())
+            .into_stream();
+        while let Some((key, pos)) = stream.next() {
+            let key = match std::str::from_utf8(key) {
+                Ok(key) if filter(key) => key,
+                _ => continue,
+            };
+            match read_bitmap(self.bitmaps.as_ref(), pos) {
+                Ok(bitmap) => series.or_inplace(&bitmap),
+                Err(e) => println!("Skipping {} in block {}: {}", key, self.filepath, e),
+            }
+        }
+        series
+    }
+
+    fn get_all_series(&self) -> Bitmap {
+        let mut series = Bitmap::create();
+        series.add_range(0..(self.series_offsets.as_ref().len() / 8) as u64);
+        series
+    }
+
+    fn get_series_records(&self, id: u32) -> Box<dyn Iterator<Item = Record> + '_> {
+        // Records are decoded from the chunk one at a time, as they are consumed.
+        let (series, decoder) = match self.get_series_bytes(id).and_then(Series::open) {
+            Ok(opened) => opened,
+            Err(e) => {
+                println!("Skipping series {} in block {}: {}", id, self.filepath, e);
+                return Box::new(std::iter::empty());
+            }
+        };
+        let filepath = &self.filepath;
+        Box::new(decoder.scan((), move |_, point| {
+            let record = point.and_then(|(timestamp, metrics)| {
+                SeriesRecord { metrics, timestamp }.to_record(&series)
+            });
+            match record {
+                Ok(record) => Some(record),
+                Err(e) => {
+                    println!("Truncating series {} in block {}: {}", id, filepath, e);
+                    None
+                }
+            }
+        }))
+    }
+}
+
+// Decode the bitmap at the given offset of a block file's bitmap segment.
+fn read_bitmap(bitmaps: &[u8], pos: u64) -> Result<Bitmap, Error> {
+    Bitmap::try_deserialize(read_entry(bitmaps, pos as usize)?)
+        .ok_or_else(|| Error::CorruptBlock(format!("bad bitmap at {}", pos)))
+}
+
+// Decode a series from a block file's storage segment.
+fn read_series(series_offsets: &[u8], storage: &[u8], id: usize) -> Result<Series, Error> {
+    Series::from_bytes(read_series_bytes(series_offsets, storage, id)?)
+}
+
+// Get the packed bytes of a series from a block file's storage segment.
+fn read_series_bytes<'a>(
+    series_offsets: &[u8],
+    storage: &'a [u8],
+    id: usize,
+) -> Result<&'a [u8], Error> {
+    let range = read_offset_range(series_offsets, id)?;
+    storage
+        .get(range)
+        .ok_or_else(|| Error::CorruptBlock(format!("series {} out of bounds", id)))
+}
+
+// Series struct.
+pub struct Series {
+    id: usize,
+    name: String,
+    labels: HashMap<String, String>,
+    variables: Vec<String>,
+    records: RwLock<Vec<SeriesRecord>>,
+}
+impl Series {
+    // Constructor. Fails if the record can't be laid out in its own variables' order.
+    pub fn new(id: usize, record: Record) -> Result<Self, Error> {
+        // Variables are sorted once, here; every record's metrics follow this order.
+        let mut variables = record.get_variable_keys();
+        variables.sort();
+        let name = record.get_name();
+        let labels = record.get_populated_labels();
+        let first = SeriesRecord::from_record(record, &variables)?;
+        Ok(Series {
+            id,
+            name,
+            labels,
+            variables,
+            records: RwLock::new(vec![first]),
+        })
+    }
+
+    // Get key, matching the key of the series' records.
+    pub fn get_key(&self) -> String {
+        series_key(&self.name, &self.labels, &self.variables)
     }
 
     // Get name.
@@ -518,8 +1227,8 @@ impl Series {
         self.variables.clone()
     }
 
-    // Export records (SeriesRecord) to a Vec<Records>.
-    pub fn get_records(&self) -> Vec<Record> {
+    // Export records (SeriesRecord) to a Vec<Records>. Fails if any can't be decoded.
+    pub fn get_records(&self) -> Result<Vec<Record>, Error> {
         self.records
             .read()
             .expect("RwLock poisoned")
@@ -528,41 +1237,134 @@ impl Series {
             .collect()
     }
 
-    // Insert a record into this series.
-    pub fn insert(&self, record: Record) {
+    // Returns true if the series has no records left.
+    pub fn is_empty(&self) -> bool {
+        self.records.read().expect("RwLock poisoned").is_empty()
+    }
+
+    // Get the rough number of bytes each point takes: its timestamp and values.
+    pub fn get_point_size(&self) -> usize {
+        8 + 8 * self.variables.len()
+    }
+
+    // Get the timestamps (millis) of the first and last records, if any.
+    pub fn get_range(&self) -> Option<(i64, i64)> {
+        let records = self.records.read().expect("RwLock poisoned");
+        Some((records.first()?.timestamp, records.last()?.timestamp))
+    }
+
+    // Insert a record into this series, keeping records sorted by timestamp. Late
+    // points are placed after any with the same timestamp.
+    pub fn insert(&self, record: Record) -> Result<(), Error> {
+        let series_record = SeriesRecord::from_record(record, &self.variables)?;
         let mut v = self.records.write().expect("RwLock poisoned");
-        v.push(SeriesRecord::from_record(record));
+        let pos = v.partition_point(|x| x.timestamp <= series_record.timestamp);
+        v.insert(pos, series_record);
+        Ok(())
     }
 
-    // Convert to bytes.
+    // Remove the records matching a delete from this series, returning how many were
+    // removed. Fails, leaving the series untouched, if any record can't be decoded.
+    pub fn delete(&self, delete: &Delete) -> Result<usize, Error> {
+        let mut v = self.records.write().expect("RwLock poisoned");
+        let mut keep = Vec::with_capacity(v.len());
+        for x in v.iter() {
+            keep.push(!delete.matches(&x.to_record(self)?));
+        }
+        let len = v.len();
+        let mut keep = keep.into_iter();
+        v.retain(|_| keep.next().unwrap_or(true));
+        Ok(len - v.len())
+    }
+
+    // Convert to bytes, compressing the records into a single chunk.
     pub fn into_bytes(&self) -> Vec<u8> {
-        bincode::serialize(self).unwrap()
+        let mut encoder = ChunkEncoder::new(self.variables.len());
+        for record in self.records.read().expect("RwLock poisoned").iter() {
+            encoder.push(record.timestamp, &record.metrics);
+        }
+        let chunk = encoder.finish();
+        bincode::serialize(&PackedSeries {
+            id: self.id,
+            name: self.name.clone(),
+            labels: self.labels.clone(),
+            variables: self.variables.clone(),
+            chunk: &chunk,
+        })
+        .unwrap()
     }
 
-    // Convert from bytes.
-    pub fn from_bytes(data: &[u8]) -> Self {
-        bincode::deserialize(data).unwrap()
+    // Convert from bytes, decompressing every record.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        let (series, decoder) = Series::open(data)?;
+        {
+            let mut records = series.records.write().expect("RwLock poisoned");
+            for point in decoder {
+                let (timestamp, metrics) = point?;
+                decode_timestamp(timestamp)?;
+                records.push(SeriesRecord { metrics, timestamp });
+            }
+        }
+        Ok(series)
+    }
+
+    // Read a series' metadata from bytes, returning it without records alongside a
+    // decoder for its chunk.
+    fn open(data: &[u8]) -> Result<(Self, ChunkDecoder<'_>), Error> {
+        let packed: PackedSeries = bincode::deserialize(data)
+            .map_err(|_| Error::CorruptBlock(String::from("bad series")))?;
+        let decoder = ChunkDecoder::new(packed.chunk)?;
+        let series = Series {
+            id: packed.id,
+            name: packed.name,
+            labels: packed.labels,
+            variables: packed.variables,
+            records: RwLock::new(vec![]),
+        };
+        Ok((series, decoder))
     }
 }
 
-// SeriesRecord struct.
+// PackedSeries struct. On-disk form of a series: its metadata and a compressed chunk
+// holding its records.
 #[derive(Serialize, Deserialize)]
+struct PackedSeries<'a> {
+    id: usize,
+    name: String,
+    labels: HashMap<String, String>,
+    variables: Vec<String>,
+    chunk: &'a [u8],
+}
+
+// SeriesRecord struct.
 pub struct SeriesRecord {
     metrics: Vec<f64>,
     timestamp: i64,
 }
 impl SeriesRecord {
-    pub fn from_record(record: Record) -> Self {
-        SeriesRecord {
-            metrics: record.get_populated_variables().values().cloned().collect(),
-            timestamp: record.get_timestamp().timestamp_millis(),
+    // Constructor. Lays the record's metrics out in the order of the series' variables,
+    // failing if the record doesn't have exactly those variables.
+    pub fn from_record(record: Record, variables: &[String]) -> Result<Self, Error> {
+        let populated = record.get_populated_variables();
+        let metrics: Option<Vec<f64>> = variables
+            .iter()
+            .map(|x| populated.get(x).cloned())
+            .collect();
+        match metrics {
+            Some(metrics) if populated.len() == variables.len() => Ok(SeriesRecord {
+                metrics,
+                timestamp: record.get_timestamp().timestamp_millis(),
+            }),
+            _ => Err(Error::SchemaMismatch(format!(
+                "expected variables {:?}, got {:?}",
+                variables,
+                record.get_variable_keys()
+            ))),
         }
     }
 
-    pub fn to_record(&self, series: &Series) -> Record {
-        let ts_secs = (self.timestamp / 1000) as i64;
-        let ts_nanos = ((self.timestamp % 1000) * 1_000_000) as u32;
-        Record::new(
+    pub fn to_record(&self, series: &Series) -> Result<Record, Error> {
+        Ok(Record::new(
             series.get_name(),
             series.get_labels(),
             series
@@ -570,19 +1372,23 @@ impl SeriesRecord {
                 .into_iter()
                 .zip(self.metrics.clone())
                 .collect(),
-            DateTime::from_utc(NaiveDateTime::from_timestamp(ts_secs, ts_nanos), Utc),
-        )
+            decode_timestamp(self.timestamp)?,
+        ))
     }
 }
 
-// Ingests a read operation.
+// Ingests a read operation. Reader threads take turns receiving requests.
 fn db_read(
-    read_rx: Receiver<SelectRequest>,
+    read_rx: Arc<Mutex<Receiver<SelectRequest>>>,
     shared_block: Arc<RwLock<Block>>,
     shared_index: Arc<RwLock<BlockIndex>>,
 ) {
     // Receive read operations from the server
-    for request in read_rx {
+    loop {
+        let request = match read_rx.lock().expect("Mutex poisoned").recv() {
+            Ok(request) => request,
+            Err(_) => break,
+        };
         // Eval statement and reply.
         let statement = request.statement.clone();
         println!("===================================");
@@ -591,115 +1397,1409 @@ fn db_read(
             Value::to_string(&json!(statement))
         );
 
-        // Convert to DNF. NOTE: Changing this param
-        let dnf_statement = statement;
-        //let dnf_statement = dnf(statement);
+        // Convert to DNF, pushing negations down to the leaves.
+        let dnf_statement = dnf(statement);
         println!("===================================");
         println!(
             "Converted statement: {}",
             Value::to_string(&json!(dnf_statement))
         );
 
-        let mut result = dnf_statement.eval(&shared_block);
-        result.unpack(&shared_block);
-        request.reply(result.into_vec());
+        // Snapshot what the statement reads, taking the locks in the same order as
+        // db_write to avoid deadlocking with a flush. Both are released before any
+        // flushed block is decoded, so a long scan doesn't hold up writes or flushes.
+        let snapshot = {
+            let block = shared_block.read().expect("RwLock poisoned");
+            let index = shared_index.read().expect("RwLock poisoned");
+            Snapshot::take(&dnf_statement, &block, &index)
+        };
+
+        // Raw points grouped by series are read a series at a time.
+        if dnf_statement.by_series && dnf_statement.aggregation.is_none() {
+            let result = select_series(&dnf_statement, &snapshot);
+            request.reply(SelectResult::Series(result));
+            continue;
+        }
+
+        // Aggregations over long ranges are answered from rollups where possible.
+        let result = match rollup::plan(&dnf_statement) {
+            Some(plan) => {
+                let mut summaries = select_rollup(&plan.rollup, plan.tier, &snapshot);
+                for edge in plan.edges.iter() {
+                    summaries.extend(summarize(select_raw(edge, &snapshot), plan.tier.width));
+                }
+                let aggregation = dnf_statement.aggregation.as_ref().unwrap();
+                aggregation.apply_summaries(summaries)
+            }
+            None => dnf_statement.aggregate(select_raw(&dnf_statement, &snapshot)),
+        };
+        match dnf_statement.by_series {
+            true => request.reply(SelectResult::Series(SeriesResult::from_records(result))),
+            false => request.reply(SelectResult::Records(result)),
+        }
+    }
+}
+
+// Snapshot Struct. Everything a statement reads, taken under the head and index locks
+// so that it can be scanned with neither held: the live block's matching records, the
+// frozen blocks, and the flushed blocks that may match (which stay mapped, and so
+// readable, even if compaction replaces them meanwhile), with their tombstones.
+// Selects run against it must only narrow the statement's time range.
+pub struct Snapshot {
+    head: Vec<Record>,
+    frozen: Vec<(String, Arc<Block>)>,
+    packed_blocks: Vec<PackedBlock>,
+    tombstones: HashMap<String, Vec<Tombstone>>,
+}
+impl Snapshot {
+    // Constructor.
+    pub fn take(statement: &Select, block: &Block, index: &BlockIndex) -> Self {
+        let mut head = statement.eval(block);
+        head.unpack(block);
+        let frozen = index.get_frozen().to_vec();
+        let packed_blocks = get_candidate_blocks(statement, index);
+        let filepaths = frozen
+            .iter()
+            .map(|x| x.0.clone())
+            .chain(packed_blocks.iter().map(|x| x.get_filepath()));
+        let mut tombstones = HashMap::new();
+        for filepath in filepaths {
+            let block_tombstones = index.get_tombstones(&filepath);
+            if !block_tombstones.is_empty() {
+                tombstones.insert(filepath, block_tombstones);
+            }
+        }
+        Snapshot {
+            head: head.into_vec(),
+            frozen,
+            packed_blocks,
+            tombstones,
+        }
+    }
+
+    // Get the live block's records within a select's time range.
+    fn get_head(&self, select: &Select) -> Vec<Record> {
+        let time_range = select.get_time_range();
+        self.head
+            .iter()
+            .filter(|x| time_range.contains(x.get_timestamp()))
+            .cloned()
+            .collect()
+    }
+
+    // Get the flushed blocks a select may match.
+    fn get_packed_blocks<'a>(
+        &'a self,
+        select: &'a Select,
+    ) -> impl Iterator<Item = &'a PackedBlock> {
+        self.packed_blocks
+            .iter()
+            .filter(move |x| select.may_match(x))
+    }
+
+    // Get the tombstones against a flushed (or frozen) block.
+    fn get_tombstones(&self, filepath: &str) -> &[Tombstone] {
+        self.tombstones.get(filepath).map_or(&[], |x| x.as_slice())
+    }
+
+    // Drop the records of a flushed (or frozen) block that its tombstones delete.
+    fn apply_tombstones(&self, records: &mut Vec<Record>, filepath: &str) {
+        let tombstones = self.get_tombstones(filepath);
+        if !tombstones.is_empty() {
+            records.retain(|r| !tombstones.iter().any(|t| t.matches(r)));
+        }
+    }
+}
+
+// Evaluate a select against the in-memory blocks (live and frozen), then every flushed
+// block that may match.
+fn select_raw(select: &Select, snapshot: &Snapshot) -> Vec<Record> {
+    let mut result = ResultSet::from_records(snapshot.get_head(select));
+    for (filepath, frozen_block) in snapshot.frozen.iter() {
+        let mut block_result = select.eval(&**frozen_block);
+        block_result.unpack(&**frozen_block);
+        snapshot.apply_tombstones(&mut block_result.data, filepath);
+        result.merge(block_result);
+    }
+    for packed_block in snapshot.get_packed_blocks(select) {
+        // Only the series that survive the predicate get decoded.
+        let mut block_result = select.eval(packed_block);
+        block_result.unpack(packed_block);
+        snapshot.apply_tombstones(&mut block_result.data, &packed_block.get_filepath());
+        result.merge(block_result);
+    }
+    result.into_vec()
+}
+
+// Evaluate a select into per-series results. Each block's series are read straight
+// from the block, then merged with the same series from other blocks.
+fn select_series(select: &Select, snapshot: &Snapshot) -> Vec<SeriesResult> {
+    let mut results = SeriesResult::from_records(snapshot.get_head(select));
+    for (filepath, frozen_block) in snapshot.frozen.iter() {
+        let mut block_result = select.eval(&**frozen_block);
+        let tombstones = snapshot.get_tombstones(filepath).to_vec();
+        if !tombstones.is_empty() {
+            block_result.add_filter(Box::new(move |r| !tombstones.iter().any(|t| t.matches(r))));
+        }
+        results.append(&mut block_result.to_series(&**frozen_block));
+    }
+    for packed_block in snapshot.get_packed_blocks(select) {
+        let mut block_result = select.eval(packed_block);
+        let tombstones = snapshot
+            .get_tombstones(&packed_block.get_filepath())
+            .to_vec();
+        if !tombstones.is_empty() {
+            block_result.add_filter(Box::new(move |r| !tombstones.iter().any(|t| t.matches(r))));
+        }
+        results.append(&mut block_result.to_series(packed_block));
     }
+    SeriesResult::merge_all(results)
+}
+
+// Evaluate a select into summaries at a tier's granularity, reading each flushed
+// block's rollup where it has one, and summarizing raw points otherwise. A point in
+// more than one block (e.g. replayed from the write-ahead log before compaction) would
+// be counted once per rollup, so only blocks overlapping no other block use theirs;
+// the rest are read raw and merged, which keeps each point once.
+fn select_rollup(select: &Select, tier: &Tier, snapshot: &Snapshot) -> Vec<Record> {
+    let head = snapshot.get_head(select);
+    let packed_blocks: Vec<&PackedBlock> = snapshot.get_packed_blocks(select).collect();
+
+    // Get the time range of every block read.
+    let mut ranges: Vec<(DateTime<Utc>, DateTime<Utc>)> = packed_blocks
+        .iter()
+        .map(|x| (x.get_start_timestamp(), x.get_end_timestamp()))
+        .chain(
+            snapshot
+                .frozen
+                .iter()
+                .map(|x| (x.1.start_timestamp, x.1.end_timestamp)),
+        )
+        .filter_map(|x| Some((x.0?, x.1?)))
+        .collect();
+    let head_timestamps = head.iter().map(|x| x.get_timestamp());
+    if let (Some(start), Some(end)) = (head_timestamps.clone().min(), head_timestamps.max()) {
+        ranges.push((start, end));
+    }
+    let overlaps_other = |i: usize| {
+        let (start, end) = ranges[i];
+        ranges
+            .iter()
+            .enumerate()
+            .any(|(j, x)| j != i && x.0 <= end && start <= x.1)
+    };
+
+    let mut result = ResultSet::from_records(head);
+    for (filepath, frozen_block) in snapshot.frozen.iter() {
+        let mut block_result = select.eval(&**frozen_block);
+        block_result.unpack(&**frozen_block);
+        snapshot.apply_tombstones(&mut block_result.data, filepath);
+        result.merge(block_result);
+    }
+    let mut summaries = vec![];
+    for (i, packed_block) in packed_blocks.into_iter().enumerate() {
+        // Rollups still include deleted points, so blocks with tombstones are read raw.
+        let rollup_filename = tier.get_path(&packed_block.get_filepath());
+        let use_rollup = snapshot
+            .get_tombstones(&packed_block.get_filepath())
+            .is_empty()
+            && !overlaps_other(i);
+        let rollup_block = match use_rollup {
+            true => PackedBlock::from_filepath(rollup_filename).ok(),
+            false => None,
+        };
+        match rollup_block {
+            Some(rollup_block) => {
+                let mut block_result = select.eval(&rollup_block);
+                block_result.unpack(&rollup_block);
+                summaries.append(&mut block_result.into_vec());
+            }
+            None => {
+                let mut block_result = select.eval(packed_block);
+                block_result.unpack(packed_block);
+                snapshot.apply_tombstones(&mut block_result.data, &packed_block.get_filepath());
+                result.merge(block_result);
+            }
+        }
+    }
+    summaries.append(&mut summarize(result.into_vec(), tier.width));
+    summaries
+}
+
+// Get the flushed blocks a select may match. Blocks outside its time range are skipped
+// via the index, before they are mapped; the rest are checked by may_match.
+fn get_candidate_blocks(select: &Select, index: &BlockIndex) -> Vec<PackedBlock> {
+    index
+        .get_packed_blocks_in_range(&select.get_time_range())
+        .into_iter()
+        .filter(|x| select.may_match(x))
+        .collect()
 }
 
 // Ingests a write operation.
 fn db_write(
-    write_rx: Receiver<Record>,
+    write_rx: Receiver<WriteRequest>,
     shared_block: Arc<RwLock<Block>>,
     shared_index: Arc<RwLock<BlockIndex>>,
+    mut wal: Wal,
+    policy: FlushPolicy,
+    flush_tx: Sender<FlushJob>,
 ) {
-    // Counter for number of writes. NOTE: Temporary.
-    let mut counter = 0;
-
-    // Receive write operations from the server
-    for received in write_rx {
-        // Get key and block.
-        let key: String = received.get_key();
+    let mut head = HeadState::new();
+
+    // Receive write operations from the server, waking up regularly to check whether
+    // the block is due a flush even if nothing is written.
+    loop {
+        let request = match write_rx.recv_timeout(FLUSH_CHECK_INTERVAL) {
+            Ok(request) => Some(request),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                // Every queued write has been applied; hand what's left to the flusher,
+                // which finishes before the database shuts down.
+                let mut block = shared_block.write().expect("RwLock poisoned");
+                freeze_block(&shared_index, &mut block, &mut wal, &mut head, &flush_tx);
+                break;
+            }
+        };
         let mut block = shared_block.write().expect("RwLock poisoned");
+        if let Some(request) = request {
+            // A point from a later window starts a new block.
+            if let Mutation::Write(record) = &request.mutation {
+                if policy.is_cut_by(&block, record.get_timestamp()) {
+                    freeze_block(&shared_index, &mut block, &mut wal, &mut head, &flush_tx);
+                }
+            }
 
-        // TODO: Shouldn't this be in the Block impl?
-        // Check if this series exists in the block
-        if let Some(id) = block.key_map.get(&key) {
-            block.storage[*id].insert(received.clone());
+            // Apply the mutation, and log it before acknowledging it.
+            let result = match &request.mutation {
+                // Rejected records are never logged.
+                Mutation::Write(record) => block
+                    .insert(record.clone())
+                    .and_then(|_| wal.append(&request.mutation)),
+                Mutation::Delete(delete) => {
+                    apply_delete(&shared_index, &mut block, &mut wal, delete)
+                }
+            };
+            if result.is_ok() {
+                head.add_write();
+            }
+            request.reply(result);
         }
-        // Key does not exist in the block
-        else {
-            let id = block.storage.len();
-            block
-                .storage
-                .push(Series::new(id.clone(), received.clone()));
-            block.id_map.push(key.clone());
-            block.key_map.insert(key, id.clone());
-
-            // Insert label key-value pairs and metrics into the fst
-            let mut labels = received.get_labels();
-            labels.append(&mut received.get_metrics());
-            for label in labels {
-                match block.index.get_mut(&label) {
-                    Some(rb) => {
-                        rb.add(id as u32);
-                    }
-                    None => {
-                        let mut new_rb = Bitmap::create();
-                        new_rb.add(id as u32);
-                        block.index.insert(label, new_rb);
-                    }
+
+        if policy.is_due(&block, &head, Utc::now()) {
+            freeze_block(&shared_index, &mut block, &mut wal, &mut head, &flush_tx);
+        }
+    }
+}
+
+// Apply a delete: flushed points are tombstoned, the delete is logged, then points in
+// memory go. If it can't be logged, the tombstone is dropped again, so a delete the
+// client is told failed isn't applied, now or on replay.
+fn apply_delete(
+    shared_index: &Arc<RwLock<BlockIndex>>,
+    block: &mut Block,
+    wal: &mut Wal,
+    delete: &Delete,
+) -> Result<(), Error> {
+    {
+        let mut index = shared_index.write().expect("RwLock poisoned");
+        let id = index.add_tombstone(delete.clone())?;
+        if let Err(e) = wal.append(&Mutation::Delete(delete.clone())) {
+            if let Some(id) = id {
+                if let Err(e) = index.remove_tombstone(id) {
+                    println!("Failed to drop tombstone {}: {}", id, e);
                 }
             }
+            return Err(e);
         }
+    }
+    block.delete(delete)
+}
 
-        // Update block timeranges.
-        if block.start_timestamp.is_none()
-            || block.start_timestamp.unwrap() > received.clone().get_timestamp()
-        {
-            block.start_timestamp = Some(received.clone().get_timestamp().clone());
+// Freeze the in-memory block and hand it to the flusher, along with the WAL backing it,
+// so writes can carry on into a fresh block. If the WAL can't be sealed, the block is
+// kept, and it is tried again when next due.
+fn freeze_block(
+    shared_index: &Arc<RwLock<BlockIndex>>,
+    block: &mut RwLockWriteGuard<Block>,
+    wal: &mut Wal,
+    head: &mut HeadState,
+    flush_tx: &Sender<FlushJob>,
+) {
+    // With no points to write, only deletes were logged, and those are already
+    // tombstoned in the index.
+    if block.get_start_timestamp().is_none() {
+        match wal.truncate() {
+            Ok(_) => *head = HeadState::new(),
+            Err(e) => println!("Failed to truncate write-ahead log: {}", e),
         }
-        if block.end_timestamp.is_none()
-            || block.end_timestamp.unwrap() < received.clone().get_timestamp()
-        {
-            block.end_timestamp = Some(received.clone().get_timestamp().clone());
+        return;
+    }
+
+    let segments = match wal.seal() {
+        Ok(segments) => segments,
+        Err(e) => {
+            println!("Failed to freeze block: {}", e);
+            return;
         }
+    };
+    let (filepath, block) = shared_index
+        .write()
+        .expect("RwLock poisoned")
+        .freeze(mem::replace(&mut **block, Block::new()));
+    *head = HeadState::new();
+    let job = FlushJob {
+        filepath,
+        block,
+        segments,
+    };
+    if flush_tx.send(job).is_err() {
+        println!("Block flusher is not running; frozen block kept in memory.");
+    }
+}
 
-        // After write, consider flushing.
-        counter = counter + 1;
-        if counter % FLUSH_FREQUENCY == 0 {
-            shared_index
-                .write()
-                .expect("RwLock poisoined")
-                .update(&mut block);
+// Write frozen blocks to disk in the order they were frozen, then drop the WAL segments
+// backing them. A block that fails to write stays frozen (and readable), and is retried;
+// once shutting down, only a few times before the error is returned.
+fn db_flush(
+    flush_rx: Receiver<FlushJob>,
+    shared_index: Arc<RwLock<BlockIndex>>,
+) -> Result<(), Error> {
+    for job in flush_rx.iter() {
+        let mut retries = 0;
+        while let Err(e) = flush_frozen(&shared_index, &job.filepath, &job.block) {
+            // Once shutting down, give up rather than hang; the block's points are still
+            // in its write-ahead log segments, and are replayed on the next start.
+            if signal::is_shutting_down() {
+                if retries == SHUTDOWN_FLUSH_RETRIES {
+                    return Err(e);
+                }
+                retries += 1;
+            }
+            println!("Failed to flush block, retrying: {}", e);
+            thread::sleep(FLUSH_RETRY_INTERVAL);
+        }
+        if let Err(e) = wal::remove_segments(&job.segments) {
+            println!("Failed to remove write-ahead log segments: {}", e);
         }
     }
+    Ok(())
 }
 
-// Create block and open database.
-pub fn db_open(read_rx: Receiver<SelectRequest>, write_rx: Receiver<Record>) {
+// Create block and open database. Returns once the request channels are closed and
+// drained, and every block in memory has been flushed, or fails if one couldn't be.
+pub fn db_open(
+    config: &Config,
+    read_rx: Receiver<SelectRequest>,
+    write_rx: Receiver<WriteRequest>,
+) -> Result<(), Error> {
     // Create an in-memory index, populated from disk.
-    fs::create_dir_all(format!("{}", dotenv::var("DATAROOT").unwrap()))
-        .expect("ERROR: issue creating data dir.");
-    let index = BlockIndex::from_disk(format!("{}/index.rdb", dotenv::var("DATAROOT").unwrap()));
-
-    // Alternatively, populate it manually from the blocks in the dir.
-    // let mut index = BlockIndex::new();
-    // index.populate_manually();
+    let dataroot = &config.data_dir;
+    fs::create_dir_all(dataroot)?;
+    let mut index = BlockIndex::from_disk(dataroot)?;
+
+    // Make sure the index and the block dir agree, in case we crashed mid-write.
+    index.reconcile()?;
+
+    // Recover any mutations that were logged but never flushed. Deletes were already
+    // tombstoned in the index, so only the live block needs them reapplied.
+    let wal_filename = format!("{}/wal.rdb", dataroot);
+    let mut block = Block::new();
+    let mutations = Wal::replay(wal_filename.clone())?;
+    if !mutations.is_empty() {
+        println!(
+            "Replaying {} mutations from write-ahead log.",
+            mutations.len()
+        );
+    }
+    for mutation in mutations {
+        match mutation {
+            Mutation::Write(record) => {
+                if let Err(e) = block.insert(record) {
+                    println!("Skipping logged record: {}", e);
+                }
+            }
+            Mutation::Delete(delete) => {
+                if let Err(e) = block.delete(&delete) {
+                    println!("Skipping logged delete: {}", e);
+                }
+            }
+        }
+    }
+    let wal = Wal::open(wal_filename)?;
 
     // Create shared in-memory storage structures.
-    let shared_block = Arc::new(RwLock::new(Block::new()));
+    let shared_block = Arc::new(RwLock::new(block));
     let shared_index = Arc::new(RwLock::new(index));
 
     // Set up separate r/w threads so that read operations don't block writes
-    let read_block = Arc::clone(&shared_block);
-    let read_index = Arc::clone(&shared_index);
-    let read_thr = thread::spawn(move || db_read(read_rx, read_block, read_index));
+    let read_rx = Arc::new(Mutex::new(read_rx));
+    let mut read_thrs = vec![];
+    for _ in 0..config.reader_threads {
+        let read_rx = Arc::clone(&read_rx);
+        let read_block = Arc::clone(&shared_block);
+        let read_index = Arc::clone(&shared_index);
+        read_thrs.push(thread::spawn(move || {
+            db_read(read_rx, read_block, read_index)
+        }));
+    }
+
+    // Frozen blocks are written out by their own thread, so a flush never stalls writes.
+    let (flush_tx, flush_rx) = mpsc::channel();
+    let flush_index = Arc::clone(&shared_index);
+    let flush_thr = thread::spawn(move || db_flush(flush_rx, flush_index));
 
     let write_block = Arc::clone(&shared_block);
     let write_index = Arc::clone(&shared_index);
-    let write_thr = thread::spawn(move || db_write(write_rx, write_block, write_index));
+    let policy = FlushPolicy::from_config(config);
+    let write_thr =
+        thread::spawn(move || db_write(write_rx, write_block, write_index, wal, policy, flush_tx));
+
+    // Merge flushed blocks in the background, until told to stop.
+    let (compact_stop_tx, compact_stop_rx) = mpsc::channel();
+    let compact_index = Arc::clone(&shared_index);
+    let partition_millis = config.get_window_millis();
+    let compact_thr =
+        thread::spawn(move || db_compact(compact_index, partition_millis, compact_stop_rx));
+
+    // Likewise, drop expired data.
+    let (retain_stop_tx, retain_stop_rx) = mpsc::channel();
+    let retain_index = Arc::clone(&shared_index);
+    let retention_policy = RetentionPolicy::from_config(config);
+    let retain_thr =
+        thread::spawn(move || db_retain(retain_index, retention_policy, retain_stop_rx));
 
     // Join threads.
-    read_thr.join().unwrap();
-    write_thr.join().unwrap();
+    for read_thr in read_thrs {
+        if read_thr.join().is_err() {
+            println!("Database reader panicked.");
+        }
+    }
+    if write_thr.join().is_err() {
+        println!("Database writer panicked.");
+    }
+    let result = match flush_thr.join() {
+        Ok(result) => result,
+        Err(_) => {
+            println!("Database flusher panicked.");
+            Ok(())
+        }
+    };
+
+    // Stop the background threads, waiting out any pass they are in the middle of.
+    drop(compact_stop_tx);
+    drop(retain_stop_tx);
+    if compact_thr.join().is_err() {
+        println!("Database compactor panicked.");
+    }
+    if retain_thr.join().is_err() {
+        println!("Database retainer panicked.");
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::operators::{
+        select::{Condition, Conditions, Op, Type},
+        Select,
+    };
+    use crate::server::test_util::{make_record, pack_block};
+    use chrono::NaiveDateTime;
+    use std::env;
+
+    fn make_select(hostname: &str) -> Select {
+        let data = format!(
+            r#"{{
+                "name": "s",
+                "predicate": {{
+                    "name": "p",
+                    "condition": {{
+                        "Leaf": {{ "lhs": {{"LabelKey": "hostname"}}, "rhs": {{"LabelValue": "{}"}}, "op": "Eq" }}
+                    }}
+                }}
+            }}"#,
+            hostname
+        );
+        serde_json::from_str(&data).unwrap()
+    }
+
+    // Snapshot a block and index, and select from them.
+    fn select_raw_now(select: &Select, block: &Block, index: &BlockIndex) -> Vec<Record> {
+        select_raw(select, &Snapshot::take(select, block, index))
+    }
+
+    fn select_series_now(select: &Select, block: &Block, index: &BlockIndex) -> Vec<SeriesResult> {
+        select_series(select, &Snapshot::take(select, block, index))
+    }
+
+    #[test]
+    fn test_select_across_packed_block() {
+        // Write an older block to disk.
+        let mut old_block = Block::new();
+        old_block.insert(make_record("host_0", 1.0, 10)).unwrap();
+        old_block.insert(make_record("host_0", 3.0, 30)).unwrap();
+        old_block.insert(make_record("host_1", 9.0, 20)).unwrap();
+        let packed_block = pack_block(&old_block);
+
+        // Keep a newer in-memory block.
+        let mut new_block = Block::new();
+        new_block.insert(make_record("host_0", 2.0, 20)).unwrap();
+        new_block.insert(make_record("host_0", 4.0, 40)).unwrap();
+
+        // The packed block's index is used for pruning.
+        assert!(make_select("host_0").may_match(&packed_block));
+        assert!(!make_select("host_2").may_match(&packed_block));
+
+        // Results from both blocks are merged in timestamp order.
+        let select = make_select("host_0");
+        let mut result = select.eval(&new_block);
+        result.unpack(&new_block);
+        let mut packed_result = select.eval(&packed_block);
+        packed_result.unpack(&packed_block);
+        result.merge(packed_result);
+
+        let usages: Vec<f64> = result
+            .into_vec()
+            .iter()
+            .map(|r| *r.get_metric("usage_user".to_string()).unwrap())
+            .collect();
+        assert_eq!(usages, vec![1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn test_select_time_range() {
+        let mut old_block = Block::new();
+        old_block.insert(make_record("host_0", 1.0, 10)).unwrap();
+        old_block.insert(make_record("host_0", 2.0, 20)).unwrap();
+        let packed_block = pack_block(&old_block);
+
+        let mut new_block = Block::new();
+        new_block.insert(make_record("host_0", 3.0, 30)).unwrap();
+        new_block.insert(make_record("host_0", 4.0, 40)).unwrap();
+        new_block.insert(make_record("host_0", 5.0, 50)).unwrap();
+
+        // Blocks entirely outside the range are pruned.
+        let mut select = make_select("host_0");
+        select.start = Some(DateTime::from_utc(
+            NaiveDateTime::from_timestamp(25, 0),
+            Utc,
+        ));
+        assert!(!select.may_match(&packed_block));
+        select.start = Some(DateTime::from_utc(
+            NaiveDateTime::from_timestamp(20, 0),
+            Utc,
+        ));
+        assert!(select.may_match(&packed_block));
+
+        // Records outside the range are trimmed on unpack.
+        select.end = Some(DateTime::from_utc(
+            NaiveDateTime::from_timestamp(40, 0),
+            Utc,
+        ));
+        let mut result = select.eval(&new_block);
+        result.unpack(&new_block);
+        let mut packed_result = select.eval(&packed_block);
+        packed_result.unpack(&packed_block);
+        result.merge(packed_result);
+
+        let usages: Vec<f64> = result
+            .into_vec()
+            .iter()
+            .map(|r| *r.get_metric("usage_user".to_string()).unwrap())
+            .collect();
+        assert_eq!(usages, vec![2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn test_select_out_of_order() {
+        // A late point is stored before the newer one, so it isn't cut off by the end
+        // of the range.
+        let mut block = Block::new();
+        block.insert(make_record("host_0", 5.0, 50)).unwrap();
+        block.insert(make_record("host_0", 1.0, 10)).unwrap();
+        let mut select = make_select("host_0");
+        select.end = Some(DateTime::from_utc(
+            NaiveDateTime::from_timestamp(20, 0),
+            Utc,
+        ));
+        let mut result = select.eval(&block);
+        result.unpack(&block);
+        let usages: Vec<f64> = result
+            .into_vec()
+            .iter()
+            .map(|r| *r.get_metric("usage_user".to_string()).unwrap())
+            .collect();
+        assert_eq!(usages, vec![1.0]);
+        let series = select.eval(&block).to_series(&block);
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0].points.len(), 1);
+    }
+
+    #[test]
+    fn test_packed_block_lazy_access() {
+        let mut block = Block::new();
+        block.insert(make_record("host_0", 1.0, 10)).unwrap();
+        block.insert(make_record("host_1", 2.0, 20)).unwrap();
+        block.insert(make_record("host_1", 3.0, 30)).unwrap();
+        let packed_block = pack_block(&block);
+
+        // Bitmaps and series are decoded individually, straight from the mapped file.
+        assert!(packed_block.contains_key("hostname=host_1"));
+        assert_eq!(
+            packed_block.search_index("hostname=host_1".to_string()),
+            block.search_index("hostname=host_1".to_string())
+        );
+        assert_eq!(
+            packed_block.search_index("hostname=host_2".to_string()),
+            None
+        );
+        for id in 0..block.get_storage().len() as u32 {
+            assert_eq!(
+                packed_block.get_series_records(id).collect::<Vec<_>>(),
+                block.get_series_records(id).collect::<Vec<_>>()
+            );
+        }
+
+        // The whole block can still be unpacked.
+        let unpacked_block = packed_block.unpack().unwrap();
+        assert_eq!(
+            unpacked_block.get_storage().len(),
+            block.get_storage().len()
+        );
+    }
+
+    #[test]
+    fn test_series_variable_order() {
+        // Variables go into a fresh HashMap per record, so each iterates in its own order.
+        let make_wide_record = |secs: i64| {
+            let mut variables = HashMap::new();
+            for (i, name) in ["a", "b", "c", "d", "e", "f"].iter().enumerate() {
+                variables.insert(name.to_string(), (secs * 10 + i as i64) as f64);
+            }
+            let timestamp = DateTime::from_utc(NaiveDateTime::from_timestamp(secs, 0), Utc);
+            Record::new("cpu".to_string(), HashMap::new(), variables, timestamp)
+        };
+        let mut block = Block::new();
+        for secs in 1..20 {
+            block.insert(make_wide_record(secs)).unwrap();
+        }
+        let packed_block = pack_block(&block);
+
+        for records in [
+            block.get_series_records(0).collect::<Vec<_>>(),
+            packed_block.get_series_records(0).collect::<Vec<_>>(),
+        ] {
+            assert_eq!(records.len(), 19);
+            for record in records.iter() {
+                let expected = make_wide_record(record.get_timestamp().timestamp());
+                assert_eq!(
+                    record.get_populated_variables(),
+                    expected.get_populated_variables()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_storage_errors() {
+        // Missing block files and unreadable indexes are reported, not panicked on.
+        let dir = env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let filepath = dir.join("missing.rdb").to_str().unwrap().to_string();
+        match PackedBlock::from_filepath(filepath) {
+            Err(Error::Io(_)) => (),
+            _ => panic!("missing block was not reported"),
+        }
+        let index_path = dir.join("index.rdb");
+        let index = BlockIndex::from_disk(dir.to_str().unwrap()).unwrap();
+        assert!(index.get_packed_blocks().is_empty());
+        fs::write(&index_path, b"\xff\xff\xff\xff\xff\xff\xff\xff\xff").unwrap();
+        match BlockIndex::from_disk(dir.to_str().unwrap()) {
+            Err(Error::Decode(_)) => (),
+            _ => panic!("corrupt index was not reported"),
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_timestamps() {
+        // Timestamps out of range are reported as corrupt blocks, not panicked on.
+        let mut block = Block::new();
+        block.insert(make_record("host_0", 1.0, 10)).unwrap();
+        let mut bytes = block.to_bytes();
+        let range = BlockFileReader::new(&bytes)
+            .unwrap()
+            .section_range(Section::StartTimestamp);
+        bytes[range].copy_from_slice(&i64::MAX.to_le_bytes());
+        match Block::from_bytes(&bytes) {
+            Err(Error::CorruptBlock(_)) => (),
+            _ => panic!("corrupt block timestamp was not reported"),
+        }
+
+        // Likewise for a record's timestamp in a series.
+        let series = &block.storage[0];
+        series.records.write().unwrap()[0].timestamp = i64::MAX;
+        match Series::from_bytes(&series.into_bytes()) {
+            Err(Error::CorruptBlock(_)) => (),
+            _ => panic!("corrupt record timestamp was not reported"),
+        }
+        assert!(series.get_records().is_err());
+    }
+
+    #[test]
+    fn test_packed_blocks_in_range() {
+        let dir = env::temp_dir().join(Uuid::new_v4().to_string());
+        let dataroot = dir.to_str().unwrap();
+        let at = |secs: i64| DateTime::from_utc(NaiveDateTime::from_timestamp(secs, 0), Utc);
+        let mut index = BlockIndex::new(dataroot);
+        for (start, end) in [(10, 20), (30, 40)].iter() {
+            let mut block = Block::new();
+            block.insert(make_record("host_0", 1.0, *start)).unwrap();
+            block.insert(make_record("host_0", 1.0, *end)).unwrap();
+            let filepath = write_block(dataroot, &block.to_bytes()).unwrap();
+            index.insert(start * 1000, end * 1000, filepath);
+        }
+        index.persist().unwrap();
+
+        // Blocks are skipped if they start after the range or end before it.
+        let get_starts = |index: &BlockIndex, start: Option<i64>, end: Option<i64>| {
+            let time_range = TimeRange {
+                start: start.map(at),
+                end: end.map(at),
+            };
+            index
+                .get_packed_blocks_in_range(&time_range)
+                .iter()
+                .map(|x| x.get_start_timestamp().unwrap().timestamp())
+                .collect::<Vec<i64>>()
+        };
+        assert_eq!(get_starts(&index, None, None), vec![10, 30]);
+        assert_eq!(get_starts(&index, Some(20), Some(30)), vec![10, 30]);
+        assert_eq!(get_starts(&index, Some(21), None), vec![30]);
+        assert_eq!(get_starts(&index, None, Some(29)), vec![10]);
+        assert!(get_starts(&index, Some(41), None).is_empty());
+
+        // Until reconciled, end timestamps are unknown and blocks are mapped to check.
+        let mut index = BlockIndex::from_disk(dataroot).unwrap();
+        assert_eq!(get_starts(&index, Some(21), None), vec![10, 30]);
+        index.reconcile().unwrap();
+        assert_eq!(get_starts(&index, Some(21), None), vec![30]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let dir = env::temp_dir().join(Uuid::new_v4().to_string());
+        let dataroot = dir.to_str().unwrap();
+        let mut block = Block::new();
+        block.set_sequence(7);
+        block.insert(make_record("host_0", 1.0, 10)).unwrap();
+        let key = block.get_start_timestamp().unwrap().timestamp_millis();
+
+        // One good block, one truncated after indexing, one never indexed, and a temp
+        // file from an interrupted write.
+        let good = write_block(dataroot, &block.to_bytes()).unwrap();
+        let truncated = write_block(dataroot, &block.to_bytes()).unwrap();
+        let orphan = write_block(dataroot, &block.to_bytes()).unwrap();
+        let bytes = fs::read(&truncated).unwrap();
+        fs::write(&truncated, &bytes[..bytes.len() / 2]).unwrap();
+        let temp = format!("{}{}", good, TEMP_SUFFIX);
+        fs::write(&temp, b"partial").unwrap();
+        let mut index = BlockIndex::new(dataroot);
+        index.insert(key, key, good.clone());
+        index.insert(key, key, truncated.clone());
+        index.tombstones.push(Tombstone {
+            id: 0,
+            delete: Delete {
+                predicate: make_select("host_0").predicate,
+                start: None,
+                end: None,
+            },
+            filepaths: vec![truncated.clone()],
+        });
+        index.persist().unwrap();
+
+        // Only the good block is kept, and the rest is reported rather than panicked on.
+        // New blocks are flushed after it.
+        let mut index = BlockIndex::from_disk(dataroot).unwrap();
+        index.reconcile().unwrap();
+        assert_eq!(index.freeze(Block::new()).1.get_sequence(), 8);
+        let index = BlockIndex::from_disk(dataroot).unwrap();
+        let filepaths: Vec<String> = index
+            .get_packed_blocks()
+            .iter()
+            .map(|x| x.get_filepath())
+            .collect();
+        assert_eq!(filepaths, vec![good]);
+        assert!(index.get_tombstoned_filepaths().is_empty());
+        assert!(!Path::new(&temp).exists());
+        let orphaned_dir = dir.join("orphaned");
+        assert!(orphaned_dir.join(get_filename(&orphan)).exists());
+        assert!(orphaned_dir.join(get_filename(&truncated)).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_flush_policy() {
+        let hour = 60 * 60;
+        let policy = FlushPolicy {
+            count: 100,
+            bytes: Some(64),
+            age_secs: None,
+            span_secs: Some(hour as u64),
+            idle_secs: None,
+            window_millis: 2 * hour * 1000,
+        };
+        let mut block = Block::new();
+        let mut head = HeadState::new();
+        let now = |secs: i64| DateTime::from_utc(NaiveDateTime::from_timestamp(secs, 0), Utc);
+        assert!(!policy.is_due(&block, &head, now(0)));
+        block.insert(make_record("host_0", 1.0, 10)).unwrap();
+        head.add_write();
+        assert!(!policy.is_due(&block, &head, now(20)));
+        head.writes = 100;
+        assert!(policy.is_due(&block, &head, now(20)));
+        head.writes = 1;
+
+        // The block is cut by a point from a later window, but not an earlier one.
+        assert!(!policy.is_cut_by(&block, now(2 * hour - 1)));
+        assert!(policy.is_cut_by(&block, now(2 * hour)));
+        assert!(!policy.is_cut_by(&block, now(-10)));
+
+        // Once its window is over, an idle block is flushed.
+        assert!(!policy.is_due(&block, &head, now(2 * hour)));
+        head.last_write -= FLUSH_CHECK_INTERVAL;
+        assert!(policy.is_due(&block, &head, now(2 * hour)));
+
+        // So is one spanning too long.
+        let mut block = Block::new();
+        let head = HeadState::new();
+        block.insert(make_record("host_0", 1.0, 10)).unwrap();
+        block.insert(make_record("host_0", 1.0, hour + 10)).unwrap();
+        assert!(policy.is_due(&block, &head, now(hour + 20)));
+
+        // The block grows with each new point, not just each new series.
+        let mut block = Block::new();
+        for secs in 10..20 {
+            block.insert(make_record("host_0", 1.0, secs)).unwrap();
+        }
+        assert!(policy.is_due(&block, &head, now(20)));
+    }
+
+    #[test]
+    fn test_flush_triggers() {
+        let hour = 60 * 60;
+        let policy = FlushPolicy {
+            count: 100,
+            bytes: None,
+            age_secs: None,
+            span_secs: None,
+            idle_secs: None,
+            window_millis: hour * 1000,
+        };
+        let now = |secs: i64| DateTime::from_utc(NaiveDateTime::from_timestamp(secs, 0), Utc);
+        let mut block = Block::new();
+        block.insert(make_record("host_0", 1.0, hour + 10)).unwrap();
+        let mut head = HeadState::new();
+        head.add_write();
+        assert!(!policy.is_due(&block, &head, now(hour + 20)));
+
+        // The window cut falls exactly on the window's end, and windows before the
+        // epoch are cut the same way.
+        assert!(!policy.is_cut_by(&block, now(2 * hour - 1)));
+        assert!(policy.is_cut_by(&block, now(2 * hour)));
+        assert!(!policy.is_cut_by(&block, now(hour)));
+        assert!(!policy.is_cut_by(&Block::new(), now(2 * hour)));
+        let mut early = Block::new();
+        early.insert(make_record("host_0", 1.0, -hour)).unwrap();
+        assert!(!policy.is_cut_by(&early, now(-1)));
+        assert!(policy.is_cut_by(&early, now(0)));
+
+        // The block is due once it's been open for age_secs.
+        let aged = FlushPolicy {
+            age_secs: Some(60),
+            ..policy
+        };
+        assert!(!aged.is_due(&block, &head, now(hour + 20)));
+        let mut old_head = HeadState::new();
+        old_head.add_write();
+        old_head.opened -= Duration::from_secs(60);
+        assert!(aged.is_due(&block, &old_head, now(hour + 20)));
+        assert!(!policy.is_due(&block, &old_head, now(hour + 20)));
+
+        // Or once it holds bytes, counting every point's timestamp and values.
+        let sized = FlushPolicy {
+            bytes: Some(block.get_size() as u64 + 16),
+            ..aged
+        };
+        assert!(!sized.is_due(&block, &head, now(hour + 20)));
+        block.insert(make_record("host_0", 1.0, hour + 20)).unwrap();
+        assert!(sized.is_due(&block, &head, now(hour + 30)));
+
+        // Or once it's gone idle_secs without writes.
+        let idle = FlushPolicy {
+            idle_secs: Some(60),
+            ..policy
+        };
+        assert!(!idle.is_due(&block, &head, now(hour + 30)));
+        head.last_write -= Duration::from_secs(60);
+        assert!(idle.is_due(&block, &head, now(hour + 30)));
+    }
+
+    #[test]
+    fn test_schema_mismatch() {
+        let timestamp = DateTime::from_utc(NaiveDateTime::from_timestamp(10, 0), Utc);
+        let make_record_with = |names: &[&str]| {
+            let variables = names.iter().map(|x| (x.to_string(), 1.0)).collect();
+            Record::new("cpu".to_string(), HashMap::new(), variables, timestamp)
+        };
+
+        // Variables that concatenate the same are still different series.
+        let mut block = Block::new();
+        block.insert(make_record_with(&["ab", "c"])).unwrap();
+        block.insert(make_record_with(&["a", "bc"])).unwrap();
+        assert_eq!(block.get_series_records(0).count(), 1);
+        assert_eq!(block.get_series_records(1).count(), 1);
+
+        // A series rejects records with other variables.
+        let series = Series::new(0, make_record_with(&["ab", "c"])).unwrap();
+        for names in [&["a", "bc"][..], &["ab"], &["ab", "c", "d"]].iter() {
+            match series.insert(make_record_with(names)) {
+                Err(Error::SchemaMismatch(_)) => (),
+                _ => panic!("mismatched record was not rejected"),
+            }
+        }
+        assert_eq!(series.get_records().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_select_from_rollups() {
+        // Flush a block spanning a few hours into its own data root.
+        let dataroot = env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(dataroot.join("blocks")).unwrap();
+        let filepath = dataroot.join("blocks").join("block.rdb");
+        let filepath = filepath.to_str().unwrap().to_string();
+        let mut block = Block::new();
+        for secs in (0..4 * 60 * 60).step_by(90) {
+            block
+                .insert(make_record("host_0", (secs % 7) as f64, secs))
+                .unwrap();
+        }
+        fs::write(&filepath, block.to_bytes()).unwrap();
+        write_rollups(&block, &filepath).unwrap();
+        let mut index = BlockIndex::new(dataroot.to_str().unwrap());
+        index.insert(
+            block.get_start_timestamp().unwrap().timestamp_millis(),
+            block.end_timestamp.unwrap().timestamp_millis(),
+            filepath,
+        );
+
+        // Keep some newer points in memory.
+        let mut new_block = Block::new();
+        new_block
+            .insert(make_record("host_0", 9.0, 4 * 60 * 60))
+            .unwrap();
+        new_block
+            .insert(make_record("host_0", 3.0, 5 * 60 * 60))
+            .unwrap();
+
+        // An unaligned hourly mean reads rollups, edges and memory, and matches the raw answer.
+        let mut select = make_select("host_0");
+        select.start = Some(DateTime::from_utc(
+            NaiveDateTime::from_timestamp(1000, 0),
+            Utc,
+        ));
+        select.end = Some(DateTime::from_utc(
+            NaiveDateTime::from_timestamp(5 * 60 * 60 + 10, 0),
+            Utc,
+        ));
+        select.aggregation = Some(
+            serde_json::from_str(
+                r#"{ "group_by_time": 3600000, "functions": { "usage_user": "Mean" } }"#,
+            )
+            .unwrap(),
+        );
+        let plan = rollup::plan(&select).unwrap();
+        assert_eq!(plan.tier.name, "1h");
+        let snapshot = Snapshot::take(&select, &new_block, &index);
+        let mut summaries = select_rollup(&plan.rollup, plan.tier, &snapshot);
+        for edge in plan.edges.iter() {
+            summaries.extend(summarize(select_raw(edge, &snapshot), plan.tier.width));
+        }
+        let aggregation = select.aggregation.as_ref().unwrap();
+        let expected = select.aggregate(select_raw(&select, &snapshot));
+        let actual = aggregation.apply_summaries(summaries);
+        assert_eq!(actual.len(), 6);
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert_eq!(a.get_timestamp(), e.get_timestamp());
+            let a = a.get_metric("usage_user".to_string()).unwrap();
+            let e = e.get_metric("usage_user".to_string()).unwrap();
+            assert!((a - e).abs() < 1e-9);
+        }
+        fs::remove_dir_all(dataroot).unwrap();
+    }
+
+    #[test]
+    fn test_rollups_overlapping_blocks() {
+        // Two blocks share the points of an hour, as after a replay before compaction;
+        // a third overlaps neither.
+        let dataroot = env::temp_dir().join(Uuid::new_v4().to_string());
+        let mut index = BlockIndex::new(dataroot.to_str().unwrap());
+        let hour = 60 * 60;
+        for (start, end) in [(0, 2 * hour), (hour, 2 * hour), (3 * hour, 4 * hour)].iter() {
+            let mut block = Block::new();
+            for secs in (*start..*end).step_by(600) {
+                block.insert(make_record("host_0", 1.0, secs)).unwrap();
+            }
+            let filepath = write_block(dataroot.to_str().unwrap(), &block.to_bytes()).unwrap();
+            write_rollups(&block, &filepath).unwrap();
+            index.insert(
+                block.get_start_timestamp().unwrap().timestamp_millis(),
+                block.end_timestamp.unwrap().timestamp_millis(),
+                filepath,
+            );
+        }
+
+        // Each point is counted once.
+        let mut select = make_select("host_0");
+        select.start = Some(DateTime::from_utc(NaiveDateTime::from_timestamp(0, 0), Utc));
+        select.end = Some(DateTime::from_utc(
+            NaiveDateTime::from_timestamp(4 * hour - 1, 0),
+            Utc,
+        ));
+        select.aggregation = Some(
+            serde_json::from_str(
+                r#"{ "group_by_time": 3600000, "functions": { "usage_user": "Sum" } }"#,
+            )
+            .unwrap(),
+        );
+        let plan = rollup::plan(&select).unwrap();
+        let snapshot = Snapshot::take(&select, &Block::new(), &index);
+        let mut summaries = select_rollup(&plan.rollup, plan.tier, &snapshot);
+        for edge in plan.edges.iter() {
+            summaries.extend(summarize(select_raw(edge, &snapshot), plan.tier.width));
+        }
+        let actual = select
+            .aggregation
+            .as_ref()
+            .unwrap()
+            .apply_summaries(summaries);
+        let sums: Vec<f64> = actual
+            .iter()
+            .map(|r| *r.get_metric("usage_user".to_string()).unwrap())
+            .collect();
+        assert_eq!(sums, vec![6.0, 6.0, 6.0]);
+        fs::remove_dir_all(dataroot).unwrap();
+    }
+
+    #[test]
+    fn test_read_not() {
+        // Only some series have usage_idle.
+        let mut block = Block::new();
+        block.insert(make_record("host_0", 1.0, 10)).unwrap();
+        for (hostname, idle) in [("host_1", 2.0), ("host_2", 8.0)].iter() {
+            let record = make_record(hostname, 1.0, 10);
+            let mut variables = record.get_populated_variables();
+            variables.insert("usage_idle".to_string(), *idle);
+            let record = Record::new(
+                record.get_name(),
+                record.get_populated_labels(),
+                variables,
+                record.get_timestamp(),
+            );
+            block.insert(record).unwrap();
+        }
+        let dir = env::temp_dir().join(Uuid::new_v4().to_string());
+        let shared_block = Arc::new(RwLock::new(block));
+        let shared_index = Arc::new(RwLock::new(BlockIndex::new(dir.to_str().unwrap())));
+        let (read_tx, read_rx) = mpsc::channel();
+        let read_rx = Arc::new(Mutex::new(read_rx));
+        let read_thr = thread::spawn(move || db_read(read_rx, shared_block, shared_index));
+
+        // NOT over a variable comparison is flipped, keeping series without the variable.
+        let mut select = make_select("host_0");
+        select.predicate.condition = Conditions::Not(Box::new(Conditions::Leaf(Condition {
+            lhs: Type::Variable(String::from("usage_idle")),
+            rhs: Type::Metric(5.0),
+            op: Op::Gt,
+        })));
+        let (request, rx) = SelectRequest::new(select);
+        read_tx.send(request).unwrap();
+        let mut hostnames: Vec<String> = match rx.recv().unwrap() {
+            SelectResult::Records(records) => records
+                .iter()
+                .map(|r| r.get_populated_labels()["hostname"].clone())
+                .collect(),
+            _ => panic!("expected records"),
+        };
+        hostnames.sort();
+        assert_eq!(hostnames, vec!["host_0", "host_1"]);
+        drop(read_tx);
+        read_thr.join().unwrap();
+    }
+
+    #[test]
+    fn test_delete() {
+        let delete = Delete {
+            predicate: make_select("host_0").predicate,
+            start: Some(DateTime::from_utc(
+                NaiveDateTime::from_timestamp(15, 0),
+                Utc,
+            )),
+            end: None,
+        };
+
+        // Flush a block, and tombstone it.
+        let mut old_block = Block::new();
+        old_block.insert(make_record("host_0", 1.0, 10)).unwrap();
+        old_block.insert(make_record("host_0", 2.0, 20)).unwrap();
+        old_block.insert(make_record("host_1", 3.0, 20)).unwrap();
+        let filepath = env::temp_dir().join(format!("{}.rdb", Uuid::new_v4()));
+        let filepath = filepath.to_str().unwrap().to_string();
+        fs::write(&filepath, old_block.to_bytes()).unwrap();
+        let mut index = BlockIndex::new(env::temp_dir().to_str().unwrap());
+        index.insert(
+            old_block.get_start_timestamp().unwrap().timestamp_millis(),
+            old_block.end_timestamp.unwrap().timestamp_millis(),
+            filepath.clone(),
+        );
+        index.tombstones.push(Tombstone {
+            id: 0,
+            delete: delete.clone(),
+            filepaths: vec![filepath.clone()],
+        });
+
+        // Delete from the live block directly.
+        let mut new_block = Block::new();
+        new_block.insert(make_record("host_0", 4.0, 30)).unwrap();
+        new_block.insert(make_record("host_1", 5.0, 40)).unwrap();
+        let size = new_block.get_size();
+        new_block.delete(&delete).unwrap();
+
+        // The deleted series leaves the index, and the block's range and size shrink.
+        assert!(new_block
+            .search_index("hostname=host_0".to_string())
+            .is_none());
+        assert_eq!(new_block.get_start_timestamp().unwrap().timestamp(), 40);
+        assert!(new_block.get_size() < size);
+
+        // Writing to the emptied series again puts it back in the index.
+        let mut block = Block::new();
+        block.insert(make_record("host_0", 6.0, 20)).unwrap();
+        block.delete(&delete).unwrap();
+        assert!(block.get_start_timestamp().is_none());
+        block.insert(make_record("host_0", 7.0, 50)).unwrap();
+        assert!(block.search_index("hostname=host_0".to_string()).is_some());
+        assert_eq!(block.get_series_records(0).count(), 1);
+
+        let get_usages = |hostname: &str| -> Vec<f64> {
+            select_raw_now(&make_select(hostname), &new_block, &index)
+                .iter()
+                .map(|r| *r.get_metric("usage_user".to_string()).unwrap())
+                .collect()
+        };
+        assert_eq!(get_usages("host_0"), vec![1.0]);
+        assert_eq!(get_usages("host_1"), vec![3.0, 5.0]);
+
+        // Rewriting the block moves the tombstone, unless it was applied.
+        let new_filepath = String::from("new.rdb");
+        index.retarget_tombstones(std::slice::from_ref(&filepath), Some(&new_filepath), &[]);
+        assert_eq!(index.get_tombstones(&new_filepath).len(), 1);
+        index.retarget_tombstones(std::slice::from_ref(&new_filepath), Some(&filepath), &[0]);
+        assert!(index.get_tombstoned_filepaths().is_empty());
+        fs::remove_file(filepath).unwrap();
+    }
+
+    #[test]
+    fn test_freeze() {
+        let dir = env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let shared_index = Arc::new(RwLock::new(BlockIndex::new(dir.to_str().unwrap())));
+        let mut block = Block::new();
+        block.insert(make_record("host_0", 1.0, 10)).unwrap();
+        block.insert(make_record("host_0", 2.0, 20)).unwrap();
+        let (filepath, frozen_block) = shared_index.write().unwrap().freeze(block);
+
+        // A frozen block is read from memory, and deletes reach it by its filepath.
+        let live_block = Block::new();
+        let get_usages = || -> Vec<f64> {
+            let index = shared_index.read().unwrap();
+            select_raw_now(&make_select("host_0"), &live_block, &index)
+                .iter()
+                .map(|r| *r.get_metric("usage_user".to_string()).unwrap())
+                .collect()
+        };
+        assert_eq!(get_usages(), vec![1.0, 2.0]);
+        let delete = Delete {
+            predicate: make_select("host_0").predicate,
+            start: Some(DateTime::from_utc(
+                NaiveDateTime::from_timestamp(15, 0),
+                Utc,
+            )),
+            end: None,
+        };
+        shared_index.write().unwrap().add_tombstone(delete).unwrap();
+        assert_eq!(get_usages(), vec![1.0]);
+
+        // A snapshot holds no locks, so the block can be installed while it's read.
+        let select = make_select("host_0");
+        let snapshot = Snapshot::take(&select, &live_block, &shared_index.read().unwrap());
+        flush_frozen(&shared_index, &filepath, &frozen_block).unwrap();
+        assert_eq!(select_raw(&select, &snapshot).len(), 1);
+
+        // Once written, it's read from disk instead, with the same result.
+        assert!(shared_index.read().unwrap().get_frozen().is_empty());
+        assert_eq!(shared_index.read().unwrap().get_packed_blocks().len(), 1);
+        assert_eq!(get_usages(), vec![1.0]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_shutdown() {
+        // Writes still queued when the channels close are applied and flushed.
+        let dir = env::temp_dir().join(Uuid::new_v4().to_string());
+        let dataroot = dir.to_str().unwrap();
+        let config = Config {
+            data_dir: String::from(dataroot),
+            ..Config::default()
+        };
+        let (read_tx, read_rx) = mpsc::channel();
+        let (write_tx, write_rx) = mpsc::channel();
+        let mut replies = vec![];
+        for secs in [10, 20].iter() {
+            let record = make_record("host_0", *secs as f64, *secs);
+            let (request, reply_rx) = WriteRequest::new(Mutation::Write(record));
+            write_tx.send(request).unwrap();
+            replies.push(reply_rx);
+        }
+        drop(read_tx);
+        drop(write_tx);
+        db_open(&config, read_rx, write_rx).unwrap();
+        for reply_rx in replies {
+            reply_rx.recv().unwrap().unwrap();
+        }
+
+        let index = BlockIndex::from_disk(dataroot).unwrap();
+        assert_eq!(index.get_packed_blocks().len(), 1);
+        let wal_filename = format!("{}/wal.rdb", dataroot);
+        assert_eq!(Wal::replay(wal_filename).unwrap(), vec![]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_select_series() {
+        // Flush a block, and tombstone host_1's points in it.
+        let mut old_block = Block::new();
+        old_block.insert(make_record("host_0", 1.0, 10)).unwrap();
+        old_block.insert(make_record("host_0", 3.0, 30)).unwrap();
+        old_block.insert(make_record("host_1", 9.0, 20)).unwrap();
+        let filepath = env::temp_dir().join(format!("{}.rdb", Uuid::new_v4()));
+        let filepath = filepath.to_str().unwrap().to_string();
+        fs::write(&filepath, old_block.to_bytes()).unwrap();
+        let mut index = BlockIndex::new(env::temp_dir().to_str().unwrap());
+        index.insert(
+            old_block.get_start_timestamp().unwrap().timestamp_millis(),
+            old_block.end_timestamp.unwrap().timestamp_millis(),
+            filepath.clone(),
+        );
+        index.tombstones.push(Tombstone {
+            id: 0,
+            delete: Delete {
+                predicate: make_select("host_1").predicate,
+                start: None,
+                end: None,
+            },
+            filepaths: vec![filepath.clone()],
+        });
+        let mut new_block = Block::new();
+        new_block.insert(make_record("host_0", 2.0, 20)).unwrap();
+        new_block.insert(make_record("host_0", 4.0, 40)).unwrap();
+        new_block.insert(make_record("host_2", 5.0, 40)).unwrap();
+
+        // Each series' points are merged across blocks, with its labels given once.
+        let mut select = make_select("host_0");
+        select.predicate.condition = Conditions::Not(Box::new(select.predicate.condition));
+        let results = select_series_now(&select, &new_block, &index);
+        let hostnames: Vec<&str> = results.iter().map(|x| &x.labels["hostname"][..]).collect();
+        assert_eq!(hostnames, vec!["host_2"]);
+        let results = select_series_now(&make_select("host_0"), &new_block, &index);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].variables, vec![String::from("usage_user")]);
+        let points: Vec<(i64, Vec<f64>)> = results[0]
+            .points
+            .iter()
+            .map(|(t, values)| (t.timestamp(), values.clone()))
+            .collect();
+        assert_eq!(
+            points,
+            vec![
+                (10, vec![1.0]),
+                (20, vec![2.0]),
+                (30, vec![3.0]),
+                (40, vec![4.0])
+            ]
+        );
+
+        // The same grouping comes from merged records.
+        let records = select_raw_now(&make_select("host_0"), &new_block, &index);
+        assert_eq!(SeriesResult::from_records(records), results);
+        fs::remove_file(filepath).unwrap();
+    }
+
+    #[test]
+    fn test_label_matchers() {
+        let mut block = Block::new();
+        block.insert(make_record("web-1", 1.0, 10)).unwrap();
+        block.insert(make_record("web-2", 2.0, 20)).unwrap();
+        block.insert(make_record("db-1", 3.0, 30)).unwrap();
+        let mut labels = HashMap::new();
+        labels.insert("region".to_string(), "us-west-1".to_string());
+        let mut variables = HashMap::new();
+        variables.insert("usage_user".to_string(), 4.0);
+        let timestamp = DateTime::from_utc(NaiveDateTime::from_timestamp(40, 0), Utc);
+        block
+            .insert(Record::new("cpu".to_string(), labels, variables, timestamp))
+            .unwrap();
+        let packed_block = pack_block(&block);
+
+        // Both the live and the flushed index give the same answers.
+        let check = |op: &str, value: &str, expected: Vec<f64>| {
+            let data = format!(
+                r#"{{
+                    "name": "s",
+                    "predicate": {{
+                        "name": "p",
+                        "condition": {{
+                            "Leaf": {{ "lhs": {{"LabelKey": "hostname"}}, "rhs": {{"LabelValue": "{}"}}, "op": "{}" }}
+                        }}
+                    }}
+                }}"#,
+                value, op
+            );
+            let select: Select = serde_json::from_str(&data).unwrap();
+            let mut live = select.eval(&block);
+            live.unpack(&block);
+            let mut flushed = select.eval(&packed_block);
+            flushed.unpack(&packed_block);
+            for result in [live.into_vec(), flushed.into_vec()].iter() {
+                let mut usages: Vec<f64> = result
+                    .iter()
+                    .map(|r| *r.get_metric("usage_user".to_string()).unwrap())
+                    .collect();
+                usages.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                assert_eq!(usages, expected, "{} {}", op, value);
+            }
+            assert_eq!(select.may_match(&packed_block), !expected.is_empty());
+        };
+        check("NEq", "web-1", vec![2.0, 3.0, 4.0]);
+        check("Match", "web-.*", vec![1.0, 2.0]);
+        check("Match", "web", vec![]);
+        check("Match", "(db|web)-1", vec![1.0, 3.0]);
+        check("NMatch", "web-.*", vec![3.0, 4.0]);
+        check("Prefix", "db", vec![3.0]);
+        check("Exists", "", vec![1.0, 2.0, 3.0]);
+        check("Gt", "web-1", vec![]);
+    }
 }
@@ -0,0 +1,33 @@
+// Fixtures shared by the tests of several modules.
+use crate::server::{
+    record::Record,
+    store::{Block, PackedBlock},
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::{collections::HashMap, env, fs};
+use uuid::Uuid;
+
+// Make a record of a metric from a host, with its usage_user at a time (secs).
+pub fn make_metric_record(name: &str, hostname: &str, usage: f64, secs: i64) -> Record {
+    let mut labels = HashMap::new();
+    labels.insert("hostname".to_string(), hostname.to_string());
+    let mut variables = HashMap::new();
+    variables.insert("usage_user".to_string(), usage);
+    let timestamp = DateTime::from_utc(NaiveDateTime::from_timestamp(secs, 0), Utc);
+    Record::new(name.to_string(), labels, variables, timestamp)
+}
+
+// Make a cpu record from a host, with its usage_user at a time (secs).
+pub fn make_record(hostname: &str, usage: f64, secs: i64) -> Record {
+    make_metric_record("cpu", hostname, usage, secs)
+}
+
+// Write a block to a temp file and map it. The file is removed straight away; the
+// mapping stays readable.
+pub fn pack_block(block: &Block) -> PackedBlock {
+    let filepath = env::temp_dir().join(format!("{}.rdb", Uuid::new_v4()));
+    fs::write(&filepath, block.to_bytes()).unwrap();
+    let packed_block = PackedBlock::from_filepath(filepath.to_str().unwrap().to_string()).unwrap();
+    fs::remove_file(filepath).unwrap();
+    packed_block
+}
@@ -0,0 +1,300 @@
+use crate::error::Error;
+use crate::server::execute::Mutation;
+use crc32fast::Hasher;
+use std::{
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read, Write},
+    path::Path,
+};
+
+// Size of an entry header: a u32 payload length followed by a u32 crc32 checksum.
+const ENTRY_HEADER_SIZE: usize = 8;
+
+// Wal Struct. An append-only log of mutations that haven't been flushed to a block yet.
+// When a block is frozen, the log is sealed into a segment ({path}.{n}) that is
+// removed once the block is on disk, and a fresh log is started.
+pub struct Wal {
+    file: File,
+    path: String,
+    segments: Vec<String>, // Sealed segments not yet handed off with a frozen block
+    next_segment: u64,
+}
+impl Wal {
+    // Open the log at the given path, creating it if needed. Segments left over from
+    // before a restart were replayed with the log, so are handed off with it.
+    pub fn open(path: String) -> Result<Self, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let segments = get_segments(&path)?;
+        let next_segment = segments.last().map_or(0, |x| x.0 + 1);
+        Ok(Wal {
+            file,
+            path,
+            segments: segments.into_iter().map(|x| x.1).collect(),
+            next_segment,
+        })
+    }
+
+    // Read back every intact mutation in the log at the given path, and its sealed
+    // segments before it.
+    pub fn replay(path: String) -> Result<Vec<Mutation>, Error> {
+        let mut mutations = vec![];
+        for (_, segment) in get_segments(&path)? {
+            mutations.append(&mut replay_file(&segment)?);
+        }
+        mutations.append(&mut replay_file(&path)?);
+        Ok(mutations)
+    }
+
+    // Seal the log into a segment, and start a fresh one. Returns every segment not
+    // yet handed off, which back the block being frozen.
+    pub fn seal(&mut self) -> Result<Vec<String>, Error> {
+        self.file.sync_all()?;
+        let segment = format!("{}.{}", self.path, self.next_segment);
+        fs::rename(&self.path, &segment)?;
+        match OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+        {
+            Ok(file) => self.file = file,
+            Err(e) => {
+                // Keep appending to the old log, under its old name.
+                let _ = fs::rename(&segment, &self.path);
+                return Err(Error::Io(e));
+            }
+        }
+        self.next_segment += 1;
+        self.segments.push(segment);
+        Ok(self.segments.drain(..).collect())
+    }
+
+    // Append a mutation to the log. The entry is synced to disk before we return, so
+    // once acknowledged it survives the process crashing, the OS crashing, and power
+    // loss. A write torn by a crash mid-append is dropped on replay, and never acked.
+    pub fn append(&mut self, mutation: &Mutation) -> Result<(), Error> {
+        self.file.write_all(&encode_entry(mutation)?)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    // Empty the log; called once its records are durably stored in a block.
+    pub fn truncate(&mut self) -> Result<(), Error> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+// Remove sealed segments, once the block they back is durably stored.
+pub fn remove_segments(segments: &[String]) -> Result<(), Error> {
+    for segment in segments.iter() {
+        match fs::remove_file(segment) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(Error::Io(e)),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// Get the sealed segments of the log at path, oldest first.
+fn get_segments(path: &str) -> Result<Vec<(u64, String)>, Error> {
+    let path = Path::new(path);
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let prefix = format!(
+        "{}.",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    );
+    let mut segments = vec![];
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if let Some(n) = name.strip_prefix(&prefix).and_then(|x| x.parse().ok()) {
+            segments.push((n, dir.join(&name).to_string_lossy().into_owned()));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+// Read back every intact mutation in a log file. A torn or corrupt entry (e.g. from a
+// crash mid-append) ends the replay, and is cut off the file.
+fn replay_file(path: &str) -> Result<Vec<Mutation>, Error> {
+    // Read file out to bytes. A missing log has nothing to replay.
+    let mut bytes = vec![];
+    match File::open(path) {
+        Ok(mut f) => {
+            f.read_to_end(&mut bytes)?;
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(Error::Io(e)),
+    }
+
+    // Decode entries until we run out of valid ones.
+    let mut mutations = vec![];
+    let mut pos = 0;
+    while let Some((mutation, len)) = decode_entry(&bytes[pos..]) {
+        mutations.push(mutation);
+        pos += len;
+    }
+
+    // Drop any trailing garbage so later appends start from a clean entry boundary.
+    if pos < bytes.len() {
+        println!(
+            "Discarding {} corrupt bytes from the end of the write-ahead log.",
+            bytes.len() - pos
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|f| f.set_len(pos as u64))?;
+    }
+    Ok(mutations)
+}
+
+// Compute the checksum of an entry's payload.
+fn checksum(payload: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+// Serialize a mutation into a length-prefixed, checksummed entry.
+fn encode_entry(mutation: &Mutation) -> Result<Vec<u8>, Error> {
+    let payload = bincode::serialize(mutation)?;
+    let mut entry = Vec::with_capacity(ENTRY_HEADER_SIZE + payload.len());
+    entry.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    entry.extend_from_slice(&checksum(&payload).to_le_bytes());
+    entry.extend_from_slice(&payload);
+    Ok(entry)
+}
+
+// Decode the entry at the start of bytes, returning the mutation and the entry's length.
+fn decode_entry(bytes: &[u8]) -> Option<(Mutation, usize)> {
+    if bytes.len() < ENTRY_HEADER_SIZE {
+        return None;
+    }
+    let len = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+    let expected = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    let payload = bytes.get(ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + len)?;
+    if checksum(payload) != expected {
+        return None;
+    }
+    let mutation = bincode::deserialize(payload).ok()?;
+    Some((mutation, ENTRY_HEADER_SIZE + len))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::record::Record;
+    use chrono::{DateTime, NaiveDateTime, Utc};
+    use std::{collections::HashMap, env, fs};
+    use uuid::Uuid;
+
+    fn make_write(secs: i64) -> Mutation {
+        let mut labels = HashMap::new();
+        labels.insert("hostname".to_string(), "host_0".to_string());
+        let mut variables = HashMap::new();
+        variables.insert("usage_user".to_string(), secs as f64);
+        let timestamp = DateTime::from_utc(NaiveDateTime::from_timestamp(secs, 0), Utc);
+        Mutation::Write(Record::new("cpu".to_string(), labels, variables, timestamp))
+    }
+
+    fn temp_path() -> String {
+        let path = env::temp_dir().join(format!("{}.wal", Uuid::new_v4()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_append_replay() {
+        let path = temp_path();
+        let mut wal = Wal::open(path.clone()).unwrap();
+        wal.append(&make_write(1)).unwrap();
+        wal.append(&make_write(2)).unwrap();
+        assert_eq!(
+            Wal::replay(path.clone()).unwrap(),
+            vec![make_write(1), make_write(2)]
+        );
+
+        wal.truncate().unwrap();
+        assert_eq!(Wal::replay(path.clone()).unwrap(), vec![]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_torn_tail() {
+        let path = temp_path();
+        let mut wal = Wal::open(path.clone()).unwrap();
+        wal.append(&make_write(1)).unwrap();
+        wal.append(&make_write(2)).unwrap();
+
+        // Chop the last entry in half, as if we crashed mid-write.
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 5)
+            .unwrap();
+        assert_eq!(Wal::replay(path.clone()).unwrap(), vec![make_write(1)]);
+
+        // The torn entry is gone, so new appends are readable again.
+        let mut wal = Wal::open(path.clone()).unwrap();
+        wal.append(&make_write(3)).unwrap();
+        assert_eq!(
+            Wal::replay(path.clone()).unwrap(),
+            vec![make_write(1), make_write(3)]
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_bad_checksum() {
+        let path = temp_path();
+        let mut wal = Wal::open(path.clone()).unwrap();
+        wal.append(&make_write(1)).unwrap();
+
+        // Flip a byte in the payload.
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+        assert_eq!(Wal::replay(path.clone()).unwrap(), vec![]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_seal() {
+        let path = temp_path();
+        let mut wal = Wal::open(path.clone()).unwrap();
+        wal.append(&make_write(1)).unwrap();
+        let first = wal.seal().unwrap();
+        wal.append(&make_write(2)).unwrap();
+
+        // Segments are replayed before the log, and handed off again after a restart.
+        let mut wal = Wal::open(path.clone()).unwrap();
+        wal.append(&make_write(3)).unwrap();
+        assert_eq!(
+            Wal::replay(path.clone()).unwrap(),
+            vec![make_write(1), make_write(2), make_write(3)]
+        );
+        let second = wal.seal().unwrap();
+        assert_eq!(second.len(), 2);
+        assert_eq!(second[0], first[0]);
+        let third = wal.seal().unwrap();
+        assert_eq!(third.len(), 1);
+
+        remove_segments(&second).unwrap();
+        remove_segments(&third).unwrap();
+        assert_eq!(Wal::replay(path.clone()).unwrap(), vec![]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_missing() {
+        assert_eq!(Wal::replay(temp_path()).unwrap(), vec![]);
+    }
+}